@@ -1,11 +1,18 @@
 use std::env;
 use std::fs;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
 use std::result;
 use std::time;
 
 const VERSION: (usize, usize) = (0, 2);
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
 pub struct Config {
     pub num_workers: usize,
     pub num_threads: usize,
@@ -16,8 +23,38 @@ pub struct Config {
     pub body_stacktrace: bool,
     pub log_response: bool,
     pub ctrlc_wait_time: time::Duration,
-    pub request_read_timeout: time::Duration,
+    pub header_read_timeout: time::Duration,
+    pub request_read_timeout: Option<time::Duration>,
+    pub body_min_rate: usize,
+    pub body_rate_grace_period: time::Duration,
+    pub max_slow_conns_per_ip: usize,
     pub python_code_timeout: time::Duration,
+    pub response_buffer_size: usize,
+    pub response_write_timeout: Option<time::Duration>,
+    pub request_body_spool_size: usize,
+    pub request_decompression: bool,
+    pub request_max_decoded_size: usize,
+    pub strict_http: bool,
+    pub http2: bool,
+    pub http2_idle_timeout: time::Duration,
+    pub proxy_protocol: bool,
+    pub compression: Vec<String>,
+    pub compression_min_size: usize,
+    pub compression_types: Vec<String>,
+    pub error_format: ErrorFormat,
+    pub error_templates: Vec<(u16, PathBuf)>,
+    pub static_mounts: Vec<(String, PathBuf)>,
+    pub cache_size: usize,
+    pub cache_max_entry_size: usize,
+    pub etag_max_size: usize,
+    pub sendfile_root: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_sni_certs: Vec<(String, PathBuf, PathBuf)>,
+    // None offers h2 (when CASKET_HTTP2=1) then http/1.1
+    pub tls_alpn: Option<Vec<String>>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub tls_client_cert_required: bool,
     pub version: (usize, usize),
 }
 
@@ -38,8 +75,46 @@ impl Default for Config {
             body_stacktrace: true,
             log_response: true,
             ctrlc_wait_time: time::Duration::from_secs(10),
-            request_read_timeout: time::Duration::from_secs(30),
+            header_read_timeout: time::Duration::from_secs(10),
+            request_read_timeout: Some(time::Duration::from_secs(30)),
+            body_min_rate: 512,
+            body_rate_grace_period: time::Duration::from_secs(5),
+            max_slow_conns_per_ip: 0,
             python_code_timeout: time::Duration::from_secs(10),
+            response_buffer_size: 1 << 20,
+            response_write_timeout: Some(time::Duration::from_secs(60)),
+            request_body_spool_size: 1 << 20,
+            request_decompression: false,
+            request_max_decoded_size: 10 << 20,
+            strict_http: true,
+            http2: false,
+            http2_idle_timeout: time::Duration::from_secs(60),
+            proxy_protocol: false,
+            compression: vec![],
+            compression_min_size: 1024,
+            compression_types: [
+                "text/",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+            error_format: ErrorFormat::Text,
+            error_templates: vec![],
+            static_mounts: vec![],
+            cache_size: 0,
+            cache_max_entry_size: 1 << 20,
+            etag_max_size: 0,
+            sendfile_root: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_sni_certs: vec![],
+            tls_alpn: None,
+            tls_client_ca: None,
+            tls_client_cert_required: true,
             version: VERSION,
         }
     }
@@ -120,14 +195,46 @@ impl Config {
                         .map_err(|_| ERR_STR)
                         .map(time::Duration::from_secs)?;
                 }
+                "CASKET_HEADER_READ_TIMEOUT" => {
+                    const ERR_STR: &str = "CASKET_HEADER_READ_TIMEOUT must be a positive integer";
+
+                    slf.header_read_timeout = value
+                        .parse::<u64>()
+                        .map_err(|_| ERR_STR)
+                        .map(time::Duration::from_secs)?;
+                }
                 "CASKET_REQUEST_READ_TIMEOUT" => {
-                    const ERR_STR: &str = "CASKET_CTRLC_READ_TIMEOUT must be a positive integer";
+                    const ERR_STR: &str = "CASKET_REQUEST_READ_TIMEOUT must be a positive integer";
+
+                    // Zero turns the overall deadline off
+                    slf.request_read_timeout =
+                        value
+                            .parse::<u64>()
+                            .map_err(|_| ERR_STR)
+                            .map(|secs| match secs {
+                                0 => None,
+                                secs => Some(time::Duration::from_secs(secs)),
+                            })?;
+                }
+                "CASKET_BODY_MIN_RATE" => {
+                    slf.body_min_rate = value
+                        .parse()
+                        .map_err(|_| "CASKET_BODY_MIN_RATE must be positive integer")?;
+                }
+                "CASKET_BODY_RATE_GRACE_PERIOD" => {
+                    const ERR_STR: &str =
+                        "CASKET_BODY_RATE_GRACE_PERIOD must be a positive integer";
 
-                    slf.request_read_timeout = value
+                    slf.body_rate_grace_period = value
                         .parse::<u64>()
                         .map_err(|_| ERR_STR)
                         .map(time::Duration::from_secs)?;
                 }
+                "CASKET_MAX_SLOW_CONNECTIONS_PER_IP" => {
+                    slf.max_slow_conns_per_ip = value.parse().map_err(|_| {
+                        "CASKET_MAX_SLOW_CONNECTIONS_PER_IP must be positive integer"
+                    })?;
+                }
                 "CASKET_PYTHON_CODE_GATEWAY_TIMEOUT" => {
                     const ERR_STR: &str =
                         "CASKET_PYTHON_CODE_GATEWAY_TIMEOUT must be a positive integer";
@@ -137,14 +244,254 @@ impl Config {
                         .map_err(|_| ERR_STR)
                         .map(time::Duration::from_secs)?;
                 }
+                "CASKET_RESPONSE_BUFFER_SIZE" => {
+                    const ERR_STR: &str = "CASKET_RESPONSE_BUFFER_SIZE must be positive integer";
+
+                    slf.response_buffer_size = value
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|size| *size > 0)
+                        .ok_or(ERR_STR)?;
+                }
+                "CASKET_RESPONSE_WRITE_TIMEOUT" => {
+                    const ERR_STR: &str =
+                        "CASKET_RESPONSE_WRITE_TIMEOUT must be a positive integer";
+
+                    // Zero lets a client take as long as it likes
+                    slf.response_write_timeout =
+                        value
+                            .parse::<u64>()
+                            .map_err(|_| ERR_STR)
+                            .map(|secs| match secs {
+                                0 => None,
+                                secs => Some(time::Duration::from_secs(secs)),
+                            })?;
+                }
+                "CASKET_REQUEST_BODY_SPOOL_SIZE" => {
+                    slf.request_body_spool_size = value
+                        .parse()
+                        .map_err(|_| "CASKET_REQUEST_BODY_SPOOL_SIZE must be positive integer")?;
+                }
+                "CASKET_REQUEST_DECOMPRESSION" => {
+                    slf.request_decompression =
+                        parse_flag(&value, "CASKET_REQUEST_DECOMPRESSION must be 0 or 1")?;
+                }
+                "CASKET_REQUEST_MAX_DECODED_SIZE" => {
+                    slf.request_max_decoded_size = value
+                        .parse()
+                        .map_err(|_| "CASKET_REQUEST_MAX_DECODED_SIZE must be positive integer")?;
+                }
+                "CASKET_STRICT_HTTP" => {
+                    slf.strict_http = parse_flag(&value, "CASKET_STRICT_HTTP must be 0 or 1")?;
+                }
+                "CASKET_HTTP2" => {
+                    slf.http2 = parse_flag(&value, "CASKET_HTTP2 must be 0 or 1")?;
+                }
+                "CASKET_HTTP2_IDLE_TIMEOUT" => {
+                    const ERR_STR: &str = "CASKET_HTTP2_IDLE_TIMEOUT must be a positive integer";
+
+                    slf.http2_idle_timeout = value
+                        .parse::<u64>()
+                        .map_err(|_| ERR_STR)
+                        .map(time::Duration::from_secs)?;
+                }
+                "CASKET_PROXY_PROTOCOL" => {
+                    slf.proxy_protocol =
+                        parse_flag(&value, "CASKET_PROXY_PROTOCOL must be 0 or 1")?;
+                }
+                "CASKET_COMPRESSION" => {
+                    slf.compression = parse_compression(&value)?;
+                }
+                "CASKET_COMPRESSION_MIN_SIZE" => {
+                    slf.compression_min_size = value
+                        .parse()
+                        .map_err(|_| "CASKET_COMPRESSION_MIN_SIZE must be positive integer")?;
+                }
+                "CASKET_COMPRESSION_TYPES" => {
+                    slf.compression_types = value
+                        .split(',')
+                        .map(|t| t.trim().to_ascii_lowercase())
+                        .filter(|t| !t.is_empty())
+                        .collect();
+                }
+                "CASKET_ERROR_FORMAT" => {
+                    slf.error_format = match value.as_ref() {
+                        "text" => ErrorFormat::Text,
+                        "json" => ErrorFormat::Json,
+                        _ => return Err("CASKET_ERROR_FORMAT must be text or json".to_string()),
+                    };
+                }
+                "CASKET_ERROR_TEMPLATES" => {
+                    slf.error_templates = parse_error_templates(&value)?;
+                }
+                "CASKET_STATIC" => {
+                    slf.static_mounts = parse_static_mounts(&value)?;
+                }
+                "CASKET_CACHE_SIZE" => {
+                    slf.cache_size = value
+                        .parse()
+                        .map_err(|_| "CASKET_CACHE_SIZE must be positive integer")?;
+                }
+                "CASKET_CACHE_MAX_ENTRY_SIZE" => {
+                    slf.cache_max_entry_size = value
+                        .parse()
+                        .map_err(|_| "CASKET_CACHE_MAX_ENTRY_SIZE must be positive integer")?;
+                }
+                "CASKET_ETAG_MAX_SIZE" => {
+                    slf.etag_max_size = value
+                        .parse()
+                        .map_err(|_| "CASKET_ETAG_MAX_SIZE must be positive integer")?;
+                }
+                "CASKET_SENDFILE_ROOT" => {
+                    slf.sendfile_root = Some(PathBuf::from(value));
+                }
+                "CASKET_TLS_CERT" => {
+                    slf.tls_cert = Some(PathBuf::from(value));
+                }
+                "CASKET_TLS_KEY" => {
+                    slf.tls_key = Some(PathBuf::from(value));
+                }
+                "CASKET_TLS_SNI_CERTS" => {
+                    slf.tls_sni_certs = parse_sni_certs(&value)?;
+                }
+                "CASKET_TLS_ALPN" => {
+                    slf.tls_alpn = Some(
+                        value
+                            .split(',')
+                            .map(|proto| proto.trim().to_string())
+                            .filter(|proto| !proto.is_empty())
+                            .collect(),
+                    );
+                }
+                "CASKET_TLS_CLIENT_CA" => {
+                    slf.tls_client_ca = Some(PathBuf::from(value));
+                }
+                "CASKET_TLS_CLIENT_CERT_REQUIRED" => {
+                    slf.tls_client_cert_required =
+                        parse_flag(&value, "CASKET_TLS_CLIENT_CERT_REQUIRED must be 0 or 1")?;
+                }
                 _ => {}
             }
         }
 
+        if slf.tls_cert.is_some() != slf.tls_key.is_some() {
+            return Err("CASKET_TLS_CERT and CASKET_TLS_KEY must be set together".to_string());
+        }
+
+        if !slf.tls_sni_certs.is_empty() && slf.tls_cert.is_none() {
+            return Err(
+                "CASKET_TLS_SNI_CERTS requires CASKET_TLS_CERT and CASKET_TLS_KEY".to_string(),
+            );
+        }
+
+        if slf.tls_client_ca.is_some() && slf.tls_cert.is_none() {
+            return Err(
+                "CASKET_TLS_CLIENT_CA requires CASKET_TLS_CERT and CASKET_TLS_KEY".to_string(),
+            );
+        }
+
         Ok(slf)
     }
 
     pub fn port(&self) -> u16 {
         self.bind_addr.port()
     }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some()
+    }
+}
+
+// Comma separated list of server_name=cert_path:key_path
+fn parse_sni_certs(value: &str) -> result::Result<Vec<(String, PathBuf, PathBuf)>, &'static str> {
+    const ERR_STR: &str = "CASKET_TLS_SNI_CERTS must be a list of server_name=cert_path:key_path";
+
+    let mut sni_certs = vec![];
+
+    for entry in value.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let (server_name, paths) = entry.split_once('=').ok_or(ERR_STR)?;
+        let (cert_path, key_path) = paths.split_once(':').ok_or(ERR_STR)?;
+
+        if server_name.is_empty() || cert_path.is_empty() || key_path.is_empty() {
+            return Err(ERR_STR);
+        }
+
+        sni_certs.push((
+            server_name.to_string(),
+            PathBuf::from(cert_path),
+            PathBuf::from(key_path),
+        ));
+    }
+
+    Ok(sni_certs)
+}
+
+// Comma separated list of content codings, in order of preference
+fn parse_compression(value: &str) -> result::Result<Vec<String>, &'static str> {
+    const ERR_STR: &str = "CASKET_COMPRESSION must be a list of br, zstd and gzip";
+
+    let mut codings = vec![];
+
+    for coding in value.split(',').map(|c| c.trim()).filter(|c| !c.is_empty()) {
+        if !matches!(coding, "br" | "zstd" | "gzip") {
+            return Err(ERR_STR);
+        }
+
+        codings.push(coding.to_string());
+    }
+
+    Ok(codings)
+}
+
+// Comma separated list of status=template_path
+fn parse_error_templates(value: &str) -> result::Result<Vec<(u16, PathBuf)>, &'static str> {
+    const ERR_STR: &str = "CASKET_ERROR_TEMPLATES must be a list of status=template_path";
+
+    let mut templates = vec![];
+
+    for entry in value.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let (code, path) = entry.split_once('=').ok_or(ERR_STR)?;
+        let code = code
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|code| (400..600).contains(code))
+            .ok_or(ERR_STR)?;
+
+        if path.is_empty() {
+            return Err(ERR_STR);
+        }
+
+        templates.push((code, PathBuf::from(path)));
+    }
+
+    Ok(templates)
+}
+
+// Comma separated list of url_prefix=directory
+fn parse_static_mounts(value: &str) -> result::Result<Vec<(String, PathBuf)>, &'static str> {
+    const ERR_STR: &str = "CASKET_STATIC must be a list of url_prefix=directory";
+
+    let mut mounts = vec![];
+
+    for entry in value.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let (prefix, dir) = entry.split_once('=').ok_or(ERR_STR)?;
+
+        if !prefix.starts_with('/') || dir.is_empty() {
+            return Err(ERR_STR);
+        }
+
+        // "/static/" and "/static" are the same mount, "/" mounts everything
+        mounts.push((prefix.trim_end_matches('/').to_string(), PathBuf::from(dir)));
+    }
+
+    Ok(mounts)
+}
+
+fn parse_flag(value: &str, err_str: &'static str) -> result::Result<bool, &'static str> {
+    match value.parse::<usize>() {
+        Ok(0) => Ok(false),
+        Ok(1) => Ok(true),
+        _ => Err(err_str),
+    }
 }
@@ -0,0 +1,304 @@
+// Bodies for the error responses casket sends itself (400, 408, 415, 500, 503, 504).
+// Templates are read once at startup, before the workers fork.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::{Config, ErrorFormat};
+
+pub struct ErrorPages {
+    format: ErrorFormat,
+    templates: HashMap<u16, Template>,
+}
+
+struct Template {
+    content_type: &'static str,
+    text: String,
+}
+
+pub struct ErrorPage {
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl ErrorPages {
+    pub fn load(cfg: &Config) -> Result<Self, String> {
+        let mut templates = HashMap::new();
+
+        for (code, path) in cfg.error_templates.iter() {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("couldn't read {} - {}", path.display(), e))?;
+
+            templates.insert(
+                *code,
+                Template {
+                    content_type: content_type(path),
+                    text,
+                },
+            );
+        }
+
+        Ok(Self {
+            format: cfg.error_format,
+            templates,
+        })
+    }
+
+    // A template for the status wins, then the JSON format. None means
+    // nothing is configured and the caller sends its usual response.
+    pub fn render(
+        &self,
+        code: u16,
+        reason: &str,
+        trace_id: &str,
+        error: Option<&str>,
+    ) -> Option<ErrorPage> {
+        if let Some(template) = self.templates.get(&code) {
+            let code = code.to_string();
+            let vars = [
+                ("status", code.as_str()),
+                ("reason", reason),
+                ("trace_id", trace_id),
+                ("error", error.unwrap_or("")),
+            ];
+
+            return Some(ErrorPage {
+                content_type: template.content_type,
+                body: substitute(&template.text, &vars, template.content_type).into_bytes(),
+            });
+        }
+
+        match self.format {
+            ErrorFormat::Text => None,
+            ErrorFormat::Json => {
+                let body = format!(
+                    "{{\"error\":\"{}\",\"status\":{},\"trace_id\":\"{}\"}}",
+                    json_escape(error.unwrap_or(reason)),
+                    code,
+                    json_escape(trace_id)
+                );
+
+                Some(ErrorPage {
+                    content_type: "application/json",
+                    body: body.into_bytes(),
+                })
+            }
+        }
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=UTF-8",
+        Some("json") => "application/json",
+        _ => "text/plain; charset=UTF-8",
+    }
+}
+
+// {name} is replaced by its value, anything else in braces is left alone.
+// Values are escaped for the template's content type.
+fn substitute(text: &str, vars: &[(&str, &str)], content_type: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let var = rest.find('}').and_then(|end| {
+            vars.iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, *value))
+        });
+
+        match var {
+            Some((end, value)) => {
+                // Error messages can carry user input
+                if content_type.starts_with("text/html") {
+                    out.push_str(&html_escape(value));
+                } else if content_type.starts_with("application/json") {
+                    out.push_str(&json_escape(value));
+                } else {
+                    out.push_str(value);
+                }
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn html_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn json_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{html_escape, json_escape, substitute, ErrorPages, Template};
+    use crate::config::ErrorFormat;
+
+    const VARS: [(&str, &str); 2] = [("status", "400"), ("error", "<b>\"bad\" & 'worse'</b>")];
+
+    fn text(template: &str) -> String {
+        substitute(template, &VARS, "text/plain; charset=UTF-8")
+    }
+
+    #[test]
+    fn substitute_vars() {
+        assert_eq!(text("{status}: {error}"), "400: <b>\"bad\" & 'worse'</b>");
+        assert_eq!(text("{status}{status}"), "400400");
+        assert_eq!(text("no vars"), "no vars");
+        assert_eq!(text(""), "");
+    }
+
+    #[test]
+    fn substitute_unknown() {
+        assert_eq!(text("{unknown} {status}"), "{unknown} 400");
+        assert_eq!(text("body { color: red }"), "body { color: red }");
+        assert_eq!(text("{}"), "{}");
+        assert_eq!(text("{{status}}"), "{400}");
+        assert_eq!(text("{ {status}"), "{ 400");
+    }
+
+    #[test]
+    fn substitute_unclosed() {
+        assert_eq!(text("{status"), "{status");
+        assert_eq!(text("{status} {"), "400 {");
+        assert_eq!(text("{"), "{");
+    }
+
+    #[test]
+    fn substitute_escaped() {
+        assert_eq!(
+            substitute("<p>{error}</p>", &VARS, "text/html; charset=UTF-8"),
+            "<p>&lt;b&gt;&quot;bad&quot; &amp; &#39;worse&#39;&lt;/b&gt;</p>"
+        );
+        assert_eq!(
+            substitute("{\"error\":\"{error}\"}", &VARS, "application/json"),
+            "{\"error\":\"<b>\\\"bad\\\" & 'worse'</b>\"}"
+        );
+    }
+
+    #[test]
+    fn html() {
+        assert_eq!(html_escape("plain é"), "plain é");
+        assert_eq!(
+            html_escape("<script>alert('x')</script>"),
+            "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"
+        );
+        assert_eq!(html_escape("&amp;"), "&amp;amp;");
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(json_escape("plain é"), "plain é");
+        assert_eq!(json_escape("a \"b\" \\c"), "a \\\"b\\\" \\\\c");
+        assert_eq!(json_escape("\r\n\t"), "\\r\\n\\t");
+        assert_eq!(
+            json_escape("\0\x08\x0c\x1f"),
+            "\\u0000\\u0008\\u000c\\u001f"
+        );
+        // Only control characters below 0x20 must be escaped
+        assert_eq!(json_escape("\x7f"), "\x7f");
+    }
+
+    #[test]
+    fn render_json() {
+        let error_pages = ErrorPages {
+            format: ErrorFormat::Json,
+            templates: HashMap::new(),
+        };
+
+        let page = error_pages
+            .render(400, "Bad Request", "abc", Some("bad \"header\"\n"))
+            .unwrap();
+        assert_eq!(page.content_type, "application/json");
+        assert_eq!(
+            page.body,
+            b"{\"error\":\"bad \\\"header\\\"\\n\",\"status\":400,\"trace_id\":\"abc\"}"
+        );
+
+        // The reason stands in for a missing error
+        let page = error_pages
+            .render(504, "Gateway Timeout", "abc", None)
+            .unwrap();
+        assert_eq!(
+            page.body,
+            b"{\"error\":\"Gateway Timeout\",\"status\":504,\"trace_id\":\"abc\"}"
+        );
+    }
+
+    #[test]
+    fn render_template() {
+        let mut templates = HashMap::new();
+        templates.insert(
+            503,
+            Template {
+                content_type: "text/html; charset=UTF-8",
+                text: "<h1>{status} {reason}</h1><p>{error}</p><!-- {trace_id} -->".to_string(),
+            },
+        );
+
+        let error_pages = ErrorPages {
+            format: ErrorFormat::Json,
+            templates,
+        };
+
+        // The template wins over the JSON format
+        let page = error_pages
+            .render(503, "Service Unavailable", "abc", Some("<busy>"))
+            .unwrap();
+        assert_eq!(page.content_type, "text/html; charset=UTF-8");
+        assert_eq!(
+            page.body,
+            b"<h1>503 Service Unavailable</h1><p>&lt;busy&gt;</p><!-- abc -->"
+        );
+
+        let error_pages = ErrorPages {
+            format: ErrorFormat::Text,
+            templates: HashMap::new(),
+        };
+        assert!(error_pages
+            .render(503, "Service Unavailable", "abc", None)
+            .is_none());
+    }
+}
@@ -0,0 +1,217 @@
+// HTTP dates, sent in the IMF-fixdate format, e.g "Sun, 06 Nov 1994 08:49:37 GMT"
+use std::time;
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const SECS_PER_DAY: u64 = 86400;
+
+pub fn format(t: time::SystemTime) -> String {
+    let secs = t
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let days = secs / SECS_PER_DAY;
+    let rem = secs % SECS_PER_DAY;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a Thursday
+        WEEKDAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// IMF-fixdate, and the obsolete RFC 850 and asctime forms a recipient
+// still has to accept (RFC 9110 section 5.6.7)
+pub fn parse(value: &str) -> Option<time::SystemTime> {
+    let parts = value.split_whitespace().collect::<Vec<&str>>();
+
+    let (day, month, year, hms) = match parts[..] {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        [weekday, day, month, year, hms, "GMT"] if weekday.ends_with(',') => {
+            (day, month, year.parse::<i64>().ok()?, hms)
+        }
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        [weekday, date, hms, "GMT"] if weekday.ends_with(',') => {
+            let mut date = date.split('-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+
+            if date.next().is_some() || year.len() != 2 || !year.bytes().all(|b| b.is_ascii_digit())
+            {
+                return None;
+            }
+
+            (
+                day,
+                month,
+                full_year(year.parse::<i64>().ok()?, this_year()),
+                hms,
+            )
+        }
+        // Sun Nov  6 08:49:37 1994
+        [_weekday, month, day, hms, year] => (day, month, year.parse::<i64>().ok()?, hms),
+        _ => return None,
+    };
+
+    let day = day.parse::<i64>().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+
+    let mut hms = hms.split(':');
+    let hour = hms.next()?.parse::<u64>().ok()?;
+    let min = hms.next()?.parse::<u64>().ok()?;
+    let sec = hms.next()?.parse::<u64>().ok()?;
+
+    if hms.next().is_some() {
+        return None;
+    }
+
+    if !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+
+    let secs = days as u64 * SECS_PER_DAY + hour * 3600 + min * 60 + sec;
+    Some(time::UNIX_EPOCH + time::Duration::from_secs(secs))
+}
+
+fn this_year() -> i64 {
+    let days = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs() / SECS_PER_DAY)
+        .unwrap_or(0);
+
+    civil_from_days(days as i64).0
+}
+
+// A two digit year more than 50 years ahead is the most recent one in the past
+fn full_year(year: i64, this_year: i64) -> i64 {
+    let year = this_year - this_year % 100 + year;
+    if year > this_year + 50 {
+        year - 100
+    } else if year + 100 <= this_year + 50 {
+        year + 100
+    } else {
+        year
+    }
+}
+
+// Howard Hinnant's days_from_civil and civil_from_days
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{format, full_year, parse, this_year};
+
+    fn at(secs: u64) -> time::SystemTime {
+        time::UNIX_EPOCH + time::Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn format_dates() {
+        assert_eq!(format(at(0)), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format(at(784111777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(at(951782400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parse_imf_fixdate() {
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(at(784111777)));
+        assert_eq!(
+            parse(" Sun, 06 Nov 1994 08:49:37 GMT "),
+            Some(at(784111777))
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for secs in [0, 784111777, 951782400, 1700000000, 4102444799] {
+            assert_eq!(parse(&format(at(secs))), Some(at(secs)));
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for value in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 08:49:37:00 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "784111777",
+        ] {
+            assert_eq!(parse(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_rfc850() {
+        let imf = format!("Sun, 06 Nov {} 08:49:37 GMT", full_year(94, this_year()));
+
+        assert!(parse(&imf).is_some());
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), parse(&imf));
+        assert_eq!(parse("Sunday, 06-Nov-1994 08:49:37 GMT"), None);
+        assert_eq!(parse("Sunday, 06-Nov-+4 08:49:37 GMT"), None);
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37"), None);
+    }
+
+    #[test]
+    fn parse_asctime() {
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), Some(at(784111777)));
+        assert_eq!(parse("Sun Nov 06 08:49:37 1994"), Some(at(784111777)));
+        assert_eq!(parse("Sun Nov  6 08:49:37"), None);
+        assert_eq!(parse("Sun 6 Nov 08:49:37 1994"), None);
+    }
+
+    #[test]
+    fn two_digit_years() {
+        assert_eq!(full_year(94, 2026), 1994);
+        assert_eq!(full_year(26, 2026), 2026);
+        assert_eq!(full_year(76, 2026), 2076);
+        assert_eq!(full_year(77, 2026), 1977);
+        assert_eq!(full_year(1, 2099), 2101);
+        assert_eq!(full_year(49, 2099), 2149);
+        assert_eq!(full_year(50, 2099), 2050);
+    }
+}
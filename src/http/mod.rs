@@ -1,25 +1,88 @@
+use std::cmp;
+use std::fs;
 use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 
 use random_fast_rng::{FastRng, Random};
 
+pub mod date;
+
 pub enum HttpError {
     Io((&'static str, io::Error)),
     HeaderParse(httparse::Error),
     BadValue(&'static str),
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2.0",
+        }
+    }
+}
+
 pub struct HttpRequest {
     pub method: http_types::Method,
     pub url: http_types::Url,
+    pub version: Version,
     pub headers: Vec<(String, String)>,
     pub context: Context,
     pub keep_alive: bool,
     pub content_type: Option<String>,
     pub content_length: usize,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<RequestBody>,
+    pub tls: Option<TlsInfo>,
+    pub addrs: Option<ConnAddrs>,
+}
+
+// Both ends of the client's connection. With CASKET_PROXY_PROTOCOL
+// these come from the proxy header rather than the tcp stream.
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ConnAddrs {
+    pub remote: SocketAddr,
+    pub local: SocketAddr,
 }
 
+pub struct TlsInfo {
+    pub server_name: Option<String>,
+    pub protocol: Option<String>,
+    // Only set when the client presented a certificate (mutual tls)
+    pub client_cert: Option<ClientCert>,
+}
+
+// The client certificate has been verified against CASKET_TLS_CLIENT_CA
+#[derive(Clone)]
+pub struct ClientCert {
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub fingerprint: String,
+    pub dns_names: Vec<String>,
+    pub emails: Vec<String>,
+    pub uris: Vec<String>,
+}
+
+pub enum RequestBody {
+    Memory(Vec<u8>),
+    // Bodies larger than the spool size are written to an unlinked temporary file
+    Spooled(fs::File),
+}
+
+#[derive(Clone)]
 pub struct HttpResponseHeader {
     pub code: u16,
     pub reason: String,
@@ -29,10 +92,16 @@ pub struct HttpResponseHeader {
 pub struct HttpResponse {
     pub method: http_types::Method,
     pub url: http_types::Url,
+    pub version: Version,
     pub code: u16,
     pub reason: String,
     pub context: Context,
     pub keep_alive: bool,
+    pub addrs: Option<ConnAddrs>,
+    // HTTP/1.1 body of unknown length
+    pub chunked: bool,
+    // HEAD, 1xx, 204 and 304 - headers only, whatever the app sends
+    pub no_body: bool,
 
     // req
     pub req_headers: Vec<(String, String)>,
@@ -42,6 +111,27 @@ pub struct HttpResponse {
     pub resp_headers: Vec<(String, String)>,
     pub resp_content_length: Option<usize>,
     pub resp_body: Option<Receiver<Vec<u8>>>,
+    // wsgi.file_wrapper over a regular file, sent before resp_body
+    pub resp_file: Option<SendFile>,
+    // Copy of the body as it's sent, for the response cache
+    pub capture: Option<Capture>,
+    // Set by the python thread before it drops resp_body if the app failed
+    // part way through the body
+    pub aborted: Arc<AtomicBool>,
+}
+
+pub struct Capture {
+    // The app's headers, before the writer adds to them
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub limit: usize,
+}
+
+// The part of a file still to be sent as the response body
+pub struct SendFile {
+    file: fs::File,
+    offset: u64,
+    remaining: u64,
 }
 
 impl HttpRequest {
@@ -50,38 +140,66 @@ impl HttpRequest {
         header: HttpResponseHeader,
         body: Receiver<Vec<u8>>,
     ) -> HttpResponse {
-        let mut resp_content_length: Option<usize> = None;
+        let resp_content_length = content_length(&header.headers);
+        let no_body = no_body(&self.method, header.code);
 
-        for (name, value) in header.headers.iter() {
-            if name.eq_ignore_ascii_case("Content-Lenth") {
-                if let Ok(cl) = value.parse::<usize>() {
-                    resp_content_length = Some(cl);
-                }
-            }
-        }
+        // HTTP/1.0 has no chunked encoding, the body is delimited
+        // by closing the stream unless we know the length upfront
+        let keep_alive = match self.version {
+            Version::Http10 => self.keep_alive && (resp_content_length.is_some() || no_body),
+            Version::Http11 | Version::Http2 => self.keep_alive,
+        };
+
+        // HTTP/2 has its own framing
+        let chunked = self.version == Version::Http11 && resp_content_length.is_none() && !no_body;
 
         HttpResponse {
             method: self.method,
             url: self.url,
+            version: self.version,
             code: header.code,
             reason: header.reason,
             context: self.context,
-            keep_alive: self.keep_alive,
+            keep_alive,
+            addrs: self.addrs,
+            chunked,
+            no_body,
             req_headers: self.headers,
             req_content_length: self.content_length,
             resp_headers: header.headers,
             resp_content_length,
             resp_body: Some(body),
+            resp_file: None,
+            capture: None,
+            aborted: Arc::new(AtomicBool::new(false)),
         }
     }
 }
 
 impl HttpResponse {
+    // The app's status and headers give way to casket's, e.g for a file it sends itself
+    pub fn set_header(&mut self, header: HttpResponseHeader) {
+        self.resp_content_length = content_length(&header.headers);
+        self.no_body = no_body(&self.method, header.code);
+        self.chunked =
+            self.version == Version::Http11 && self.resp_content_length.is_none() && !self.no_body;
+
+        self.code = header.code;
+        self.reason = header.reason;
+        self.resp_headers = header.headers;
+    }
+
+    // Only meaningful once resp_body has disconnected
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
     pub fn write_header(&self, buf: &mut Vec<u8>) {
         buf.clear();
 
         // Status line
-        buf.extend(b"HTTP/1.1 ");
+        buf.extend(self.version.as_str().as_bytes());
+        buf.extend(b" ");
         buf.extend(self.code.to_string().as_bytes());
         buf.extend(b" ");
         buf.extend(self.reason.as_bytes());
@@ -96,6 +214,13 @@ impl HttpResponse {
             buf.extend(b"\r\n");
         }
 
+        if self.chunked {
+            buf.extend("Transfer-Encoding".as_bytes());
+            buf.extend(b": ");
+            buf.extend("chunked".as_bytes());
+            buf.extend(b"\r\n");
+        }
+
         // Context
         buf.extend("X-TraceId".as_bytes());
         buf.extend(b": ");
@@ -123,6 +248,92 @@ impl HttpResponse {
     }
 }
 
+fn content_length(headers: &[(String, String)]) -> Option<usize> {
+    let mut resp_content_length = None;
+
+    for (name, value) in headers.iter() {
+        if name.eq_ignore_ascii_case("Content-Length") {
+            if let Ok(cl) = value.parse::<usize>() {
+                resp_content_length = Some(cl);
+            }
+        }
+    }
+
+    resp_content_length
+}
+
+// HEAD, 1xx, 204 and 304 responses never carry a body
+fn no_body(method: &http_types::Method, code: u16) -> bool {
+    *method == http_types::Method::Head || matches!(code, 100..=199 | 204 | 304)
+}
+
+impl Capture {
+    // False once the body is over the limit, the capture is then dropped
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        if self.body.len() + data.len() > self.limit {
+            return false;
+        }
+
+        self.body.extend(data);
+        true
+    }
+}
+
+impl SendFile {
+    pub fn new(file: fs::File, offset: u64, remaining: u64) -> Self {
+        Self {
+            file,
+            offset,
+            remaining,
+        }
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    // Never send more than the app's Content-Length
+    pub fn limit(&mut self, length: u64) {
+        self.remaining = cmp::min(self.remaining, length);
+    }
+
+    // Returns an empty chunk once the file is exhausted
+    pub fn read_chunk(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        let len = cmp::min(max as u64, self.remaining) as usize;
+        let mut chunk = vec![0; len];
+
+        let bytes_read = self.file.read_at(&mut chunk, self.offset)?;
+        chunk.truncate(bytes_read);
+        self.advance(bytes_read);
+
+        Ok(chunk)
+    }
+
+    // Zero copy, straight from the page cache onto the socket
+    pub fn send_to(&mut self, fd: RawFd) -> io::Result<usize> {
+        let count = cmp::min(self.remaining, isize::MAX as u64) as usize;
+        let mut offset = self.offset as libc::off_t;
+
+        let sent = unsafe { libc::sendfile(fd, self.file.as_raw_fd(), &mut offset, count) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.advance(sent as usize);
+        Ok(sent as usize)
+    }
+
+    fn advance(&mut self, len: usize) {
+        // The file has been truncated since we looked at its size
+        if len == 0 {
+            self.remaining = 0;
+        }
+
+        self.offset += len as u64;
+        self.remaining -= len as u64;
+    }
+}
+
 #[derive(Clone)]
 pub struct Context {
     pub trace_id: String,
@@ -14,9 +14,11 @@ mod server;
 use server::run_server;
 mod worker;
 use worker::run_worker;
+mod errorpages;
 mod errors;
 use errors::{fatal_io_error, RuntimeError, RuntimeResult};
 mod pythonexec;
+mod tls;
 mod workq;
 
 fn main() {
@@ -61,6 +63,30 @@ fn run(
     let listener = TcpListener::bind(cfg.bind_addr)
         .map_err(|err| fatal_io_error("couldn't bind tcp listener on port", err))?;
 
+    // Load certificates before fork, so a bad configuration fails at startup
+    let tls = if cfg.tls_enabled() {
+        tls::register_sighup()
+            .map_err(|err| fatal_io_error("couldn't register SIGHUP handler", err))?;
+
+        match tls::load_server_config(&cfg) {
+            Ok(server_config) => Some(server_config),
+            Err(s) => {
+                error!("couldn't load tls certificates", { error = &s });
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let error_pages = match errorpages::ErrorPages::load(&cfg) {
+        Ok(error_pages) => Arc::new(error_pages),
+        Err(s) => {
+            error!("couldn't load error templates", { error = &s });
+            process::exit(1);
+        }
+    };
+
     let mut parent_socks = vec![];
 
     // Ctrl-C handler in server
@@ -81,7 +107,7 @@ fn run(
                 // reading running and close_now currently does not work.
                 // GH-20
                 ctrlc_handler(running, close_now);
-                return run_worker(cfg, application, sock2);
+                return run_worker(cfg, application, sock2, tls, error_pages);
             }
             Err(_) => return Err(RuntimeError::ForkFailed),
         }
@@ -94,7 +120,9 @@ fn run(
         "cfg.num_threads"      : usize = cfg.num_threads,
         "cfg.max_connections"  : usize = cfg.max_conns,
         "cfg.max_requests"     : usize = cfg.max_requests,
-        "cfg.return_stacktrace": bool  = cfg.body_stacktrace
+        "cfg.return_stacktrace": bool  = cfg.body_stacktrace,
+        "cfg.tls"              : bool  = cfg.tls_enabled(),
+        "cfg.proxy_protocol"   : bool  = cfg.proxy_protocol
     });
 
     drop(application);
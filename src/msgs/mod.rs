@@ -5,14 +5,18 @@ use std::os::unix::prelude::RawFd;
 use fd_queue::{mio::UnixStream, DequeueFd, EnqueueFd};
 use mio::Token;
 
+use crate::http::ConnAddrs;
+
 pub struct ServerMsgBuffer {
     read_buffer: Vec<u8>,
     read_buf_len: usize,
 
     stream_tks: VecDeque<(Token, RawFd)>,
     stream_close_tks: VecDeque<(Token, RawFd)>,
+    purges: VecDeque<Purge>,
 
-    to_send: VecDeque<(Request, RawFd)>,
+    // Only stream msgs carry an fd
+    to_send: VecDeque<(ServerMsg, Option<RawFd>)>,
     write_buffer: Vec<u8>,
 }
 
@@ -24,6 +28,7 @@ impl ServerMsgBuffer {
 
             stream_tks: VecDeque::new(),
             stream_close_tks: VecDeque::new(),
+            purges: VecDeque::new(),
 
             to_send: VecDeque::new(),
             write_buffer: vec![],
@@ -48,13 +53,17 @@ impl ServerMsgBuffer {
                 break;
             }
 
-            let msg: Response =
+            let msg: WorkerMsg =
                 bincode::deserialize(&buf[4..(size + 4)]).expect("couldn't deserialize response");
 
-            if msg.keep_alive && msg.error.is_none() {
-                self.stream_tks.push_back((Token(msg.token), msg.fd));
-            } else {
-                self.stream_close_tks.push_back((Token(msg.token), msg.fd));
+            match msg {
+                WorkerMsg::Stream(msg) if msg.keep_alive && msg.error.is_none() => {
+                    self.stream_tks.push_back((Token(msg.token), msg.fd));
+                }
+                WorkerMsg::Stream(msg) => {
+                    self.stream_close_tks.push_back((Token(msg.token), msg.fd));
+                }
+                WorkerMsg::Purge(purge) => self.purges.push_back(purge),
             }
 
             bytes_read += size + 4;
@@ -78,15 +87,21 @@ impl ServerMsgBuffer {
         self.stream_close_tks.pop_front().map(|(tk, _)| tk)
     }
 
+    pub fn next_purge(&mut self) -> Option<Purge> {
+        self.purges.pop_front()
+    }
+
     pub fn write_unix_stream(&mut self, stream: &mut UnixStream) -> io::Result<()> {
         while let Some((msg, fd)) = self.to_send.pop_front() {
-            if stream.enqueue(&fd).is_err() {
-                self.to_send.push_front((msg, fd));
-                break;
+            if let Some(fd) = fd {
+                if stream.enqueue(&fd).is_err() {
+                    self.to_send.push_front((msg, Some(fd)));
+                    break;
+                }
             }
 
             let msg = bincode::serialize(&msg).expect("couldn't serialize msg");
-            self.write_buffer.push(msg.len() as u8);
+            self.write_buffer.extend((msg.len() as u32).to_be_bytes());
             self.write_buffer.extend(msg);
         }
 
@@ -105,9 +120,17 @@ impl ServerMsgBuffer {
         !self.to_send.is_empty() || !self.write_buffer.is_empty()
     }
 
-    pub fn req_tcp_stream_fd(&mut self, tk: Token, fd: RawFd) {
-        let msg = Request { token: tk.0, fd };
-        self.to_send.push_back((msg, fd));
+    pub fn req_tcp_stream_fd(&mut self, tk: Token, fd: RawFd, addrs: ConnAddrs) {
+        let msg = Request {
+            token: tk.0,
+            fd,
+            addrs,
+        };
+        self.to_send.push_back((ServerMsg::Stream(msg), Some(fd)));
+    }
+
+    pub fn req_purge(&mut self, purge: Purge) {
+        self.to_send.push_back((ServerMsg::Purge(purge), None));
     }
 }
 
@@ -116,9 +139,11 @@ pub struct WorkerMsgBuffer {
     read_buf_len: usize,
     write_buffer: Vec<u8>,
 
-    server_fds: HashMap<Token, RawFd>,
+    // Our token -> (server token, server fd)
+    server_fds: HashMap<Token, (Token, RawFd)>,
     stream_fds: VecDeque<RawFd>,
     stream_msgs: VecDeque<Request>,
+    purges: VecDeque<Purge>,
 }
 
 impl WorkerMsgBuffer {
@@ -131,10 +156,15 @@ impl WorkerMsgBuffer {
             server_fds: HashMap::new(),
             stream_fds: VecDeque::new(),
             stream_msgs: VecDeque::new(),
+            purges: VecDeque::new(),
         }
     }
 
     pub fn read_unix_stream(&mut self, stream: &mut UnixStream) -> io::Result<()> {
+        if self.read_buffer.len() - self.read_buf_len < 512 {
+            self.read_buffer.resize(self.read_buffer.len() * 2, 0);
+        }
+
         let buf = &mut self.read_buffer[self.read_buf_len..];
         self.read_buf_len += stream.read(buf)?;
 
@@ -142,20 +172,23 @@ impl WorkerMsgBuffer {
         let mut buf = &self.read_buffer[..self.read_buf_len];
 
         // Take our msgs
-        while !buf.is_empty() {
-            let size = buf[0] as usize;
+        while buf.len() > 4 {
+            let size = (u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])) as usize;
 
-            if buf.len() < (size + 1) {
+            if buf.len() < (size + 4) {
                 break;
             }
 
-            let msg: Request =
-                bincode::deserialize(&buf[1..(size + 1)]).expect("couldn't deserialize request");
+            let msg: ServerMsg =
+                bincode::deserialize(&buf[4..(size + 4)]).expect("couldn't deserialize request");
 
-            self.stream_msgs.push_back(msg);
+            match msg {
+                ServerMsg::Stream(msg) => self.stream_msgs.push_back(msg),
+                ServerMsg::Purge(purge) => self.purges.push_back(purge),
+            }
 
-            bytes_read += size + 1;
-            buf = &buf[(size + 1)..];
+            bytes_read += size + 4;
+            buf = &buf[(size + 4)..];
         }
 
         let bytes_remaining = self.read_buf_len - bytes_read;
@@ -172,7 +205,7 @@ impl WorkerMsgBuffer {
         Ok(())
     }
 
-    pub fn next_stream_fd(&mut self) -> Option<(Token, RawFd)> {
+    pub fn next_stream_fd(&mut self) -> Option<(Token, RawFd, ConnAddrs)> {
         let fd = match self.stream_fds.pop_front() {
             Some(fd) => fd,
             None => return None,
@@ -187,9 +220,14 @@ impl WorkerMsgBuffer {
         };
 
         // Save the server fd
-        self.server_fds.insert(Token(msg.token), msg.fd);
+        self.server_fds
+            .insert(Token(msg.token), (Token(msg.token), msg.fd));
 
-        Some((Token(msg.token), fd))
+        Some((Token(msg.token), fd, msg.addrs))
+    }
+
+    pub fn next_purge(&mut self) -> Option<Purge> {
+        self.purges.pop_front()
     }
 
     pub fn has_data_to_send(&self) -> bool {
@@ -208,75 +246,98 @@ impl WorkerMsgBuffer {
         Ok(())
     }
 
-    pub fn resp_io_error(&mut self, tk: Token, err: io::Error) {
-        let resp = Response {
-            token: tk.0,
-            fd: self
-                .server_fds
-                .remove(&tk)
-                .expect("couldn't find server fd"),
-            keep_alive: false,
-            error: Some(format!("{}-{}", "i/o error with stream", err)),
-        };
+    // A stream the worker keeps between requests (tls) takes a new token
+    // for each request, the server still knows it by the original token
+    pub fn rekey_stream(&mut self, old_tk: Token, new_tk: Token) {
+        let server_fd = self.take_server_fd(old_tk);
+        self.server_fds.insert(new_tk, server_fd);
+    }
 
-        let msg = bincode::serialize(&resp).expect("couldn't serialize response");
-        self.write_buffer.extend((msg.len() as u32).to_be_bytes());
-        self.write_buffer.extend(msg);
+    fn take_server_fd(&mut self, tk: Token) -> (Token, RawFd) {
+        self.server_fds
+            .remove(&tk)
+            .expect("couldn't find server fd")
     }
 
-    pub fn resp_bad_client(&mut self, tk: Token) {
+    pub fn resp_io_error(&mut self, tk: Token, err: io::Error) {
+        let (server_tk, fd) = self.take_server_fd(tk);
+
         let resp = Response {
-            token: tk.0,
-            fd: self
-                .server_fds
-                .remove(&tk)
-                .expect("couldn't find server fd"),
+            token: server_tk.0,
+            fd,
             keep_alive: false,
-            error: Some("badly formed client request".to_string()),
+            error: Some(format!("{}-{}", "i/o error with stream", err)),
         };
 
-        let msg = bincode::serialize(&resp).expect("couldn't serialize response");
-        self.write_buffer.extend((msg.len() as u32).to_be_bytes());
-        self.write_buffer.extend(msg);
+        self.write_msg(&WorkerMsg::Stream(resp));
     }
 
     pub fn resp_stream_reg_error(&mut self, tk: Token, err: io::Error) {
+        let (server_tk, fd) = self.take_server_fd(tk);
+
         let resp = Response {
-            token: tk.0,
-            fd: self
-                .server_fds
-                .remove(&tk)
-                .expect("couldn't find server fd"),
+            token: server_tk.0,
+            fd,
             keep_alive: false,
             error: Some(format!("{}-{}", "couldn't register stream with mio", err)),
         };
 
-        let msg = bincode::serialize(&resp).expect("couldn't serialize response");
-        self.write_buffer.extend((msg.len() as u32).to_be_bytes());
-        self.write_buffer.extend(msg);
+        self.write_msg(&WorkerMsg::Stream(resp));
     }
 
     pub fn resp_stream_done_ok(&mut self, tk: Token, _: RawFd, keep_alive: bool) {
+        let (server_tk, fd) = self.take_server_fd(tk);
+
         let resp = Response {
-            token: tk.0,
-            fd: self
-                .server_fds
-                .remove(&tk)
-                .expect("couldn't find server fd"),
+            token: server_tk.0,
+            fd,
             keep_alive,
             error: None,
         };
 
-        let msg = bincode::serialize(&resp).expect("couldn't serialize response");
+        self.write_msg(&WorkerMsg::Stream(resp));
+    }
+
+    // The server passes it on to the other workers
+    pub fn resp_purge(&mut self, purge: Purge) {
+        self.write_msg(&WorkerMsg::Purge(purge));
+    }
+
+    fn write_msg(&mut self, msg: &WorkerMsg) {
+        let msg = bincode::serialize(msg).expect("couldn't serialize response");
         self.write_buffer.extend((msg.len() as u32).to_be_bytes());
         self.write_buffer.extend(msg);
     }
 }
 
+// Each worker has its own response cache, a purge reaches all of them
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub enum Purge {
+    // One url, after an unsafe method on it
+    Url(String),
+    // Every url under a path
+    Prefix(String),
+    // Every response tagged with the Surrogate-Key
+    Key(String),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+enum ServerMsg {
+    Stream(Request),
+    Purge(Purge),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+enum WorkerMsg {
+    Stream(Response),
+    Purge(Purge),
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 struct Request {
     token: usize,
     fd: RawFd,
+    addrs: ConnAddrs,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -1,16 +1,22 @@
 use std::fs;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
 use std::sync::Arc;
 use std::thread::Builder as ThreadBuilder;
 use std::time;
 
-use ndjsonlogger::error;
-use pyo3::exceptions::PyRuntimeError;
+use mio::Waker;
+use ndjsonlogger::{error, info};
+use pyo3::exceptions::{PyConnectionError, PyRuntimeError};
 use pyo3::prelude::*;
 use pyo3::types::PyDict;
 
 use crate::config::Config;
-use crate::http::{HttpRequest, HttpResponse};
+use crate::errorpages::ErrorPages;
+use crate::http::{Context, HttpError, HttpRequest, HttpResponse, HttpResponseHeader};
+use crate::worker::compress;
+use crate::worker::conditional::{self, header};
 use crate::workq;
 
 mod logger;
@@ -29,6 +35,8 @@ pub fn spawn(
     cfg: Arc<Config>,
     application: Application,
     server: (String, u16),
+    waker: Arc<Waker>,
+    error_pages: Arc<ErrorPages>,
 ) -> (RequestSender, CodeStartReceiver, ResponseReceiver) {
     let (resp_send, resp_recv) = channel();
     let (code_start_send, code_start_recv) = channel();
@@ -41,6 +49,8 @@ pub fn spawn(
         let req_recv = req_send.new_recv();
         let cfg = cfg.clone();
         let code_start_send = code_start_send.clone();
+        let waker = waker.clone();
+        let error_pages = error_pages.clone();
 
         ThreadBuilder::new()
             .name(format!("python-{}", n))
@@ -52,6 +62,8 @@ pub fn spawn(
                     resp_send,
                     server,
                     wsgi_callable,
+                    waker,
+                    error_pages,
                 )
             })
             .expect("couldn't spawn thread");
@@ -60,11 +72,99 @@ pub fn spawn(
     (req_send, code_start_recv, resp_recv)
 }
 
+// A wrapped file read into the body after write(), sendfile needs the header
+const FILE_READ_SIZE: usize = 65536;
+
+// Bigger body chunks are split, so the channel's slots bound its bytes
+const BODY_PIECE_SIZE: usize = 65536;
+
 enum RespBody {
     Memory(Vec<u8>),
     PyIterator(wsgi::BytesIter),
 }
 
+// The response to the request a python thread is running. The legacy
+// write() sends the header before the app returns, so the request waits here.
+pub struct Responder {
+    key: usize,
+    resp_send: Sender<(usize, HttpResponse)>,
+    waker: Arc<Waker>,
+    body_send: SyncSender<Vec<u8>>,
+    piece_size: usize,
+    // Until the header goes to the worker
+    pending: Option<(HttpRequest, Receiver<Vec<u8>>)>,
+    // The response's, once it has gone
+    aborted: Option<Arc<AtomicBool>>,
+}
+
+impl Responder {
+    pub fn headers_sent(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    // PEP 3333 write(), the first call sends the header
+    pub fn write(&mut self, data: Vec<u8>) -> PyResult<()> {
+        if let Some((http_req, body)) = self.pending.take() {
+            let resp_header = match reqlocal::response_header() {
+                Some(resp_header) => resp_header,
+                None => {
+                    self.pending = Some((http_req, body));
+                    return Err(PyRuntimeError::new_err("start_response not called"));
+                }
+            };
+
+            if !self.send_response(http_req.into_http_response(resp_header, body)) {
+                return Err(PyRuntimeError::new_err("casket worker has gone"));
+            }
+        }
+
+        if !data.is_empty() && !self.send_body(data) {
+            return Err(PyConnectionError::new_err("client has gone"));
+        }
+
+        Ok(())
+    }
+
+    // The app failed after the header was sent, the worker cuts the body short
+    // once the channel is dropped instead of ending it cleanly
+    fn abort(&self) {
+        if let Some(ref aborted) = self.aborted {
+            aborted.store(true, Ordering::Release);
+        }
+    }
+
+    fn send_response(&mut self, http_resp: HttpResponse) -> bool {
+        self.aborted = Some(http_resp.aborted.clone());
+
+        if self.resp_send.send((self.key, http_resp)).is_err() {
+            return false;
+        }
+
+        self.waker.wake().unwrap_or(());
+        true
+    }
+
+    // Blocks while the channel is full. False once the worker has dropped the response.
+    fn send_body(&self, chunk: Vec<u8>) -> bool {
+        if chunk.len() <= self.piece_size {
+            return self.send_piece(chunk);
+        }
+
+        chunk
+            .chunks(self.piece_size)
+            .all(|piece| self.send_piece(piece.to_vec()))
+    }
+
+    fn send_piece(&self, piece: Vec<u8>) -> bool {
+        if self.body_send.send(piece).is_err() {
+            return false;
+        }
+
+        self.waker.wake().unwrap_or(());
+        true
+    }
+}
+
 fn run(
     cfg: Arc<Config>,
     req_recv: workq::Receiver<(usize, HttpRequest)>,
@@ -72,12 +172,23 @@ fn run(
     resp_send: Sender<(usize, HttpResponse)>,
     server: (String, u16),
     wsgi_callable: PyObject,
+    waker: Arc<Waker>,
+    error_pages: Arc<ErrorPages>,
 ) {
     for (key, mut http_req) in req_recv {
         reqlocal::init_req_thread();
         reqlocal::set_context(http_req.context.clone());
+        let context = http_req.context.clone();
 
-        let (body_send, body) = channel();
+        // Bounded so a slow client holds the app back instead of
+        // the whole body piling up in memory
+        let piece_size = cfg.response_buffer_size.min(BODY_PIECE_SIZE);
+        let (body_send, body) = sync_channel(cfg.response_buffer_size / piece_size);
+
+        // Decoded here rather than on the worker's event loop, which a large
+        // compressed body would hold up. It isn't the app's time, so it comes
+        // before the code timeout starts.
+        let decoded = decode_body(&cfg, &mut http_req);
 
         if code_start_send
             .send((key, time::SystemTime::now()))
@@ -86,52 +197,315 @@ fn run(
             // Main thread has died
             return;
         }
+        waker.wake().unwrap_or(());
 
-        let (resp_header, resp_body) = match wsgi::execute(&wsgi_callable, &server, &mut http_req) {
-            Ok((resp_header, bytes_iter)) => (resp_header, RespBody::PyIterator(bytes_iter)),
-            Err(exec_error) => {
-                error!("python application raised exception", {
-                    trace_id                   = &http_req.context.trace_id,
-                    span_id                    = &http_req.context.span_id,
-                    parent_id   : Option<&str> = http_req.context.parent_id_as_ref(),
-                    error                      = &exec_error.value,
-                    traceback                  = &exec_error.traceback
-                });
+        let environ = decoded.map(|()| wsgi::environ(&server, &mut http_req));
 
-                let (resp_header, resp_body) = wsgi::handle_wsgi_exec_err(&cfg, exec_error);
-                (resp_header, RespBody::Memory(resp_body))
-            }
+        reqlocal::put_responder(Responder {
+            key,
+            resp_send: resp_send.clone(),
+            waker: waker.clone(),
+            body_send,
+            piece_size,
+            pending: Some((http_req, body)),
+            aborted: None,
+        });
+
+        let alive = match environ {
+            Ok(environ) => call_app(&cfg, &error_pages, &wsgi_callable, environ, &context),
+            Err(error) => bad_request(&error_pages, error),
         };
 
-        let http_resp = http_req.into_http_response(resp_header, body);
-        if resp_send.send((key, http_resp)).is_err() {
+        // The worker finds the end of the body once the channel is disconnected
+        drop(reqlocal::take_responder());
+        waker.wake().unwrap_or(());
+
+        if !alive {
             // Main process has died
             return;
         }
+    }
+}
 
-        match resp_body {
-            RespBody::Memory(body) => {
-                if body_send.send(body).is_err() {
-                    // What to do?
-                }
+// False if the main thread has died
+fn call_app(
+    cfg: &Config,
+    error_pages: &ErrorPages,
+    wsgi_callable: &PyObject,
+    environ: wsgi::ExecResult<Py<PyDict>>,
+    context: &Context,
+) -> bool {
+    let exec_result = environ.and_then(|environ| wsgi::execute(wsgi_callable, environ));
+
+    if let Err(ref exec_error) = exec_result {
+        error!("python application raised exception", {
+            trace_id                   = &context.trace_id,
+            span_id                    = &context.span_id,
+            parent_id   : Option<&str> = context.parent_id_as_ref(),
+            error                      = &exec_error.value,
+            traceback                  = &exec_error.traceback
+        });
+    }
+
+    match reqlocal::with_responder(|r| r.pending.take()).flatten() {
+        Some((http_req, body)) => respond(cfg, error_pages, http_req, body, exec_result),
+        // write() has sent the header, the iterable follows what it wrote
+        None => {
+            match exec_result {
+                Ok((_, bytes_iter)) => stream_body(bytes_iter, context),
+                // Logged above, the client has only part of the body
+                Err(_) => abort_response(),
+            }
+            true
+        }
+    }
+}
+
+// Undo the request's Content-Encoding before the app sees the body
+fn decode_body(cfg: &Config, http_req: &mut HttpRequest) -> Result<(), &'static str> {
+    if !cfg.request_decompression {
+        return Ok(());
+    }
+
+    compress::decode_request_body(
+        http_req,
+        cfg.request_max_decoded_size,
+        cfg.request_body_spool_size,
+    )
+    .map_err(|e| match e {
+        HttpError::BadValue(error) => error,
+        _ => "couldn't decode request body",
+    })
+}
+
+// The body couldn't be decoded, a 400 or 415 goes back instead of calling the app.
+// False if the main thread has died.
+fn bad_request(error_pages: &ErrorPages, error: &str) -> bool {
+    let (http_req, body) = match reqlocal::with_responder(|r| r.pending.take()).flatten() {
+        Some(pending) => pending,
+        None => return true,
+    };
+
+    info!("invalid http", {
+        error,
+        trace_id = &http_req.context.trace_id
+    });
+
+    let (resp_header, resp_body) =
+        wsgi::handle_bad_request(error_pages, &http_req.context.trace_id, error);
+    let http_resp = http_req.into_http_response(resp_header, body);
+    let no_body = http_resp.no_body;
+
+    if !send_response(http_resp) {
+        return false;
+    }
+
+    if !no_body {
+        send_body(resp_body);
+    }
+
+    true
+}
+
+// The app returned without calling write(), its header goes to the worker now.
+// False if the main thread has died.
+fn respond(
+    cfg: &Config,
+    error_pages: &ErrorPages,
+    http_req: HttpRequest,
+    body: Receiver<Vec<u8>>,
+    exec_result: wsgi::ExecResult<(HttpResponseHeader, wsgi::BytesIter)>,
+) -> bool {
+    let (mut resp_header, mut resp_body) = match exec_result {
+        Ok((resp_header, bytes_iter)) => (resp_header, RespBody::PyIterator(bytes_iter)),
+        Err(exec_error) => {
+            let (resp_header, resp_body) = wsgi::handle_wsgi_exec_err(
+                cfg,
+                error_pages,
+                &http_req.context.trace_id,
+                exec_error,
+            );
+            (resp_header, RespBody::Memory(resp_body))
+        }
+    };
+
+    // wsgi.file_wrapper over a regular file - the worker sends it
+    let resp_file = match resp_body {
+        RespBody::PyIterator(ref mut bytes_iter) => bytes_iter.take_file(),
+        RespBody::Memory(_) => None,
+    };
+
+    if let Some(ref file) = resp_file {
+        if !resp_header
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        {
+            resp_header
+                .headers
+                .push(("Content-Length".to_string(), file.remaining().to_string()));
+        }
+    }
+
+    let get_or_head = matches!(
+        http_req.method,
+        http_types::Method::Get | http_types::Method::Head
+    );
+
+    // A weak ETag for a body the app already has in memory
+    if cfg.etag_max_size > 0
+        && get_or_head
+        && resp_header.code == 200
+        && resp_file.is_none()
+        && header(&resp_header.headers, "ETag").is_none()
+    {
+        let body = match resp_body {
+            RespBody::PyIterator(ref bytes_iter) => bytes_iter.buffered(cfg.etag_max_size),
+            RespBody::Memory(_) => None,
+        };
+
+        if let Some(body) = body {
+            resp_header
+                .headers
+                .push(("ETag".to_string(), conditional::weak_etag(&body)));
+
+            if let RespBody::PyIterator(bytes_iter) =
+                mem::replace(&mut resp_body, RespBody::Memory(body))
+            {
+                close_iterable(bytes_iter, &http_req.context);
+            }
+        }
+    }
+
+    // The client's copy is current - a 304, the body is thrown away below
+    if resp_header.code == 200
+        && conditional::not_modified(
+            &http_req.method,
+            &http_req.headers,
+            header(&resp_header.headers, "ETag"),
+            header(&resp_header.headers, "Last-Modified"),
+        )
+    {
+        resp_header = conditional::not_modified_header(resp_header.headers);
+    }
+
+    let context = http_req.context.clone();
+    let mut http_resp = http_req.into_http_response(resp_header, body);
+    let no_body = http_resp.no_body;
+
+    let sendfile = match resp_file {
+        Some(mut file) if !no_body => {
+            if let Some(cl) = http_resp.resp_content_length {
+                file.limit(cl as u64);
             }
-            RespBody::PyIterator(mut bytes_iter) => {
-                // Stream the response body
-                loop {
-                    match bytes_iter.next() {
-                        Ok(None) => break,
-                        Err(_) => {
-                            // What to do here? We've already sent the header
-                        }
-                        Ok(Some(body_chunk)) => {
-                            if body_send.send(body_chunk).is_err() {
-                                // Again - how to handle!? Main thread has crashed!?
-                            }
-                        }
+            http_resp.resp_file = Some(file);
+            true
+        }
+        _ => false,
+    };
+
+    if !send_response(http_resp) {
+        if let RespBody::PyIterator(bytes_iter) = resp_body {
+            close_iterable(bytes_iter, &context);
+        }
+        return false;
+    }
+
+    match resp_body {
+        // HEAD, 204 and 304 - the body would be thrown away, stop the app producing it.
+        // A sent file has been dup'd so the app is free to close its own.
+        RespBody::PyIterator(bytes_iter) if no_body || sendfile => {
+            close_iterable(bytes_iter, &context);
+        }
+        RespBody::Memory(_) if no_body => {}
+        RespBody::Memory(body) => {
+            send_body(body);
+        }
+        RespBody::PyIterator(bytes_iter) => stream_body(bytes_iter, &context),
+    }
+
+    true
+}
+
+fn stream_body(mut bytes_iter: wsgi::BytesIter, context: &Context) {
+    // After write() the header has gone, a wrapped file is read into the body
+    if let Some(mut file) = bytes_iter.take_file() {
+        loop {
+            match file.read_chunk(FILE_READ_SIZE) {
+                Ok(chunk) if chunk.is_empty() => break,
+                Ok(chunk) => {
+                    if !send_body(chunk) {
+                        break;
                     }
                 }
+                Err(e) => {
+                    error!("failed to read wrapped file", {
+                        trace_id                   = &context.trace_id,
+                        span_id                    = &context.span_id,
+                        parent_id   : Option<&str> = context.parent_id_as_ref(),
+                        error                      = &e.to_string()
+                    });
+                    abort_response();
+                    break;
+                }
             }
         }
+
+        close_iterable(bytes_iter, context);
+        return;
+    }
+
+    loop {
+        match bytes_iter.next() {
+            Ok(None) => break,
+            Err(exec_error) => {
+                // The header has gone, all we can do is cut the body short
+                error!("python iterable raised exception mid-stream", {
+                    trace_id                   = &context.trace_id,
+                    span_id                    = &context.span_id,
+                    parent_id   : Option<&str> = context.parent_id_as_ref(),
+                    error                      = &exec_error.value,
+                    traceback                  = &exec_error.traceback
+                });
+                abort_response();
+                break;
+            }
+            Ok(Some(body_chunk)) => {
+                // Blocks while the channel is full. The GIL is only held
+                // inside next(), so other python threads carry on meanwhile.
+                if !send_body(body_chunk) {
+                    // The worker dropped the response, the client has gone
+                    break;
+                }
+            }
+        }
+    }
+
+    // PEP 3333 - however the body ended, frameworks tear down the request here
+    close_iterable(bytes_iter, context);
+}
+
+fn send_response(http_resp: HttpResponse) -> bool {
+    reqlocal::with_responder(|r| r.send_response(http_resp)).unwrap_or(false)
+}
+
+fn send_body(chunk: Vec<u8>) -> bool {
+    reqlocal::with_responder(|r| r.send_body(chunk)).unwrap_or(false)
+}
+
+fn abort_response() {
+    reqlocal::with_responder(|r| r.abort());
+}
+
+fn close_iterable(bytes_iter: wsgi::BytesIter, context: &Context) {
+    if let Err(exec_error) = bytes_iter.close() {
+        error!("python iterable close() raised exception", {
+            trace_id                   = &context.trace_id,
+            span_id                    = &context.span_id,
+            parent_id   : Option<&str> = context.parent_id_as_ref(),
+            error                      = &exec_error.value,
+            traceback                  = &exec_error.traceback
+        });
     }
 }
 
@@ -2,9 +2,12 @@ use std::cell::RefCell;
 
 use crate::http::{Context, HttpResponseHeader};
 
+use super::Responder;
+
 thread_local! {
     static CONTEXT: RefCell<Option<Context>> = RefCell::new(None);
     static RESPONSE_HEADER: RefCell<Option<HttpResponseHeader>> = RefCell::new(None);
+    static RESPONDER: RefCell<Option<Responder>> = RefCell::new(None);
 }
 
 pub fn set_context(ctx: Context) {
@@ -18,6 +21,7 @@ pub fn get_context() -> Option<Context> {
 pub fn init_req_thread() {
     CONTEXT.with(|c| *(c.borrow_mut()) = None);
     RESPONSE_HEADER.with(|r| *(r.borrow_mut()) = None);
+    RESPONDER.with(|r| *(r.borrow_mut()) = None);
 }
 
 pub fn put_response_header(resp_header: HttpResponseHeader) {
@@ -27,3 +31,24 @@ pub fn put_response_header(resp_header: HttpResponseHeader) {
 pub fn take_response_header() -> Option<HttpResponseHeader> {
     RESPONSE_HEADER.with(|r| r.take().take())
 }
+
+pub fn response_header() -> Option<HttpResponseHeader> {
+    RESPONSE_HEADER.with(|r| r.borrow().clone())
+}
+
+pub fn has_response_header() -> bool {
+    RESPONSE_HEADER.with(|r| r.borrow().is_some())
+}
+
+pub fn put_responder(responder: Responder) {
+    RESPONDER.with(|r| *(r.borrow_mut()) = Some(responder));
+}
+
+pub fn take_responder() -> Option<Responder> {
+    RESPONDER.with(|r| r.take())
+}
+
+// None outside of a request
+pub fn with_responder<T>(f: impl FnOnce(&mut Responder) -> T) -> Option<T> {
+    RESPONDER.with(|r| r.borrow_mut().as_mut().map(f))
+}
@@ -1,3 +1,6 @@
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::result;
 
 use pyo3::exceptions::{PyRuntimeError, PyValueError};
@@ -5,7 +8,11 @@ use pyo3::prelude::*;
 use pyo3::types::{PyBytes, PyDict, PyIterator, PyList, PyString, PyTuple};
 
 use crate::config::Config;
-use crate::http::{HttpRequest, HttpResponseHeader as ResponseHeader};
+use crate::errorpages::{ErrorPage, ErrorPages};
+use crate::http::{
+    ClientCert, HttpRequest, HttpResponseHeader as ResponseHeader, RequestBody, SendFile,
+};
+use crate::worker::compress;
 use ndjsonlogger::error;
 
 use super::reqlocal;
@@ -18,38 +25,55 @@ pub struct ExecError {
 
 pub type ExecResult<T> = result::Result<T, ExecError>;
 
+pub fn environ(server: &(String, u16), http_req: &mut HttpRequest) -> ExecResult<Py<PyDict>> {
+    Python::with_gil(|py| build_environ(py, server, http_req).map_err(|e| build_exec_err(py, e)))
+}
+
 pub fn execute(
     wsgi_callable: &PyObject,
-    server: &(String, u16),
-    http_req: &mut HttpRequest,
+    environ: Py<PyDict>,
 ) -> ExecResult<(ResponseHeader, BytesIter)> {
-    execute_inner(wsgi_callable, server, http_req)
+    execute_inner(wsgi_callable, environ)
         .map_err(|py_err| Python::with_gil(|py| build_exec_err(py, py_err)))
 }
 
 fn execute_inner(
     wsgi_callable: &PyObject,
-    server: &(String, u16),
-    http_req: &mut HttpRequest,
+    environ: Py<PyDict>,
 ) -> PyResult<(ResponseHeader, BytesIter)> {
     let start_response = StartResponse {};
-    let bytes_iter = Python::with_gil(|py| -> PyResult<Py<PyIterator>> {
-        let environ = build_environ(py, server, http_req)?;
+    let (iterable, bytes_iter) = Python::with_gil(|py| -> PyResult<(PyObject, Py<PyIterator>)> {
         let start_response = Py::new(py, start_response)?;
 
-        wsgi_callable
-            .call1(py, (environ, start_response))
-            .and_then(|b| PyIterator::from_object(py, b.as_ref(py)))
-            .map(|b| b.into())
+        let iterable = wsgi_callable.call1(py, (environ, start_response))?;
+        let bytes_iter = PyIterator::from_object(py, iterable.as_ref(py))?;
+
+        Ok((iterable, bytes_iter.into()))
     })?;
 
-    let response_header = reqlocal::take_response_header()
-        .ok_or_else(|| PyRuntimeError::new_err("start_response not called"))?;
+    let response_header = match reqlocal::take_response_header() {
+        Some(response_header) => response_header,
+        None => {
+            close_iterable(&iterable);
+            return Err(PyRuntimeError::new_err("start_response not called"));
+        }
+    };
 
-    let bytes_iter = BytesIter::new(bytes_iter)?;
+    let bytes_iter = BytesIter::new(iterable, bytes_iter)?;
     Ok((response_header, bytes_iter))
 }
 
+// The app's exception is the one reported, an error from close() is dropped
+fn close_iterable(iterable: &PyObject) {
+    Python::with_gil(|py| {
+        let iterable = iterable.as_ref(py);
+
+        if iterable.hasattr("close").unwrap_or(false) {
+            iterable.call_method0("close").map(|_| ()).unwrap_or(());
+        }
+    })
+}
+
 fn build_exec_err(py: Python, py_err: PyErr) -> ExecError {
     let traceback = py_err.traceback(py).map(|t| t.format()).transpose();
 
@@ -67,14 +91,71 @@ fn build_exec_err(py: Python, py_err: PyErr) -> ExecError {
     }
 }
 
-pub fn handle_wsgi_exec_err(cfg: &Config, err: ExecError) -> (ResponseHeader, Vec<u8>) {
-    if cfg.body_stacktrace {
+pub fn handle_wsgi_exec_err(
+    cfg: &Config,
+    error_pages: &ErrorPages,
+    trace_id: &str,
+    err: ExecError,
+) -> (ResponseHeader, Vec<u8>) {
+    // The exception is only shown to clients allowed to see the stacktrace
+    let error = if cfg.body_stacktrace {
+        Some(err.value.as_str())
+    } else {
+        None
+    };
+
+    if let Some(page) = error_pages.render(500, "Internal Server Error", trace_id, error) {
+        handle_python_exc_page(page)
+    } else if cfg.body_stacktrace {
         handle_python_exc_body_stacktrace(err)
     } else {
         handle_python_exc_empty()
     }
 }
 
+// A body we couldn't decode, the app never sees the request.
+// A coding we don't know is a 415 listing the ones we do.
+pub fn handle_bad_request(
+    error_pages: &ErrorPages,
+    trace_id: &str,
+    error: &str,
+) -> (ResponseHeader, Vec<u8>) {
+    let unsupported = error == compress::UNSUPPORTED_CODING;
+    let (code, reason) = if unsupported {
+        (415, "Unsupported Media Type")
+    } else {
+        (400, "Bad Request")
+    };
+
+    // Without a template the error message is the body
+    let page = error_pages
+        .render(code, reason, trace_id, Some(error))
+        .unwrap_or_else(|| ErrorPage {
+            content_type: "text/plain; charset=UTF-8",
+            body: error.as_bytes().to_vec(),
+        });
+
+    let mut headers = vec![
+        ("Content-Length".to_string(), page.body.len().to_string()),
+        ("Content-Type".to_string(), page.content_type.to_string()),
+    ];
+
+    if unsupported {
+        headers.push((
+            "Accept-Encoding".to_string(),
+            compress::DECODABLE_CODINGS.to_string(),
+        ));
+    }
+
+    let resp_header = ResponseHeader {
+        code,
+        reason: reason.to_string(),
+        headers,
+    };
+
+    (resp_header, page.body)
+}
+
 fn handle_python_exc_body_stacktrace(err: ExecError) -> (ResponseHeader, Vec<u8>) {
     let headers = vec![
         (
@@ -97,6 +178,21 @@ fn handle_python_exc_body_stacktrace(err: ExecError) -> (ResponseHeader, Vec<u8>
     (resp_header, err.traceback.into_bytes())
 }
 
+fn handle_python_exc_page(page: ErrorPage) -> (ResponseHeader, Vec<u8>) {
+    let headers = vec![
+        ("Content-Length".to_string(), page.body.len().to_string()),
+        ("Content-Type".to_string(), page.content_type.to_string()),
+    ];
+
+    let resp_header = ResponseHeader {
+        code: 500,
+        reason: String::from("Internal Server Error"),
+        headers,
+    };
+
+    (resp_header, page.body)
+}
+
 fn handle_python_exc_empty() -> (ResponseHeader, Vec<u8>) {
     let resp_header = ResponseHeader {
         code: 500,
@@ -109,20 +205,82 @@ fn handle_python_exc_empty() -> (ResponseHeader, Vec<u8>) {
 
 pub struct BytesIter {
     next_val: Option<Vec<u8>>,
+    iterable: PyObject,
     bytes_iter: Py<PyIterator>,
+    file: Option<SendFile>,
 }
 
 impl BytesIter {
-    fn new(bytes_iter: Py<PyIterator>) -> PyResult<Self> {
-        let next_val = next_body_chunk(&bytes_iter)?;
+    fn new(iterable: PyObject, bytes_iter: Py<PyIterator>) -> PyResult<Self> {
+        // A wrapped regular file is sent by the worker, we don't iterate it
+        let first = Python::with_gil(|py| wrapped_file(iterable.as_ref(py))).and_then(|file| {
+            let next_val = match file {
+                Some(_) => None,
+                None => next_body_chunk(&bytes_iter)?,
+            };
+            Ok((file, next_val))
+        });
+
+        let (file, next_val) = match first {
+            Ok(first) => first,
+            Err(e) => {
+                close_iterable(&iterable);
+                return Err(e);
+            }
+        };
+
         Ok(Self {
             next_val,
+            iterable,
             bytes_iter,
+            file,
+        })
+    }
+
+    pub fn take_file(&mut self) -> Option<SendFile> {
+        self.file.take()
+    }
+
+    // PEP 3333 - call close() on the iterable the app returned, if it has one
+    pub fn close(self) -> ExecResult<()> {
+        Python::with_gil(|py| {
+            let iterable = self.iterable.as_ref(py);
+
+            if iterable.hasattr("close")? {
+                iterable.call_method0("close")?;
+            }
+
+            Ok(())
         })
+        .map_err(|py_err| Python::with_gil(|py| build_exec_err(py, py_err)))
     }
 
-    pub fn next(&mut self) -> PyResult<Option<Vec<u8>>> {
-        let next_val = next_body_chunk(&self.bytes_iter)?;
+    // The whole body, if the app returned a list or tuple of bytes no larger
+    // than limit. A generator might be slow, it's never read ahead.
+    pub fn buffered(&self, limit: usize) -> Option<Vec<u8>> {
+        Python::with_gil(|py| {
+            let iterable = self.iterable.as_ref(py);
+            let items: Vec<&PyAny> = match iterable.downcast::<PyList>() {
+                Ok(list) => list.iter().collect(),
+                Err(_) => iterable.downcast::<PyTuple>().ok()?.iter().collect(),
+            };
+
+            let mut body = vec![];
+            for item in items {
+                let bytes = item.downcast::<PyBytes>().ok()?.as_bytes();
+                if body.len() + bytes.len() > limit {
+                    return None;
+                }
+                body.extend(bytes);
+            }
+
+            Some(body)
+        })
+    }
+
+    pub fn next(&mut self) -> ExecResult<Option<Vec<u8>>> {
+        let next_val = next_body_chunk(&self.bytes_iter)
+            .map_err(|py_err| Python::with_gil(|py| build_exec_err(py, py_err)))?;
         let this_val = self.next_val.take();
         self.next_val = next_val;
         Ok(this_val)
@@ -142,27 +300,117 @@ fn next_body_chunk(iter: &Py<PyIterator>) -> PyResult<Option<Vec<u8>>> {
     })
 }
 
+// wsgi.file_wrapper over something with a file descriptor. Anything
+// else, e.g a pipe or BytesIO, is read through the iterator protocol.
+fn wrapped_file(iterable: &PyAny) -> PyResult<Option<SendFile>> {
+    let wrapper = match iterable.downcast::<PyCell<FileWrapper>>() {
+        Ok(wrapper) => wrapper,
+        Err(_) => return Ok(None),
+    };
+    let filelike = wrapper.borrow().filelike.clone_ref(iterable.py());
+    let filelike = filelike.as_ref(iterable.py());
+
+    if !filelike.hasattr("fileno")? {
+        return Ok(None);
+    }
+
+    // io.BytesIO has fileno() but raises from it
+    let fd = match filelike
+        .call_method0("fileno")
+        .and_then(|fd| fd.extract::<RawFd>())
+    {
+        Ok(fd) => fd,
+        Err(_) => return Ok(None),
+    };
+
+    // The app may have read part of the file already
+    let offset = match filelike
+        .call_method0("tell")
+        .and_then(|pos| pos.extract::<u64>())
+    {
+        Ok(offset) => offset,
+        Err(_) => return Ok(None),
+    };
+
+    // Our own descriptor, the app closes its file once the response is done
+    let dup_fd = unsafe { libc::dup(fd) };
+    if dup_fd < 0 {
+        return Ok(None);
+    }
+    let file = unsafe { fs::File::from_raw_fd(dup_fd) };
+
+    let metadata = match file.metadata() {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(None),
+    };
+
+    let remaining = metadata.len().saturating_sub(offset);
+    Ok(Some(SendFile::new(file, offset, remaining)))
+}
+
+// PEP 3333 forbids the app setting these, they'd fight with casket's own framing
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
 #[pyclass]
 struct StartResponse {}
 
 #[pymethods]
 impl StartResponse {
-    fn __call__(&self, status: &str, headers: &PyList) -> PyResult<()> {
+    #[args(exc_info = "None")]
+    fn __call__(
+        &self,
+        py: Python,
+        status: &str,
+        headers: &PyAny,
+        exc_info: Option<&PyAny>,
+    ) -> PyResult<Py<Write>> {
+        let headers_sent = reqlocal::with_responder(|r| r.headers_sent()).unwrap_or(true);
+
+        match exc_info {
+            // Too late to change the response, the app's exception goes back up
+            Some(exc_info) if headers_sent => return Err(PyErr::from_value(exc_info.get_item(1)?)),
+            // An error page replaces the headers
+            Some(_) => {}
+            None if headers_sent || reqlocal::has_response_header() => {
+                return Err(PyRuntimeError::new_err(
+                    "start_response already called without exc_info",
+                ));
+            }
+            None => {}
+        }
+
         let mut resp_headers = vec![];
 
         let (code, reason) = parse_status(status)?;
 
-        for h in headers {
-            let h: &PyTuple = h.downcast()?;
+        // Any iterable of (str, str) tuples
+        for h in headers.iter()? {
+            let h: &PyTuple = h?.downcast()?;
 
             if h.len() != 2 {
-                return Err(PyValueError::new_err("headers should be a list of tuples"));
+                return Err(PyValueError::new_err(
+                    "headers should be (name, value) tuples",
+                ));
             }
 
             let key: &PyString = h.get_item(0)?.downcast()?;
             let value: &PyString = h.get_item(1)?.downcast()?;
 
-            resp_headers.push((key.to_string(), value.to_string()));
+            let key = key.to_string();
+            let value = value.to_string();
+            validate_header(&key, &value)?;
+
+            resp_headers.push((key, value));
         }
 
         reqlocal::put_response_header(ResponseHeader {
@@ -171,7 +419,27 @@ impl StartResponse {
             headers: resp_headers,
         });
 
-        Ok(())
+        Py::new(py, Write {})
+    }
+}
+
+// The legacy write() callable start_response returns
+#[pyclass]
+struct Write {}
+
+#[pymethods]
+impl Write {
+    fn __call__(&self, py: Python, data: &PyBytes) -> PyResult<()> {
+        let data = data.as_bytes().to_vec();
+
+        // Blocks while the body channel is full, other python threads carry on
+        py.allow_threads(|| {
+            reqlocal::with_responder(|r| r.write(data)).unwrap_or_else(|| {
+                Err(PyRuntimeError::new_err(
+                    "write() called after the response ended",
+                ))
+            })
+        })
     }
 }
 
@@ -195,14 +463,51 @@ fn build_environ(
         environ.set_item("CONTENT_TYPE", content_type)?;
     }
 
-    if let Some(body) = http_req.body.as_ref() {
-        environ.set_item("CONTENT_LENGTH", body.len())?;
+    if http_req.body.is_some() {
+        environ.set_item("CONTENT_LENGTH", http_req.content_length)?;
     }
 
     environ.set_item("SERVER_NAME", &server.0)?;
     environ.set_item("SERVER_PORT", server.1)?;
 
-    environ.set_item("SERVER_PROTOCOL", "HTTP/1.1")?;
+    if let Some(addrs) = http_req.addrs {
+        environ.set_item("REMOTE_ADDR", addrs.remote.ip().to_string())?;
+        environ.set_item("REMOTE_PORT", addrs.remote.port().to_string())?;
+        environ.set_item("SERVER_ADDR", addrs.local.ip().to_string())?;
+    }
+
+    environ.set_item("SERVER_PROTOCOL", http_req.version.as_str())?;
+
+    if let Some(tls) = http_req.tls.as_ref() {
+        environ.set_item("HTTPS", "on")?;
+        if let Some(server_name) = tls.server_name.as_ref() {
+            environ.set_item("SSL_TLS_SNI", server_name)?;
+        }
+        if let Some(protocol) = tls.protocol.as_ref() {
+            environ.set_item("SSL_PROTOCOL", protocol)?;
+        }
+
+        match tls.client_cert.as_ref() {
+            None => environ.set_item("SSL_CLIENT_VERIFY", "NONE")?,
+            Some(client_cert) => {
+                environ.set_item("SSL_CLIENT_VERIFY", "SUCCESS")?;
+                environ.set_item("SSL_CLIENT_S_DN", &client_cert.subject)?;
+                environ.set_item("SSL_CLIENT_I_DN", &client_cert.issuer)?;
+                environ.set_item("SSL_CLIENT_M_SERIAL", &client_cert.serial)?;
+                environ.set_item("SSL_CLIENT_FINGERPRINT", &client_cert.fingerprint)?;
+
+                for (n, name) in client_cert.dns_names.iter().enumerate() {
+                    environ.set_item(format!("SSL_CLIENT_SAN_DNS_{}", n), name)?;
+                }
+                for (n, email) in client_cert.emails.iter().enumerate() {
+                    environ.set_item(format!("SSL_CLIENT_SAN_Email_{}", n), email)?;
+                }
+                for (n, uri) in client_cert.uris.iter().enumerate() {
+                    environ.set_item(format!("SSL_CLIENT_SAN_URI_{}", n), uri)?;
+                }
+            }
+        }
+    }
 
     // Headers
     for (name, value) in http_req.headers.iter_mut() {
@@ -212,9 +517,9 @@ fn build_environ(
     }
 
     environ.set_item("wsgi.version", (1, 0))?;
-    environ.set_item("wsgi.url_scheme", "http")?;
+    environ.set_item("wsgi.url_scheme", http_req.url.scheme())?;
 
-    let body = http_req.body.take().unwrap_or_default();
+    let body = http_req.body.take().unwrap_or(RequestBody::Memory(vec![]));
     environ.set_item("wsgi.input", Py::new(py, WsgiInput::new(body))?)?;
 
     environ.set_item("wsgi.errors", Py::new(py, WsgiError {})?)?;
@@ -222,6 +527,7 @@ fn build_environ(
     environ.set_item("wsgi.multithread", true)?;
     environ.set_item("wsgi.multiprocess", true)?;
     environ.set_item("wsgi.run_once", false)?;
+    environ.set_item("wsgi.file_wrapper", py.get_type::<FileWrapper>())?;
 
     // Casket specific
     let trace_ctx = TraceContext {
@@ -231,10 +537,56 @@ fn build_environ(
     };
     environ.set_item("casket.trace_ctx", Py::new(py, trace_ctx)?)?;
 
+    let client_cert = match http_req.tls.as_mut().and_then(|tls| tls.client_cert.take()) {
+        Some(client_cert) => Some(Py::new(py, ClientCertificate { client_cert })?),
+        None => None,
+    };
+    environ.set_item("casket.client_cert", client_cert)?;
+
     Ok(environ.into())
 }
 
+// Anything written verbatim onto the wire must not be able to end the header line
+fn validate_header(name: &str, value: &str) -> PyResult<()> {
+    if name.is_empty() || !name.bytes().all(is_token_char) {
+        return Err(PyValueError::new_err(format!(
+            "header name {:?} given to start_response not valid",
+            name
+        )));
+    }
+
+    if value.contains(&['\r', '\n', '\0'][..]) {
+        return Err(PyValueError::new_err(format!(
+            "header {} given to start_response contains CR, LF or NUL",
+            name
+        )));
+    }
+
+    if HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|hop_by_hop| name.eq_ignore_ascii_case(hop_by_hop))
+    {
+        return Err(PyValueError::new_err(format!(
+            "hop-by-hop header {} given to start_response",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
+// RFC 9110 tchar
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
 fn parse_status(status: &str) -> PyResult<(u16, String)> {
+    if status.contains(&['\r', '\n', '\0'][..]) {
+        return Err(PyValueError::new_err(
+            "status string given to start_response contains CR, LF or NUL",
+        ));
+    }
+
     let status_line = format!("HTTP/1.1 {}\r\n", status);
     let mut headers = [httparse::EMPTY_HEADER];
     let mut resp = httparse::Response::new(&mut headers);
@@ -255,13 +607,17 @@ fn parse_status(status: &str) -> PyResult<(u16, String)> {
 
 #[pyclass]
 pub struct WsgiInput {
-    body: Vec<u8>,
-    pos: usize,
+    body: Box<dyn BufRead + Send>,
 }
 
 impl WsgiInput {
-    pub fn new(body: Vec<u8>) -> Self {
-        Self { body, pos: 0 }
+    pub fn new(body: RequestBody) -> Self {
+        let body: Box<dyn BufRead + Send> = match body {
+            RequestBody::Memory(body) => Box::new(io::Cursor::new(body)),
+            RequestBody::Spooled(file) => Box::new(io::BufReader::new(file)),
+        };
+
+        Self { body }
     }
 }
 
@@ -274,41 +630,28 @@ struct WsgiInputIter {
 impl WsgiInput {
     #[args(size = "None")]
     fn read(&mut self, py: Python, size: Option<usize>) -> PyResult<Py<PyBytes>> {
-        let bytes_remaining = self.body.len() - self.pos;
+        let mut data = vec![];
 
-        let mut size = match size {
-            Some(sz) => sz,
-            None => bytes_remaining,
+        match size {
+            Some(sz) => (&mut self.body).take(sz as u64).read_to_end(&mut data)?,
+            None => self.body.read_to_end(&mut data)?,
         };
 
-        if size > bytes_remaining {
-            size = bytes_remaining;
-        }
-
-        let data = &self.body[self.pos..(self.pos + size)];
-        self.pos += size;
-
-        let bytes = unsafe { PyBytes::from_ptr(py, data.as_ptr(), data.len()) };
-
-        Ok(bytes.into())
+        Ok(PyBytes::new(py, &data).into())
     }
 
     #[args(size = "None")]
-    fn readline(&mut self, py: Python, _size: Option<usize>) -> PyResult<Py<PyBytes>> {
-        let remaining = &self.body[self.pos..];
-
-        for (count, &byte) in remaining.iter().enumerate() {
-            if byte == b'\n' {
-                let data = &self.body[self.pos..(self.pos + count)];
-                let bytes = unsafe { PyBytes::from_ptr(py, data.as_ptr(), data.len()) };
-                self.pos += count;
-                return Ok(bytes.into());
-            }
-        }
+    fn readline(&mut self, py: Python, size: Option<usize>) -> PyResult<Py<PyBytes>> {
+        let mut line = vec![];
+
+        match size {
+            Some(sz) => (&mut self.body)
+                .take(sz as u64)
+                .read_until(b'\n', &mut line)?,
+            None => self.body.read_until(b'\n', &mut line)?,
+        };
 
-        let bytes = unsafe { PyBytes::from_ptr(py, remaining.as_ptr(), remaining.len()) };
-        self.pos = self.body.len();
-        Ok(bytes.into())
+        Ok(PyBytes::new(py, &line).into())
     }
 
     #[args(size = "None")]
@@ -317,11 +660,15 @@ impl WsgiInput {
 
         for n in 1.. {
             let line = self.readline(py, None)?;
+            if line.as_ref(py).as_bytes().is_empty() {
+                break;
+            }
+
             list.append(line)?;
 
             if let Some(size) = size {
                 if size == n {
-                    return Ok(list.into());
+                    break;
                 }
             }
         }
@@ -341,16 +688,17 @@ impl WsgiInputIter {
         slf
     }
 
-    fn __next__(&mut self, py: Python) -> Option<u8> {
+    fn __next__(&mut self, py: Python) -> PyResult<Option<u8>> {
         let mut input = self.input.borrow_mut(py);
-        if input.pos == input.body.len() {
-            return None;
-        }
 
-        let byte = input.body[input.pos];
-        input.pos += 1;
+        let byte = match input.body.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None => return Ok(None),
+        };
+
+        input.body.consume(1);
 
-        Some(byte)
+        Ok(Some(byte))
     }
 }
 
@@ -407,3 +755,184 @@ impl TraceContext {
         self.parent_id.as_ref().map(|s| PyString::new(py, s).into())
     }
 }
+
+#[pyclass]
+pub struct FileWrapper {
+    filelike: PyObject,
+    blksize: usize,
+}
+
+#[pymethods]
+impl FileWrapper {
+    #[new]
+    #[args(blksize = "8192")]
+    fn new(filelike: PyObject, blksize: usize) -> Self {
+        Self { filelike, blksize }
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python) -> PyResult<Option<PyObject>> {
+        let data = self.filelike.call_method1(py, "read", (self.blksize,))?;
+
+        if data.as_ref(py).len()? == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(data))
+        }
+    }
+
+    fn close(&self, py: Python) -> PyResult<()> {
+        let filelike = self.filelike.as_ref(py);
+
+        if filelike.hasattr("close")? {
+            filelike.call_method0("close")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[pyclass]
+pub struct ClientCertificate {
+    client_cert: ClientCert,
+}
+
+#[pymethods]
+impl ClientCertificate {
+    #[getter]
+    fn subject(&self, py: Python) -> Py<PyString> {
+        PyString::new(py, &self.client_cert.subject).into()
+    }
+
+    #[getter]
+    fn issuer(&self, py: Python) -> Py<PyString> {
+        PyString::new(py, &self.client_cert.issuer).into()
+    }
+
+    #[getter]
+    fn serial(&self, py: Python) -> Py<PyString> {
+        PyString::new(py, &self.client_cert.serial).into()
+    }
+
+    #[getter]
+    fn fingerprint(&self, py: Python) -> Py<PyString> {
+        PyString::new(py, &self.client_cert.fingerprint).into()
+    }
+
+    #[getter]
+    fn dns_names(&self, py: Python) -> Py<PyList> {
+        PyList::new(py, &self.client_cert.dns_names).into()
+    }
+
+    #[getter]
+    fn emails(&self, py: Python) -> Py<PyList> {
+        PyList::new(py, &self.client_cert.emails).into()
+    }
+
+    #[getter]
+    fn uris(&self, py: Python) -> Py<PyList> {
+        PyList::new(py, &self.client_cert.uris).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_token_char, parse_status, validate_header, HOP_BY_HOP_HEADERS};
+
+    fn status(status: &str) -> Option<(u16, String)> {
+        parse_status(status).ok()
+    }
+
+    #[test]
+    fn token_chars() {
+        for b in b"azAZ09!#$%&'*+-.^_`|~".iter() {
+            assert!(is_token_char(*b), "{}", *b as char);
+        }
+
+        for b in b" \t\r\n\0\x7f:;,/\\\"()<>=?@[]{}".iter() {
+            assert!(!is_token_char(*b), "{:?}", *b as char);
+        }
+
+        assert!(!is_token_char(0xc3));
+    }
+
+    #[test]
+    fn valid_headers() {
+        assert!(validate_header("Content-Type", "text/html; charset=utf-8").is_ok());
+        assert!(validate_header("X-Custom_Header!", "").is_ok());
+        assert!(validate_header("Set-Cookie", "a=b; Path=/; \tHttpOnly").is_ok());
+    }
+
+    #[test]
+    fn bad_header_names() {
+        for name in [
+            "",
+            "X Header",
+            "X-Header:",
+            "X-Header\r\nSet-Cookie",
+            "X-Header\n",
+            "X-Header\0",
+            "X-Héader",
+            "(X-Header)",
+        ] {
+            assert!(validate_header(name, "value").is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn bad_header_values() {
+        for value in ["a\r\nSet-Cookie: b", "a\rb", "a\nb", "a\0b", "\r\n"] {
+            assert!(validate_header("X-Header", value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn hop_by_hop_headers() {
+        for name in HOP_BY_HOP_HEADERS.iter() {
+            let upper = name.to_ascii_uppercase();
+            let title = name
+                .split('-')
+                .map(|part| format!("{}{}", part[..1].to_ascii_uppercase(), &part[1..]))
+                .collect::<Vec<String>>()
+                .join("-");
+
+            for name in [name.to_string(), upper, title] {
+                assert!(validate_header(&name, "value").is_err(), "{}", name);
+            }
+        }
+
+        // Only the exact names
+        assert!(validate_header("X-Connection", "close").is_ok());
+        assert!(validate_header("Tea", "earl grey").is_ok());
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(status("200 OK"), Some((200, "OK".to_string())));
+        assert_eq!(
+            status("404 Not Found"),
+            Some((404, "Not Found".to_string()))
+        );
+        assert_eq!(status("599 Custom"), Some((599, "Custom".to_string())));
+    }
+
+    #[test]
+    fn bad_statuses() {
+        for value in [
+            "",
+            "OK",
+            "20 OK",
+            "2000 OK",
+            "200 OK\r\nSet-Cookie: a=b",
+            "200 OK\r",
+            "200 OK\n",
+            "200 OK\0",
+            "\r\n200 OK",
+        ] {
+            assert!(status(value).is_none(), "{:?}", value);
+        }
+    }
+}
@@ -11,19 +11,34 @@ use ndjsonlogger::{debug, info, warn};
 
 use crate::config::Config;
 use crate::errors::{fatal_io_error, RuntimeError, RuntimeResult};
+use crate::http::ConnAddrs;
+use crate::tls;
 
+mod proxyproto;
 mod unixstreams;
 use unixstreams::{UnixStream as ServerUnixStream, UnixStreams as ServerUnixStreams};
 
 // Assign a number to each new stream
 // Assuming usize is 64 bits, we have a maxmimum of (2^64) / (2^24) = 1_099_511_627_776 streams
-const NEW_STREAM_COUNT_INC: usize = 1 << 24;
+pub const NEW_STREAM_COUNT_INC: usize = 1 << 24;
 
 // Amount to increment counter for a second request on a keep-alive stream
 // We have (2^24)/(2^7) = 131_072 possible requests on a single stream.
 // Worker may use range [REQUEST_COUNT + 1, REQUEST_COUNT + 127]
 // So a single HTTP request may spawn up to 127 additional items (see worker)
-const KEEP_ALIVE_COUNT_INC: usize = 1 << 7;
+pub const KEEP_ALIVE_COUNT_INC: usize = 1 << 7;
+
+// Poll timeout when we must check for SIGHUP (tls reload)
+// or for PROXY protocol headers which haven't arrived
+const SIGNAL_CHECK_TIME: time::Duration = time::Duration::from_secs(1);
+
+struct ClientStream {
+    tcp_stream: TcpStream,
+    // None until we've read the PROXY protocol header
+    addrs: Option<ConnAddrs>,
+    // The PROXY protocol header must arrive by then
+    header_deadline: Option<time::SystemTime>,
+}
 
 pub fn run_server(
     cfg: Arc<Config>,
@@ -44,8 +59,10 @@ pub fn run_server(
         .map_err(|err| fatal_io_error("server couldn't register tcp listener", err))?;
 
     let mut tk_num = 1;
+    let mut worker_pids = vec![];
     let mut server_unix_streams = vec![];
-    for (_, unix_stream) in unix_streams {
+    for (pid, unix_stream) in unix_streams {
+        worker_pids.push(pid);
         server_unix_streams.push(ServerUnixStream::new(Token(tk_num), unix_stream));
         tk_num += 1;
     }
@@ -55,7 +72,7 @@ pub fn run_server(
 
     let mut errors = Vec::with_capacity(32);
     let mut reading_streams = HashMap::new();
-    let mut processing_streams = HashMap::<Token, TcpStream>::new();
+    let mut processing_streams = HashMap::<Token, ClientStream>::new();
 
     let mut run_shutdown = false;
     let mut ctrlc_instant: Option<time::SystemTime> = None;
@@ -74,8 +91,11 @@ pub fn run_server(
                 Ok(elapsed) => {
                     if elapsed >= cfg.ctrlc_wait_time {
                         // Send shutdown to all sockets
-                        for (_, tcp_stream) in processing_streams.drain() {
-                            tcp_stream.shutdown(std::net::Shutdown::Both).unwrap_or(());
+                        for (_, client) in processing_streams.drain() {
+                            client
+                                .tcp_stream
+                                .shutdown(std::net::Shutdown::Both)
+                                .unwrap_or(());
                         }
                         break Ok(());
                     }
@@ -88,10 +108,11 @@ pub fn run_server(
             break Ok(());
         }
 
+        unix_streams.forward_purges();
         errors.extend(unix_streams.reregister(poll.registry()));
 
         for tk in unix_streams.next_stream_tks() {
-            let mut tcp_stream = processing_streams
+            let mut client = processing_streams
                 .remove(&tk)
                 .expect("couldn't find processing tream");
 
@@ -99,38 +120,58 @@ pub fn run_server(
             let new_tk = Token(tk.0 + KEEP_ALIVE_COUNT_INC);
 
             if run_shutdown {
-                if let Err(e) = tcp_stream.shutdown(std::net::Shutdown::Both) {
+                if let Err(e) = client.tcp_stream.shutdown(std::net::Shutdown::Both) {
                     errors.push(e);
                 }
             } else {
                 if let Err(e) =
                     poll.registry()
-                        .register(&mut tcp_stream, new_tk, Interest::READABLE)
+                        .register(&mut client.tcp_stream, new_tk, Interest::READABLE)
                 {
                     errors.push(e);
                     continue;
                 }
 
-                reading_streams.insert(new_tk, tcp_stream);
+                reading_streams.insert(new_tk, client);
             }
         }
 
         for tk in unix_streams.next_stream_close_tks() {
-            let tcp_stream = processing_streams
+            let client = processing_streams
                 .remove(&tk)
                 .expect("couldn't find processing tream");
 
-            if let Err(e) = tcp_stream.shutdown(std::net::Shutdown::Both) {
+            if let Err(e) = client.tcp_stream.shutdown(std::net::Shutdown::Both) {
                 errors.push(e);
             }
         }
 
         let timeout = if run_shutdown {
             Some(time::Duration::from_millis(100))
+        } else if cfg.tls_enabled() || cfg.proxy_protocol {
+            Some(SIGNAL_CHECK_TIME)
         } else {
             None
         };
-        let poll_res = poll.poll(&mut events, timeout);
+        let mut poll_res = poll.poll(&mut events, timeout);
+
+        // SIGHUP - workers reload their tls certificates
+        if tls::take_sighup() {
+            info!("reloading tls certificates");
+            for pid in worker_pids.iter() {
+                unsafe {
+                    libc::kill(*pid, libc::SIGHUP);
+                }
+            }
+
+            if matches!(&poll_res, Err(e) if e.kind() == io::ErrorKind::Interrupted) {
+                poll_res = Ok(());
+            }
+        }
+
+        if cfg.proxy_protocol {
+            errors.extend(close_expired(&mut reading_streams, &poll));
+        }
 
         // Check we're running
         if (poll_res.is_err() || !running.load(Ordering::SeqCst)) && !run_shutdown {
@@ -153,6 +194,21 @@ pub fn run_server(
                         continue;
                     }
 
+                    let (addrs, header_deadline) = if cfg.proxy_protocol {
+                        (
+                            None,
+                            Some(time::SystemTime::now() + cfg.header_read_timeout),
+                        )
+                    } else {
+                        match proxyproto::stream_addrs(&tcp_stream) {
+                            Ok(addrs) => (Some(addrs), None),
+                            Err(e) => {
+                                errors.push(e);
+                                continue;
+                            }
+                        }
+                    };
+
                     if let Err(err) =
                         poll.registry()
                             .register(&mut tcp_stream, tk, Interest::READABLE)
@@ -161,19 +217,55 @@ pub fn run_server(
                         continue;
                     }
 
-                    reading_streams.insert(tk, tcp_stream);
+                    reading_streams.insert(
+                        tk,
+                        ClientStream {
+                            tcp_stream,
+                            addrs,
+                            header_deadline,
+                        },
+                    );
                 }
                 continue;
             }
 
-            if let Some(mut tcp_stream) = reading_streams.remove(&ev.token()) {
-                if let Err(e) = poll.registry().deregister(&mut tcp_stream) {
+            if let Some(mut client) = reading_streams.remove(&ev.token()) {
+                let addrs = match client.addrs {
+                    Some(addrs) => addrs,
+                    None => match proxyproto::read_header(&mut client.tcp_stream) {
+                        Ok(Some(addrs)) => addrs,
+                        // Wait for the rest of the header
+                        Ok(None) => {
+                            reading_streams.insert(ev.token(), client);
+                            continue;
+                        }
+                        Err(e) => {
+                            info!("couldn't read proxy protocol header", {
+                                error = &format!("{}", e)
+                            });
+
+                            let res = poll
+                                .registry()
+                                .deregister(&mut client.tcp_stream)
+                                .and_then(|_| client.tcp_stream.shutdown(std::net::Shutdown::Both));
+
+                            if let Err(e) = res {
+                                errors.push(e);
+                            }
+                            continue;
+                        }
+                    },
+                };
+                client.addrs = Some(addrs);
+                client.header_deadline = None;
+
+                if let Err(e) = poll.registry().deregister(&mut client.tcp_stream) {
                     errors.push(e);
                     continue;
                 }
 
-                unix_streams.msg_send_tcp_stream(ev.token(), tcp_stream.as_raw_fd());
-                processing_streams.insert(ev.token(), tcp_stream);
+                unix_streams.msg_send_tcp_stream(ev.token(), client.tcp_stream.as_raw_fd(), addrs);
+                processing_streams.insert(ev.token(), client);
 
                 continue;
             }
@@ -203,9 +295,42 @@ pub fn run_server(
     }
 }
 
+// Streams which connected but never sent their PROXY protocol header
+fn close_expired(
+    reading_streams: &mut HashMap<Token, ClientStream>,
+    poll: &Poll,
+) -> Vec<io::Error> {
+    let now = time::SystemTime::now();
+    let expired: Vec<Token> = reading_streams
+        .iter()
+        .filter(|(_, client)| matches!(client.header_deadline, Some(deadline) if deadline <= now))
+        .map(|(tk, _)| *tk)
+        .collect();
+
+    let mut io_errs = vec![];
+
+    for tk in expired {
+        let mut client = reading_streams
+            .remove(&tk)
+            .expect("couldn't find reading stream");
+        info!("timed out reading proxy protocol header");
+
+        let res = poll
+            .registry()
+            .deregister(&mut client.tcp_stream)
+            .and_then(|_| client.tcp_stream.shutdown(std::net::Shutdown::Both));
+
+        if let Err(e) = res {
+            io_errs.push(e);
+        }
+    }
+
+    io_errs
+}
+
 fn shutdown(
     listener: &mut TcpListener,
-    reading_streams: &mut HashMap<Token, TcpStream>,
+    reading_streams: &mut HashMap<Token, ClientStream>,
     poll: &Poll,
 ) -> Vec<io::Error> {
     info!("casket is shutting down");
@@ -218,11 +343,11 @@ fn shutdown(
     }
 
     // shutdown all idle tcp streams
-    for (_, mut stream) in reading_streams.drain() {
+    for (_, mut client) in reading_streams.drain() {
         let res = poll
             .registry()
-            .deregister(&mut stream)
-            .and_then(|_| stream.shutdown(std::net::Shutdown::Both));
+            .deregister(&mut client.tcp_stream)
+            .and_then(|_| client.tcp_stream.shutdown(std::net::Shutdown::Both));
 
         if let Err(e) = res {
             io_errs.push(e);
@@ -0,0 +1,323 @@
+// PROXY protocol v1 (text) and v2 (binary) headers, sent by a load balancer
+// before any of the client's bytes. See haproxy's doc/proxy-protocol.txt
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use mio::net::TcpStream;
+
+use crate::http::ConnAddrs;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+// Addresses plus TLVs, we refuse anything larger
+const MAX_HEADER_LEN: usize = 536;
+
+enum Header {
+    Proxied(ConnAddrs),
+    // Health checks from the proxy itself, or an address family
+    // we can't use - the tcp stream's own addresses apply
+    Local,
+}
+
+// Returns None until the whole header has arrived. Only the header is
+// consumed, the request that follows is left for the worker.
+pub fn read_header(tcp_stream: &mut TcpStream) -> io::Result<Option<ConnAddrs>> {
+    let mut buf = [0; MAX_HEADER_LEN];
+
+    let len = match tcp_stream.peek(&mut buf) {
+        Ok(0) => return Err(invalid("stream closed before proxy protocol header")),
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let (header_len, header) = match parse(&buf[..len])? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+
+    // Already buffered by the kernel, so this can't block
+    tcp_stream.read_exact(&mut buf[..header_len])?;
+
+    match header {
+        Header::Proxied(addrs) => Ok(Some(addrs)),
+        Header::Local => stream_addrs(tcp_stream).map(Some),
+    }
+}
+
+pub fn stream_addrs(tcp_stream: &TcpStream) -> io::Result<ConnAddrs> {
+    Ok(ConnAddrs {
+        remote: tcp_stream.peer_addr()?,
+        local: tcp_stream.local_addr()?,
+    })
+}
+
+fn parse(buf: &[u8]) -> io::Result<Option<(usize, Header)>> {
+    if buf.starts_with(V2_SIGNATURE) {
+        return parse_v2(buf);
+    }
+
+    if buf.starts_with(V1_PREFIX) {
+        return parse_v1(buf);
+    }
+
+    // Could still be either signature
+    if V2_SIGNATURE.starts_with(buf) || V1_PREFIX.starts_with(buf) {
+        return Ok(None);
+    }
+
+    Err(invalid("missing proxy protocol header"))
+}
+
+// PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(usize, Header)>> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        _ => return Err(invalid("proxy protocol v1 header too long")),
+    };
+
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| invalid("proxy protocol v1 header not ascii"))?;
+    let mut fields = line.split(' ');
+
+    let header = match fields.next() {
+        Some("UNKNOWN") => Header::Local,
+        Some(proto @ "TCP4") | Some(proto @ "TCP6") => {
+            let remote_ip = parse_v1_field::<IpAddr>(fields.next())?;
+            let local_ip = parse_v1_field::<IpAddr>(fields.next())?;
+            let remote_port = parse_v1_field::<u16>(fields.next())?;
+            let local_port = parse_v1_field::<u16>(fields.next())?;
+
+            if fields.next().is_some() {
+                return Err(invalid("proxy protocol v1 header has extra fields"));
+            }
+
+            if remote_ip.is_ipv4() != (proto == "TCP4") || local_ip.is_ipv4() != (proto == "TCP4") {
+                return Err(invalid("proxy protocol v1 address doesn't match protocol"));
+            }
+
+            Header::Proxied(ConnAddrs {
+                remote: SocketAddr::new(remote_ip, remote_port),
+                local: SocketAddr::new(local_ip, local_port),
+            })
+        }
+        _ => return Err(invalid("unknown proxy protocol v1 protocol")),
+    };
+
+    Ok(Some((end + 2, header)))
+}
+
+fn parse_v1_field<T: std::str::FromStr>(field: Option<&str>) -> io::Result<T> {
+    field
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| invalid("invalid proxy protocol v1 address"))
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(usize, Header)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0xf;
+    let family = buf[13];
+    let header_len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if version != 2 {
+        return Err(invalid("unsupported proxy protocol version"));
+    }
+
+    if header_len > MAX_HEADER_LEN {
+        return Err(invalid("proxy protocol v2 header too long"));
+    }
+
+    if buf.len() < header_len {
+        return Ok(None);
+    }
+
+    let addrs = &buf[V2_HEADER_LEN..header_len];
+
+    let header = match (command, family) {
+        // LOCAL
+        (0x0, _) => Header::Local,
+        // PROXY, TCP over IPv4
+        (0x1, 0x11) if addrs.len() >= 12 => {
+            let remote_ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let local_ip = Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+
+            Header::Proxied(ConnAddrs {
+                remote: SocketAddr::new(remote_ip.into(), read_port(&addrs[8..10])),
+                local: SocketAddr::new(local_ip.into(), read_port(&addrs[10..12])),
+            })
+        }
+        // PROXY, TCP over IPv6
+        (0x1, 0x21) if addrs.len() >= 36 => {
+            let mut remote_ip = [0; 16];
+            remote_ip.copy_from_slice(&addrs[0..16]);
+            let mut local_ip = [0; 16];
+            local_ip.copy_from_slice(&addrs[16..32]);
+
+            Header::Proxied(ConnAddrs {
+                remote: SocketAddr::new(
+                    Ipv6Addr::from(remote_ip).into(),
+                    read_port(&addrs[32..34]),
+                ),
+                local: SocketAddr::new(Ipv6Addr::from(local_ip).into(), read_port(&addrs[34..36])),
+            })
+        }
+        (0x1, 0x11) | (0x1, 0x21) => return Err(invalid("proxy protocol v2 addresses too short")),
+        // UDP, unix sockets and unspecified
+        (0x1, _) => Header::Local,
+        _ => return Err(invalid("unknown proxy protocol v2 command")),
+    };
+
+    Ok(Some((header_len, header)))
+}
+
+fn read_port(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{parse, Header, MAX_HEADER_LEN, V2_SIGNATURE};
+
+    // The header's length and its remote and local addresses, None for LOCAL
+    fn parsed(buf: &[u8]) -> (usize, Option<(SocketAddr, SocketAddr)>) {
+        match parse(buf).unwrap().unwrap() {
+            (len, Header::Proxied(addrs)) => (len, Some((addrs.remote, addrs.local))),
+            (len, Header::Local) => (len, None),
+        }
+    }
+
+    fn addrs(remote: &str, local: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((remote.parse().unwrap(), local.parse().unwrap()))
+    }
+
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family);
+        buf.extend((addrs.len() as u16).to_be_bytes());
+        buf.extend(addrs);
+        buf
+    }
+
+    const V2_TCP4: [u8; 12] = [127, 0, 0, 1, 10, 0, 0, 1, 0x1f, 0x90, 0x01, 0xbb];
+
+    #[test]
+    fn v1_tcp() {
+        let header = "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n";
+        let buf = format!("{}GET / HTTP/1.1\r\n", header);
+        assert_eq!(
+            parsed(buf.as_bytes()),
+            (header.len(), addrs("192.168.0.1:56324", "192.168.0.11:443"))
+        );
+
+        let header = "PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n";
+        assert_eq!(
+            parsed(header.as_bytes()),
+            (
+                header.len(),
+                addrs("[2001:db8::1]:4000", "[2001:db8::2]:443")
+            )
+        );
+
+        assert_eq!(parsed(b"PROXY UNKNOWN\r\n"), (15, None));
+    }
+
+    #[test]
+    fn v1_partial() {
+        for buf in [
+            "",
+            "PRO",
+            "PROXY ",
+            "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r",
+        ] {
+            assert!(parse(buf.as_bytes()).unwrap().is_none(), "{}", buf);
+        }
+    }
+
+    #[test]
+    fn v1_invalid() {
+        for buf in [
+            "GET / HTTP/1.1\r\n",
+            "PROXY UDP4 1.2.3.4 5.6.7.8 1 2\r\n",
+            "PROXY TCP4 1.2.3.4 2001:db8::2 1 2\r\n",
+            "PROXY TCP6 1.2.3.4 5.6.7.8 1 2\r\n",
+            "PROXY TCP4 1.2.3.4 5.6.7.8 1 2 3\r\n",
+            "PROXY TCP4 1.2.3.4 5.6.7.8 70000 2\r\n",
+            "PROXY TCP4 1.2.3.4 5.6.7.8 1\r\n",
+        ] {
+            assert!(parse(buf.as_bytes()).is_err(), "{}", buf);
+        }
+
+        let long = format!("PROXY {}", "x".repeat(120));
+        assert!(parse(long.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn v2_tcp() {
+        let mut buf = v2(0x1, 0x11, &V2_TCP4);
+        buf.extend(b"GET / HTTP/1.1\r\n");
+        assert_eq!(parsed(&buf), (28, addrs("127.0.0.1:8080", "10.0.0.1:443")));
+
+        // TLVs after the addresses are skipped
+        let mut with_tlvs = V2_TCP4.to_vec();
+        with_tlvs.extend([0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(
+            parsed(&v2(0x1, 0x11, &with_tlvs)),
+            (32, addrs("127.0.0.1:8080", "10.0.0.1:443"))
+        );
+
+        let mut tcp6 = vec![0; 36];
+        tcp6[15] = 1;
+        tcp6[31] = 2;
+        tcp6[32..36].copy_from_slice(&[0x0f, 0xa0, 0x01, 0xbb]);
+        assert_eq!(
+            parsed(&v2(0x1, 0x21, &tcp6)),
+            (52, addrs("[::1]:4000", "[::2]:443"))
+        );
+    }
+
+    #[test]
+    fn v2_local() {
+        assert_eq!(parsed(&v2(0x0, 0x00, &[])), (16, None));
+        // UDP uses the stream's own addresses
+        assert_eq!(parsed(&v2(0x1, 0x12, &V2_TCP4)), (28, None));
+    }
+
+    #[test]
+    fn v2_partial() {
+        let buf = v2(0x1, 0x11, &V2_TCP4);
+
+        for len in [5, 12, 15, 16, 27] {
+            assert!(parse(&buf[..len]).unwrap().is_none(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn v2_invalid() {
+        // Version 1 in the binary format
+        let mut buf = v2(0x1, 0x11, &V2_TCP4);
+        buf[12] = 0x11;
+        assert!(parse(&buf).is_err());
+
+        assert!(parse(&v2(0x2, 0x11, &V2_TCP4)).is_err());
+        assert!(parse(&v2(0x1, 0x11, &V2_TCP4[..8])).is_err());
+        assert!(parse(&v2(0x1, 0x21, &[0; 20])).is_err());
+
+        // Too long is refused before the rest arrives
+        let buf = v2(0x1, 0x11, &vec![0; MAX_HEADER_LEN]);
+        assert!(parse(&buf[..16]).is_err());
+    }
+}
@@ -4,6 +4,7 @@ use std::os::unix::prelude::RawFd;
 use fd_queue::mio::UnixStream as MioUnixStream;
 use mio::{Interest, Registry, Token};
 
+use crate::http::ConnAddrs;
 use crate::msgs;
 
 #[derive(Clone, Copy)]
@@ -140,9 +141,9 @@ impl UnixStream {
         }
     }
 
-    fn msg_send_tcp_stream(&mut self, tk: Token, fd: RawFd) {
+    fn msg_send_tcp_stream(&mut self, tk: Token, fd: RawFd, addrs: ConnAddrs) {
         self.num_reqs += 1;
-        self.msg_buffer.req_tcp_stream_fd(tk, fd);
+        self.msg_buffer.req_tcp_stream_fd(tk, fd, addrs);
     }
 }
 
@@ -189,7 +190,20 @@ impl UnixStreams {
         tks
     }
 
-    pub fn msg_send_tcp_stream(&mut self, tk: Token, fd: RawFd) {
+    // A worker purged its response cache, the others purge theirs
+    pub fn forward_purges(&mut self) {
+        for n in 0..self.streams.len() {
+            while let Some(purge) = self.streams[n].msg_buffer.next_purge() {
+                for (m, stream) in self.streams.iter_mut().enumerate() {
+                    if m != n {
+                        stream.msg_buffer.req_purge(purge.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn msg_send_tcp_stream(&mut self, tk: Token, fd: RawFd, addrs: ConnAddrs) {
         let mut ind = 0;
         let mut num_reqs = usize::MAX;
 
@@ -203,7 +217,7 @@ impl UnixStreams {
         self.streams
             .get_mut(ind)
             .unwrap()
-            .msg_send_tcp_stream(tk, fd);
+            .msg_send_tcp_stream(tk, fd, addrs);
     }
 
     pub fn reregister(&mut self, registry: &Registry) -> Vec<io::Error> {
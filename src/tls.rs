@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader};
+use std::mem;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ResolvesServerCert,
+};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+use x509_parser::extensions::GeneralName;
+
+use crate::config::Config;
+use crate::http::ClientCert;
+
+static SIGHUP: AtomicBool = AtomicBool::new(false);
+
+pub fn load_server_config(cfg: &Config) -> Result<Arc<ServerConfig>, String> {
+    let (cert_path, key_path) = match (cfg.tls_cert.as_ref(), cfg.tls_key.as_ref()) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Err("tls certificate and key not configured".to_string()),
+    };
+
+    let default = Arc::new(load_certified_key(cert_path, key_path)?);
+
+    let mut sni = HashMap::new();
+    for (server_name, cert_path, key_path) in cfg.tls_sni_certs.iter() {
+        let certified_key = load_certified_key(cert_path, key_path)?;
+        sni.insert(server_name.to_ascii_lowercase(), Arc::new(certified_key));
+    }
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match cfg.tls_client_ca.as_ref() {
+        None => builder.with_no_client_auth(),
+        Some(ca_path) => {
+            let roots = load_client_ca(ca_path)?;
+
+            // A certificate which fails verification is always rejected in the handshake,
+            // optionally clients without a certificate are let through
+            if cfg.tls_client_cert_required {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            } else {
+                builder.with_client_cert_verifier(
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                )
+            }
+        }
+    };
+
+    let mut server_config = builder.with_cert_resolver(Arc::new(CertResolver { default, sni }));
+
+    server_config.alpn_protocols = match cfg.tls_alpn {
+        Some(ref protos) => protos
+            .iter()
+            .filter(|proto| cfg.http2 || proto.as_str() != "h2")
+            .map(|proto| proto.as_bytes().to_vec())
+            .collect(),
+        None if cfg.http2 => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        None => vec![b"http/1.1".to_vec()],
+    };
+
+    Ok(Arc::new(server_config))
+}
+
+// Choose a certificate by SNI, falling back to the default
+struct CertResolver {
+    default: Arc<CertifiedKey>,
+    sni: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(server_name) = client_hello.server_name() {
+            if let Some(certified_key) = self.sni.get(&server_name.to_ascii_lowercase()) {
+                return Some(certified_key.clone());
+            }
+        }
+
+        Some(self.default.clone())
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+    let certs = fs::File::open(cert_path)
+        .map(BufReader::new)
+        .and_then(|mut reader| rustls_pemfile::certs(&mut reader))
+        .map_err(|e| format!("couldn't read {} - {}", cert_path.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert_path.display()));
+    }
+
+    let key = load_private_key(key_path)?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| format!("unsupported private key type in {}", key_path.display()))?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        signing_key,
+    ))
+}
+
+fn load_client_ca(ca_path: &Path) -> Result<RootCertStore, String> {
+    let certs = fs::File::open(ca_path)
+        .map(BufReader::new)
+        .and_then(|mut reader| rustls_pemfile::certs(&mut reader))
+        .map_err(|e| format!("couldn't read {} - {}", ca_path.display(), e))?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(format!("no ca certificates found in {}", ca_path.display()));
+    }
+
+    Ok(roots)
+}
+
+// Details of the (already verified) client certificate for environ
+pub fn client_cert(certs: &[Certificate]) -> Option<ClientCert> {
+    let der = &certs.first()?.0;
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+
+    let mut client_cert = ClientCert {
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        serial: hex::encode_upper(cert.raw_serial()),
+        fingerprint: hex::encode(Sha256::digest(der)),
+        dns_names: vec![],
+        emails: vec![],
+        uris: vec![],
+    };
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            match name {
+                GeneralName::DNSName(s) => client_cert.dns_names.push(s.to_string()),
+                GeneralName::RFC822Name(s) => client_cert.emails.push(s.to_string()),
+                GeneralName::URI(s) => client_cert.uris.push(s.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    Some(client_cert)
+}
+
+fn load_private_key(key_path: &Path) -> Result<PrivateKey, String> {
+    let mut reader = fs::File::open(key_path)
+        .map(BufReader::new)
+        .map_err(|e| format!("couldn't read {} - {}", key_path.display(), e))?;
+
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| format!("couldn't read {} - {}", key_path.display(), e))?;
+
+        match item {
+            Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => return Err(format!("no private key found in {}", key_path.display())),
+        }
+    }
+}
+
+// SIGHUP reloads certificates
+// The server forwards the signal to each worker
+pub fn register_sighup() -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        // NOTE: No SA_RESTART, so a blocked poll returns with EINTR
+        if libc::sigaction(libc::SIGHUP, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    SIGHUP.store(true, Ordering::SeqCst);
+}
+
+pub fn take_sighup() -> bool {
+    SIGHUP.swap(false, Ordering::SeqCst)
+}
@@ -1,16 +1,14 @@
 use std::result;
 use std::time;
 
-use mio::{net::TcpStream, Token};
+use mio::Token;
 
-use crate::http::{HttpError, HttpRequest, HttpResponse};
+use crate::errorpages::{ErrorPage, ErrorPages};
+use crate::http::{Context, HttpError, HttpRequest, HttpResponse};
 
 use super::serverreader;
 use super::serverwriter;
-
-const HTTP_408_RESPONSE: &[u8] = include_bytes!("http408");
-const HTTP_503_RESPONSE: &[u8] = include_bytes!("http503");
-const HTTP_504_RESPONSE: &[u8] = include_bytes!("http504");
+use super::stream::Stream;
 
 pub struct CasketResponse {
     pub code: u16,
@@ -20,61 +18,149 @@ pub struct CasketResponse {
 }
 
 pub enum Action {
-    NewServerRequest((Token, TcpStream)),
-    ServerContinueRead((Token, serverreader::Reader, TcpStream)),
-    ServerReadDone((Token, Box<HttpRequest>, TcpStream)),
-    ServerStreamEOF((Token, TcpStream)),
+    NewServerRequest((Token, Stream)),
+    ServerContinueRead((Token, serverreader::Reader, Stream)),
+    ServerReadDone((Token, Box<HttpRequest>, Stream)),
+    ServerStreamEOF((Token, Stream)),
+    // The client sent the HTTP/2 preface, the bytes read so far
+    Http2Start((Token, Vec<u8>, Stream)),
     ServerNewResponse((Token, Box<HttpResponse>)),
-    ServerContinueWrite((Token, serverwriter::Writer, TcpStream)),
-    ServerDoneWrite((Token, Box<HttpResponse>, TcpStream)),
+    ServerContinueWrite((Token, serverwriter::Writer, Stream)),
+    // Nothing to write until python sends more of the body
+    ServerWaitBody((Token, serverwriter::Writer, Stream)),
+    ServerDoneWrite((Token, Box<HttpResponse>, Stream)),
 
-    ServerCasketResponseNew((Token, TcpStream, CasketResponse)),
-    ServerCasketResponseContinue((Token, TcpStream, CasketResponse)),
-    ServerCasketResponseDone((Token, TcpStream, CasketResponse)),
+    ServerCasketResponseNew((Token, Stream, CasketResponse)),
+    ServerCasketResponseContinue((Token, Stream, CasketResponse)),
+    ServerCasketResponseDone((Token, Stream, CasketResponse)),
 
     ServerPythonCodeTimeoutNew((Token, time::SystemTime)),
 }
 
-pub fn new_408_timeout(tk: Token, tcp_stream: TcpStream) -> Action {
-    Action::ServerCasketResponseNew((
+pub fn new_400_bad_request(
+    tk: Token,
+    tcp_stream: Stream,
+    pages: &ErrorPages,
+    ctx: &Context,
+    error: &str,
+) -> Action {
+    // Without a template the error message is the body
+    let page = pages
+        .render(400, "Bad Request", &ctx.trace_id, Some(error))
+        .unwrap_or_else(|| ErrorPage {
+            content_type: "text/plain; charset=UTF-8",
+            body: error.as_bytes().to_vec(),
+        });
+
+    casket_response(
         tk,
         tcp_stream,
-        CasketResponse {
-            code: 408,
-            response: HTTP_408_RESPONSE.to_vec(),
-            reason: "request read timeout",
-            bytes_sent: 0,
-        },
-    ))
+        (400, "Bad Request", "bad request"),
+        ctx,
+        Some(error),
+        Some(page),
+    )
 }
 
-pub fn new_503_service_busy(tk: Token, tcp_stream: TcpStream) -> Action {
-    Action::ServerCasketResponseNew((
+pub fn new_408_timeout(tk: Token, tcp_stream: Stream, pages: &ErrorPages) -> Action {
+    // The request never arrived, so give the response a trace id of its own
+    let ctx = Context::new();
+    let page = pages.render(408, "Request Timeout", &ctx.trace_id, None);
+
+    casket_response(
         tk,
         tcp_stream,
-        CasketResponse {
-            code: 503,
-            response: HTTP_503_RESPONSE.to_vec(),
-            reason: "service busy",
-            bytes_sent: 0,
-        },
-    ))
+        (408, "Request Timeout", "request read timeout"),
+        &ctx,
+        None,
+        page,
+    )
 }
 
-pub fn new_504_gateway_timeout(tk: Token, tcp_stream: TcpStream) -> Action {
+pub fn new_503_service_busy(
+    tk: Token,
+    tcp_stream: Stream,
+    pages: &ErrorPages,
+    ctx: &Context,
+) -> Action {
+    let page = pages.render(503, "Service Busy", &ctx.trace_id, None);
+
+    casket_response(
+        tk,
+        tcp_stream,
+        (503, "Service Busy", "service busy"),
+        ctx,
+        None,
+        page,
+    )
+}
+
+pub fn new_504_gateway_timeout(
+    tk: Token,
+    tcp_stream: Stream,
+    pages: &ErrorPages,
+    ctx: &Context,
+) -> Action {
+    let page = pages.render(504, "Gateway Timeout", &ctx.trace_id, None);
+
+    casket_response(
+        tk,
+        tcp_stream,
+        (504, "Gateway Timeout", "gateway timeout"),
+        ctx,
+        None,
+        page,
+    )
+}
+
+// status is (code, reason phrase, reason logged)
+fn casket_response(
+    tk: Token,
+    tcp_stream: Stream,
+    status: (u16, &str, &'static str),
+    ctx: &Context,
+    error: Option<&str>,
+    page: Option<ErrorPage>,
+) -> Action {
+    let (code, phrase, reason) = status;
+    let mut response = vec![];
+
+    response.extend(format!("HTTP/1.1 {} {}\r\n", code, phrase).as_bytes());
+    if let Some(ref page) = page {
+        response.extend(format!("Content-Type: {}\r\n", page.content_type).as_bytes());
+    }
+    response.extend(
+        format!(
+            "Content-Length: {}\r\n",
+            page.as_ref().map(|page| page.body.len()).unwrap_or(0)
+        )
+        .as_bytes(),
+    );
+    if let Some(error) = error {
+        response.extend(format!("X-Error: {}\r\n", error).as_bytes());
+    }
+    response.extend(format!("X-TraceId: {}\r\n", ctx.trace_id).as_bytes());
+    response.extend(b"Server: Casket\r\n");
+    response.extend(b"Connection: Close\r\n");
+    response.extend(b"\r\n");
+    if let Some(page) = page {
+        response.extend(page.body);
+    }
+
     Action::ServerCasketResponseNew((
         tk,
         tcp_stream,
         CasketResponse {
-            code: 504,
-            response: HTTP_504_RESPONSE.to_vec(),
-            reason: "gateway timeout",
+            code,
+            response,
+            reason,
             bytes_sent: 0,
         },
     ))
 }
 
-pub type WorkerResult<T> = result::Result<T, Error>;
+// Boxed, the error carries the stream and the request's context
+pub type WorkerResult<T> = result::Result<T, Box<Error>>;
 pub type ActionResult = WorkerResult<Action>;
 
 #[derive(Clone, Copy)]
@@ -86,5 +172,7 @@ pub struct Error {
     pub source: ErrorSource,
     pub error: HttpError,
     pub token: Token,
-    pub tcp_stream: TcpStream,
+    pub tcp_stream: Stream,
+    // The request's, if the header was parsed before the error
+    pub context: Option<Context>,
 }
@@ -0,0 +1,537 @@
+// Responses the app gave a lifetime (Cache-Control, Expires) are kept here and
+// served again without python. Each worker has its own cache, purges reach all
+// of them through the server.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc;
+use std::time;
+
+use crate::config::Config;
+use crate::http::{date, Capture, HttpRequest, HttpResponse, HttpResponseHeader};
+use crate::msgs::Purge;
+
+use super::conditional::{header, not_modified, not_modified_header};
+
+// Responses cacheable by default (RFC 9110 section 15.1), less 204 and 206
+const CACHEABLE_CODES: [u16; 7] = [200, 203, 300, 301, 308, 404, 410];
+
+// For casket only, these never reach the client
+const PURGE_HEADER: &str = "X-Casket-Purge";
+const PURGE_KEY_HEADER: &str = "X-Casket-Purge-Key";
+const SURROGATE_KEY_HEADER: &str = "Surrogate-Key";
+
+pub struct Cache {
+    max_size: usize,
+    max_entry_size: usize,
+    size: usize,
+
+    entries: HashMap<u64, Entry>,
+    // Url -> one entry for each variant (Vary)
+    urls: HashMap<String, Vec<u64>>,
+    // Last use -> entry, the first is evicted
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    next_id: u64,
+}
+
+struct Entry {
+    url: String,
+    path: String,
+    // Request headers named by Vary and their values for this variant
+    vary: Vec<(String, Option<String>)>,
+    surrogate_keys: Vec<String>,
+
+    code: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+
+    stored: time::SystemTime,
+    ttl: time::Duration,
+    tick: u64,
+    size: usize,
+}
+
+impl Cache {
+    // None when CASKET_CACHE_SIZE is 0
+    pub fn new(cfg: &Config) -> Option<Self> {
+        if cfg.cache_size == 0 {
+            return None;
+        }
+
+        Some(Self {
+            max_size: cfg.cache_size,
+            max_entry_size: cfg.cache_max_entry_size.min(cfg.cache_size),
+            size: 0,
+
+            entries: HashMap::new(),
+            urls: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            next_id: 0,
+        })
+    }
+
+    // A fresh response for the request, with its body already on the channel
+    pub fn serve(
+        &mut self,
+        http_req: Box<HttpRequest>,
+    ) -> Result<Box<HttpResponse>, Box<HttpRequest>> {
+        let servable = matches!(
+            http_req.method,
+            http_types::Method::Get | http_types::Method::Head
+        ) && header(&http_req.headers, "Authorization").is_none()
+            && !has_directive(&http_req.headers, "no-cache")
+            && !has_directive(&http_req.headers, "no-store");
+
+        if !servable {
+            return Err(http_req);
+        }
+
+        let now = time::SystemTime::now();
+        let id = match self.lookup(&http_req, now) {
+            Some(id) => id,
+            None => return Err(http_req),
+        };
+
+        self.touch(id);
+        let entry = &self.entries[&id];
+
+        let age = now
+            .duration_since(entry.stored)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut headers = entry.headers.clone();
+        headers.push(("Age".to_string(), age.to_string()));
+
+        let mut resp_header = HttpResponseHeader {
+            code: entry.code,
+            reason: entry.reason.clone(),
+            headers,
+        };
+
+        if resp_header.code == 200
+            && not_modified(
+                &http_req.method,
+                &http_req.headers,
+                header(&resp_header.headers, "ETag"),
+                header(&resp_header.headers, "Last-Modified"),
+            )
+        {
+            resp_header = not_modified_header(resp_header.headers);
+        }
+
+        // The body is complete, the writer sees the channel disconnect
+        let (body_send, body) = mpsc::sync_channel(1);
+        body_send.send(entry.body.clone()).unwrap_or(());
+        drop(body_send);
+
+        Ok(Box::new(http_req.into_http_response(resp_header, body)))
+    }
+
+    // If the app's response can be stored its headers are kept and the
+    // writer copies the body as it's sent
+    pub fn capture(&self, http_resp: &mut HttpResponse) {
+        let headers = http_resp.resp_headers.clone();
+
+        let too_large = matches!(
+            http_resp.resp_content_length,
+            Some(cl) if cl > self.max_entry_size
+        );
+
+        let storable = http_resp.method == http_types::Method::Get
+            && !http_resp.no_body
+            && http_resp.resp_file.is_none()
+            && CACHEABLE_CODES.contains(&http_resp.code)
+            && !too_large
+            && header(&http_resp.req_headers, "Authorization").is_none()
+            && !has_directive(&http_resp.req_headers, "no-store")
+            && header(&headers, "Set-Cookie").is_none()
+            && !vary_names(&headers).iter().any(|name| name == "*")
+            && freshness(&headers, time::SystemTime::now()).is_some();
+
+        if storable {
+            http_resp.capture = Some(Capture {
+                headers,
+                body: vec![],
+                limit: self.max_entry_size,
+            });
+        }
+    }
+
+    // Once the response is sent, a complete body goes in the cache
+    pub fn store(&mut self, http_resp: &mut HttpResponse) {
+        let Capture {
+            mut headers, body, ..
+        } = match http_resp.capture.take() {
+            Some(capture) => capture,
+            None => return,
+        };
+
+        // A short body means the app failed part way
+        match header(&headers, "Content-Length").map(|cl| cl.parse::<usize>()) {
+            Some(Ok(cl)) if cl == body.len() => {}
+            Some(_) => return,
+            None => headers.push(("Content-Length".to_string(), body.len().to_string())),
+        }
+
+        let now = time::SystemTime::now();
+        let ttl = match freshness(&headers, now) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        let surrogate_keys = headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(SURROGATE_KEY_HEADER))
+            .flat_map(|(_, value)| value.split_whitespace())
+            .map(|key| key.to_string())
+            .collect();
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case(SURROGATE_KEY_HEADER));
+
+        let vary = vary_names(&headers)
+            .into_iter()
+            .map(|name| {
+                let value = header(&http_resp.req_headers, &name).map(|v| v.to_string());
+                (name, value)
+            })
+            .collect();
+
+        let url = http_resp.url.as_str().to_string();
+        let size = url.len()
+            + body.len()
+            + headers
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>();
+
+        if size > self.max_entry_size {
+            return;
+        }
+
+        // A new copy of the same variant replaces the old one
+        let replaced: Vec<u64> = self
+            .urls
+            .get(&url)
+            .map(|ids| {
+                ids.iter()
+                    .copied()
+                    .filter(|id| self.entries[id].vary == vary)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for id in replaced {
+            self.remove(id);
+        }
+
+        while self.size + size > self.max_size {
+            match self.lru.values().next().copied() {
+                Some(id) => self.remove(id),
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.next_id += 1;
+        let id = self.next_id;
+
+        self.entries.insert(
+            id,
+            Entry {
+                url: url.clone(),
+                path: http_resp.url.path().to_string(),
+                vary,
+                surrogate_keys,
+                code: http_resp.code,
+                reason: http_resp.reason.clone(),
+                headers,
+                body,
+                stored: now,
+                ttl,
+                tick: self.tick,
+                size,
+            },
+        );
+        self.urls.entry(url).or_default().push(id);
+        self.lru.insert(self.tick, id);
+        self.size += size;
+    }
+
+    pub fn purge(&mut self, purge: &Purge) {
+        let ids: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| match purge {
+                Purge::Url(url) => entry.url == *url,
+                Purge::Prefix(prefix) => entry.path.starts_with(prefix.as_str()),
+                Purge::Key(key) => entry.surrogate_keys.contains(key),
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            self.remove(id);
+        }
+    }
+
+    fn lookup(&mut self, http_req: &HttpRequest, now: time::SystemTime) -> Option<u64> {
+        let ids = self.urls.get(http_req.url.as_str())?.clone();
+        let mut found = None;
+
+        for id in ids {
+            let entry = &self.entries[&id];
+
+            if entry.stored + entry.ttl <= now {
+                self.remove(id);
+                continue;
+            }
+
+            let matches = entry
+                .vary
+                .iter()
+                .all(|(name, value)| header(&http_req.headers, name) == value.as_deref());
+
+            if matches {
+                found = Some(id);
+            }
+        }
+
+        found
+    }
+
+    fn touch(&mut self, id: u64) {
+        self.tick += 1;
+
+        if let Some(entry) = self.entries.get_mut(&id) {
+            self.lru.remove(&entry.tick);
+            self.lru.insert(self.tick, id);
+            entry.tick = self.tick;
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        let entry = match self.entries.remove(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        self.lru.remove(&entry.tick);
+        self.size -= entry.size;
+
+        if let Some(ids) = self.urls.get_mut(&entry.url) {
+            ids.retain(|i| *i != id);
+
+            if ids.is_empty() {
+                self.urls.remove(&entry.url);
+            }
+        }
+    }
+}
+
+// What the app asked to purge with X-Casket-Purge (path prefixes) and
+// X-Casket-Purge-Key (surrogate keys). A successful unsafe request also
+// purges its own url (RFC 9111 section 4.4).
+pub fn take_purges(http_resp: &mut HttpResponse) -> Vec<Purge> {
+    let mut purges = vec![];
+
+    for (name, value) in http_resp.resp_headers.iter() {
+        if name.eq_ignore_ascii_case(PURGE_HEADER) {
+            purges.extend(
+                value
+                    .split(',')
+                    .map(|prefix| prefix.trim())
+                    .filter(|prefix| !prefix.is_empty())
+                    .map(|prefix| Purge::Prefix(prefix.to_string())),
+            );
+        } else if name.eq_ignore_ascii_case(PURGE_KEY_HEADER) {
+            purges.extend(
+                value
+                    .split_whitespace()
+                    .map(|key| Purge::Key(key.to_string())),
+            );
+        }
+    }
+
+    // Before capture, so the stored copy doesn't carry them to clients
+    http_resp.resp_headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case(PURGE_HEADER) && !name.eq_ignore_ascii_case(PURGE_KEY_HEADER)
+    });
+
+    let safe = matches!(
+        http_resp.method,
+        http_types::Method::Get
+            | http_types::Method::Head
+            | http_types::Method::Options
+            | http_types::Method::Trace
+    );
+
+    if !safe && (200..400).contains(&http_resp.code) {
+        purges.push(Purge::Url(http_resp.url.as_str().to_string()));
+    }
+
+    purges
+}
+
+// Whether or not the cache is on, once it has taken what it needs
+pub fn strip_headers(http_resp: &mut HttpResponse) {
+    http_resp.resp_headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case(PURGE_HEADER)
+            && !name.eq_ignore_ascii_case(PURGE_KEY_HEADER)
+            && !name.eq_ignore_ascii_case(SURROGATE_KEY_HEADER)
+    });
+}
+
+// How long the response stays fresh, None if it mustn't be stored.
+// s-maxage wins over max-age, which wins over Expires.
+fn freshness(headers: &[(String, String)], now: time::SystemTime) -> Option<time::Duration> {
+    let directives = cache_control(headers);
+
+    if directives
+        .iter()
+        .any(|(name, _)| matches!(name.as_str(), "no-store" | "no-cache" | "private"))
+    {
+        return None;
+    }
+
+    let seconds = |directive: &str| {
+        directives
+            .iter()
+            .find(|(name, _)| name == directive)
+            .and_then(|(_, value)| value.as_ref()?.parse::<u64>().ok())
+    };
+
+    let ttl = match seconds("s-maxage").or_else(|| seconds("max-age")) {
+        Some(secs) => time::Duration::from_secs(secs),
+        // An invalid date means already expired
+        None => date::parse(header(headers, "Expires")?)?
+            .duration_since(now)
+            .ok()?,
+    };
+
+    if ttl.is_zero() {
+        None
+    } else {
+        Some(ttl)
+    }
+}
+
+fn has_directive(headers: &[(String, String)], directive: &str) -> bool {
+    cache_control(headers)
+        .iter()
+        .any(|(name, _)| name == directive)
+}
+
+// Every Cache-Control directive, lowercase name and unquoted value
+fn cache_control(headers: &[(String, String)]) -> Vec<(String, Option<String>)> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Cache-Control"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn vary_names(headers: &[(String, String)]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{freshness, vary_names};
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    fn now() -> time::SystemTime {
+        time::UNIX_EPOCH + time::Duration::from_secs(784111777)
+    }
+
+    fn secs(secs: u64) -> Option<time::Duration> {
+        Some(time::Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn freshness_max_age() {
+        let fresh = |value| freshness(&headers(&[("Cache-Control", value)]), now());
+
+        assert_eq!(fresh("max-age=60"), secs(60));
+        assert_eq!(fresh("public, max-age=\"120\""), secs(120));
+        assert_eq!(fresh("MAX-AGE=5"), secs(5));
+        assert_eq!(fresh("max-age=60, s-maxage=30"), secs(30));
+        assert_eq!(fresh("max-age=0"), None);
+        assert_eq!(fresh("max-age=soon"), None);
+        assert_eq!(fresh("public"), None);
+    }
+
+    #[test]
+    fn freshness_not_stored() {
+        for value in ["no-store, max-age=60", "max-age=60, private", "no-cache"] {
+            assert_eq!(
+                freshness(&headers(&[("Cache-Control", value)]), now()),
+                None,
+                "{}",
+                value
+            );
+        }
+
+        assert_eq!(freshness(&[], now()), None);
+    }
+
+    #[test]
+    fn freshness_expires() {
+        let fresh = |pairs: &[(&str, &str)]| freshness(&headers(pairs), now());
+
+        assert_eq!(
+            fresh(&[("Expires", "Sun, 06 Nov 1994 09:49:37 GMT")]),
+            secs(3600)
+        );
+        assert_eq!(fresh(&[("Expires", "Sun, 06 Nov 1994 07:49:37 GMT")]), None);
+        assert_eq!(fresh(&[("Expires", "Sun, 06 Nov 1994 08:49:37 GMT")]), None);
+        assert_eq!(fresh(&[("Expires", "0")]), None);
+
+        // max-age wins over Expires
+        assert_eq!(
+            fresh(&[
+                ("Expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+                ("Cache-Control", "max-age=10"),
+            ]),
+            secs(10)
+        );
+    }
+
+    #[test]
+    fn vary_names_lowercase() {
+        assert_eq!(
+            vary_names(&headers(&[
+                ("Vary", "Accept-Encoding, Accept-Language"),
+                ("Content-Type", "text/html"),
+                ("vary", "Cookie"),
+            ])),
+            vec!["accept-encoding", "accept-language", "cookie"]
+        );
+        assert_eq!(vary_names(&headers(&[("Vary", "*")])), vec!["*"]);
+        assert!(vary_names(&headers(&[("Vary", " , ")])).is_empty());
+        assert!(vary_names(&[]).is_empty());
+    }
+}
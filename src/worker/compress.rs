@@ -0,0 +1,714 @@
+// On the fly response compression, negotiated with Accept-Encoding,
+// and decoding of compressed request bodies
+use std::io::{self, Read, Write};
+use std::mem;
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+
+use crate::config::Config;
+use crate::http::{HttpError, HttpRequest, HttpResponse, RequestBody, Version};
+
+use super::serverreader::append_body;
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+// Fast settings - we compress every response as it's streamed
+const BROTLI_QUALITY: u32 = 4;
+const BROTLI_LGWIN: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+const DECODE_READ_SIZE: usize = 16384;
+
+// The request codings we decode, sent back in Accept-Encoding when a body uses another
+pub const DECODABLE_CODINGS: &str = "gzip, deflate, br";
+pub const UNSUPPORTED_CODING: &str = "unsupported request Content-Encoding";
+
+pub enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    // Picks a content coding for the response and rewrites its headers to match.
+    // Returns None if the response goes out as the app wrote it.
+    pub fn negotiate(cfg: &Config, http_resp: &mut HttpResponse) -> Option<Self> {
+        if cfg.compression.is_empty() || !compressible(cfg, http_resp) {
+            return None;
+        }
+
+        // Whether or not we compress this one, the response depends on Accept-Encoding
+        vary_accept_encoding(&mut http_resp.resp_headers);
+
+        let accept_encoding = http_resp
+            .req_headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Accept-Encoding"))
+            .map(|(_, value)| value.as_str())
+            .collect::<Vec<&str>>()
+            .join(",");
+
+        let coding = choose_coding(&accept_encoding, &cfg.compression)?;
+
+        let encoder = match coding {
+            "br" => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                vec![],
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_LGWIN,
+            ))),
+            // Sent as the app wrote it rather than fail the response
+            "zstd" => Encoder::Zstd(zstd::stream::write::Encoder::new(vec![], ZSTD_LEVEL).ok()?),
+            _ => Encoder::Gzip(GzEncoder::new(vec![], flate2::Compression::default())),
+        };
+
+        // The compressed length isn't known until the body ends, and a range
+        // of the compressed body can't be served
+        http_resp.resp_headers.retain(|(name, _)| {
+            !name.eq_ignore_ascii_case("Content-Length")
+                && !name.eq_ignore_ascii_case("Accept-Ranges")
+        });
+
+        // The ETag is for the uncompressed bytes. Weak, it still answers
+        // If-None-Match but never If-Range.
+        for (name, value) in http_resp.resp_headers.iter_mut() {
+            if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                *value = format!("W/{}", value);
+            }
+        }
+
+        http_resp
+            .resp_headers
+            .push(("Content-Encoding".to_string(), coding.to_string()));
+
+        match http_resp.version {
+            Version::Http10 => http_resp.keep_alive = false,
+            Version::Http11 => http_resp.chunked = true,
+            Version::Http2 => {}
+        }
+
+        Some(encoder)
+    }
+
+    // Each body part is flushed through, so a streamed response isn't held back
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+// Added to the app's Vary, if it has one, rather than a second Vary header
+fn vary_accept_encoding(headers: &mut Vec<(String, String)>) {
+    let covered = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|field| field.trim())
+        .any(|field| field == "*" || field.eq_ignore_ascii_case("Accept-Encoding"));
+
+    if covered {
+        return;
+    }
+
+    match headers
+        .iter_mut()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+    {
+        Some((_, value)) if !value.trim().is_empty() => value.push_str(", Accept-Encoding"),
+        Some((_, value)) => *value = "Accept-Encoding".to_string(),
+        None => headers.push(("Vary".to_string(), "Accept-Encoding".to_string())),
+    }
+}
+
+fn compressible(cfg: &Config, http_resp: &HttpResponse) -> bool {
+    // A byte range of the uncompressed body means nothing once compressed
+    if http_resp.no_body || http_resp.code == 206 {
+        return false;
+    }
+
+    if let Some(cl) = http_resp.resp_content_length {
+        if cl < cfg.compression_min_size {
+            return false;
+        }
+    }
+
+    let mut content_type = None;
+    for (name, value) in http_resp.resp_headers.iter() {
+        // Already encoded by the app
+        if name.eq_ignore_ascii_case("Content-Encoding") {
+            return false;
+        }
+
+        if name.eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(value);
+        }
+    }
+
+    let content_type = match content_type {
+        Some(content_type) => content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase(),
+        None => return false,
+    };
+
+    // An entry ending with / matches every subtype
+    cfg.compression_types.iter().any(|t| {
+        if t.ends_with('/') {
+            content_type.starts_with(t.as_str())
+        } else {
+            content_type == *t
+        }
+    })
+}
+
+// The client's highest q-value wins, ties go to the first in CASKET_COMPRESSION
+fn choose_coding<'a>(accept_encoding: &str, codings: &'a [String]) -> Option<&'a str> {
+    let mut chosen: Option<(&str, f32)> = None;
+
+    for coding in codings.iter() {
+        let q = qvalue(accept_encoding, coding);
+        if q <= 0.0 {
+            continue;
+        }
+
+        match chosen {
+            Some((_, chosen_q)) if chosen_q >= q => {}
+            _ => chosen = Some((coding, q)),
+        }
+    }
+
+    chosen.map(|(coding, _)| coding)
+}
+
+// e.g "gzip;q=0.8, br, *;q=0.1"
+pub(super) fn qvalue(accept_encoding: &str, coding: &str) -> f32 {
+    let mut wildcard = 0.0;
+
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let q = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(coding) {
+            return q;
+        }
+
+        if name == "*" {
+            wildcard = q;
+        }
+    }
+
+    wildcard
+}
+
+// Undo the request's Content-Encoding so the app sees the plain body.
+// A coding we can't decode fails the request, the app would get a body
+// which is only partly decoded or one it can't tell is encoded.
+pub fn decode_request_body(
+    http_req: &mut HttpRequest,
+    max_size: usize,
+    spool_size: usize,
+) -> Result<(), HttpError> {
+    let codings = http_req
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .collect::<Vec<String>>();
+
+    if codings.is_empty() {
+        return Ok(());
+    }
+
+    if !codings
+        .iter()
+        .all(|coding| matches!(coding.as_str(), "gzip" | "x-gzip" | "deflate" | "br"))
+    {
+        return Err(HttpError::BadValue(UNSUPPORTED_CODING));
+    }
+
+    let mut body = match http_req.body.take() {
+        Some(body) => body,
+        None => return Ok(()),
+    };
+    let mut content_length = http_req.content_length;
+
+    // Codings are listed in the order they were applied
+    for coding in codings.iter().rev() {
+        let (decoded, decoded_length) = decode(body, coding, max_size, spool_size)?;
+        body = decoded;
+        content_length = decoded_length;
+    }
+
+    http_req
+        .headers
+        .retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Encoding"));
+
+    for (name, value) in http_req.headers.iter_mut() {
+        if name.eq_ignore_ascii_case("Content-Length") {
+            *value = content_length.to_string();
+        }
+    }
+
+    http_req.content_length = content_length;
+    http_req.body = Some(body);
+
+    Ok(())
+}
+
+fn decode(
+    body: RequestBody,
+    coding: &str,
+    max_size: usize,
+    spool_size: usize,
+) -> Result<(RequestBody, usize), HttpError> {
+    let source: Box<dyn Read> = match body {
+        RequestBody::Memory(body) => Box::new(io::Cursor::new(body)),
+        RequestBody::Spooled(file) => Box::new(file),
+    };
+
+    let mut decoder: Box<dyn Read> = match coding {
+        "br" => Box::new(brotli::Decompressor::new(source, DECODE_READ_SIZE)),
+        "deflate" => Box::new(ZlibDecoder::new(source)),
+        _ => Box::new(GzDecoder::new(source)),
+    };
+
+    let mut buf = [0; DECODE_READ_SIZE];
+    let mut decoded = RequestBody::Memory(vec![]);
+    let mut length = 0;
+
+    loop {
+        let bytes_read = decoder
+            .read(&mut buf)
+            .map_err(|_| HttpError::BadValue("couldn't decode request body"))?;
+
+        if bytes_read == 0 {
+            break;
+        }
+
+        // Stop a small body which decodes to something huge
+        length += bytes_read;
+        if length > max_size {
+            return Err(HttpError::BadValue("decoded request body too large"));
+        }
+
+        append_body(&mut decoded, &buf[..bytes_read], spool_size, false)?;
+    }
+
+    append_body(&mut decoded, &[], spool_size, true)?;
+    Ok((decoded, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::sync::mpsc::sync_channel;
+
+    use flate2::write::GzEncoder;
+
+    use super::{
+        choose_coding, compressible, decode_request_body, qvalue, vary_accept_encoding, Encoder,
+        BROTLI_BUFFER_SIZE, BROTLI_LGWIN, BROTLI_QUALITY, UNSUPPORTED_CODING,
+    };
+    use crate::config::Config;
+    use crate::http::{
+        Context, HttpError, HttpRequest, HttpResponse, HttpResponseHeader, RequestBody, Version,
+    };
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn codings(codings: &[&str]) -> Vec<String> {
+        codings.iter().map(|coding| coding.to_string()).collect()
+    }
+
+    fn request(method: http_types::Method, req_headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method,
+            url: http_types::Url::parse("http://localhost/").unwrap(),
+            version: Version::Http11,
+            headers: headers(req_headers),
+            context: Context::new(),
+            keep_alive: true,
+            content_type: None,
+            content_length: 0,
+            body: Some(RequestBody::Memory(vec![])),
+            tls: None,
+            addrs: None,
+        }
+    }
+
+    fn response(code: u16, resp_headers: &[(&str, &str)]) -> HttpResponse {
+        request(http_types::Method::Get, &[("Accept-Encoding", "gzip")]).into_http_response(
+            HttpResponseHeader {
+                code,
+                reason: "OK".to_string(),
+                headers: headers(resp_headers),
+            },
+            sync_channel(1).1,
+        )
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::Gzip(GzEncoder::new(vec![], flate2::Compression::default()));
+        let mut encoded = encoder.compress(data).unwrap();
+        encoded.extend(encoder.finish().unwrap());
+        encoded
+    }
+
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+            vec![],
+            BROTLI_BUFFER_SIZE,
+            BROTLI_QUALITY,
+            BROTLI_LGWIN,
+        )));
+        let mut encoded = encoder.compress(data).unwrap();
+        encoded.extend(encoder.finish().unwrap());
+        encoded
+    }
+
+    // A request with an encoded body, as the reader would hand it over
+    fn encoded_request(content_encoding: &str, body: Vec<u8>) -> HttpRequest {
+        let mut http_req = request(
+            http_types::Method::Post,
+            &[
+                ("Content-Encoding", content_encoding),
+                ("Content-Length", &body.len().to_string()),
+            ],
+        );
+        http_req.content_length = body.len();
+        http_req.body = Some(RequestBody::Memory(body));
+        http_req
+    }
+
+    fn body(http_req: &mut HttpRequest) -> Vec<u8> {
+        match http_req.body.take() {
+            Some(RequestBody::Memory(mem)) => mem,
+            Some(RequestBody::Spooled(mut file)) => {
+                let mut data = vec![];
+                file.read_to_end(&mut data).unwrap();
+                data
+            }
+            None => vec![],
+        }
+    }
+
+    fn cfg() -> Config {
+        Config {
+            compression: codings(&["br", "gzip"]),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn qvalues() {
+        let accept = "gzip;q=0.8, BR, identity; q=0";
+        assert_eq!(qvalue(accept, "gzip"), 0.8);
+        assert_eq!(qvalue(accept, "br"), 1.0);
+        assert_eq!(qvalue(accept, "identity"), 0.0);
+        assert_eq!(qvalue(accept, "zstd"), 0.0);
+
+        // Unparsable q-values count as 1
+        assert_eq!(qvalue("gzip;q=high", "gzip"), 1.0);
+    }
+
+    #[test]
+    fn qvalue_wildcard() {
+        assert_eq!(qvalue("*;q=0.1", "br"), 0.1);
+        // Named codings win over the wildcard, in either order
+        assert_eq!(qvalue("gzip;q=0, *", "gzip"), 0.0);
+        assert_eq!(qvalue("*, gzip;q=0.5", "gzip"), 0.5);
+        assert_eq!(qvalue("*, gzip;q=0.5", "br"), 1.0);
+    }
+
+    #[test]
+    fn choose() {
+        let ours = codings(&["br", "gzip"]);
+
+        assert_eq!(choose_coding("gzip, br", &ours), Some("br"));
+        assert_eq!(choose_coding("gzip", &ours), Some("gzip"));
+        assert_eq!(choose_coding("br;q=0.5, gzip", &ours), Some("gzip"));
+        assert_eq!(choose_coding("*", &ours), Some("br"));
+        assert_eq!(choose_coding("deflate", &ours), None);
+        assert_eq!(choose_coding("", &ours), None);
+    }
+
+    #[test]
+    fn choose_refused() {
+        let ours = codings(&["br", "gzip"]);
+
+        assert_eq!(choose_coding("br;q=0, gzip;q=0", &ours), None);
+        assert_eq!(choose_coding("*;q=0", &ours), None);
+        assert_eq!(choose_coding("br;q=0, *", &ours), Some("gzip"));
+    }
+
+    #[test]
+    fn choose_ties() {
+        // The first in CASKET_COMPRESSION, not the client's order
+        let accept = "gzip;q=0.5, br;q=0.5";
+        assert_eq!(choose_coding(accept, &codings(&["br", "gzip"])), Some("br"));
+        assert_eq!(
+            choose_coding(accept, &codings(&["gzip", "br"])),
+            Some("gzip")
+        );
+    }
+
+    #[test]
+    fn vary_added() {
+        let mut resp_headers = headers(&[("Content-Type", "text/html")]);
+        vary_accept_encoding(&mut resp_headers);
+        assert_eq!(
+            resp_headers,
+            headers(&[("Content-Type", "text/html"), ("Vary", "Accept-Encoding")])
+        );
+    }
+
+    #[test]
+    fn vary_merged() {
+        for (vary, merged) in [
+            ("Cookie", "Cookie, Accept-Encoding"),
+            ("", "Accept-Encoding"),
+            ("accept-encoding", "accept-encoding"),
+            ("Cookie, Accept-Encoding", "Cookie, Accept-Encoding"),
+            ("*", "*"),
+        ] {
+            let mut resp_headers = headers(&[("vary", vary)]);
+            vary_accept_encoding(&mut resp_headers);
+            assert_eq!(resp_headers, headers(&[("vary", merged)]), "{}", vary);
+        }
+
+        // Covered by a second Vary header
+        let mut resp_headers = headers(&[("Vary", "Cookie"), ("Vary", "Accept-Encoding")]);
+        vary_accept_encoding(&mut resp_headers);
+        assert_eq!(
+            resp_headers,
+            headers(&[("Vary", "Cookie"), ("Vary", "Accept-Encoding")])
+        );
+    }
+
+    #[test]
+    fn compressible_types() {
+        let cfg = cfg();
+
+        for content_type in [
+            "text/html",
+            "text/plain; charset=utf-8",
+            "Application/JSON",
+            "image/svg+xml",
+        ] {
+            let http_resp = response(200, &[("Content-Type", content_type)]);
+            assert!(compressible(&cfg, &http_resp), "{}", content_type);
+        }
+
+        for content_type in [
+            "image/png",
+            "application/json-seq",
+            "application/octet-stream",
+        ] {
+            let http_resp = response(200, &[("Content-Type", content_type)]);
+            assert!(!compressible(&cfg, &http_resp), "{}", content_type);
+        }
+
+        assert!(!compressible(&cfg, &response(200, &[])));
+    }
+
+    #[test]
+    fn compressible_size() {
+        let cfg = cfg();
+
+        let small = response(
+            200,
+            &[("Content-Type", "text/html"), ("Content-Length", "1023")],
+        );
+        assert!(!compressible(&cfg, &small));
+
+        let large = response(
+            200,
+            &[("Content-Type", "text/html"), ("Content-Length", "1024")],
+        );
+        assert!(compressible(&cfg, &large));
+
+        // Unknown length
+        let streamed = response(200, &[("Content-Type", "text/html")]);
+        assert!(compressible(&cfg, &streamed));
+    }
+
+    #[test]
+    fn not_compressible() {
+        let cfg = cfg();
+
+        let encoded = response(
+            200,
+            &[("Content-Type", "text/html"), ("Content-Encoding", "br")],
+        );
+        assert!(!compressible(&cfg, &encoded));
+
+        assert!(!compressible(
+            &cfg,
+            &response(206, &[("Content-Type", "text/html")])
+        ));
+        assert!(!compressible(
+            &cfg,
+            &response(304, &[("Content-Type", "text/html")])
+        ));
+
+        let head = request(http_types::Method::Head, &[]).into_http_response(
+            HttpResponseHeader {
+                code: 200,
+                reason: "OK".to_string(),
+                headers: headers(&[("Content-Type", "text/html")]),
+            },
+            sync_channel(1).1,
+        );
+        assert!(!compressible(&cfg, &head));
+    }
+
+    #[test]
+    fn negotiate_headers() {
+        let mut http_resp = response(
+            200,
+            &[
+                ("Content-Type", "text/html"),
+                ("Content-Length", "2048"),
+                ("Accept-Ranges", "bytes"),
+                ("ETag", "\"abc\""),
+            ],
+        );
+
+        assert!(matches!(
+            Encoder::negotiate(&cfg(), &mut http_resp),
+            Some(Encoder::Gzip(_))
+        ));
+        assert_eq!(
+            http_resp.resp_headers,
+            headers(&[
+                ("Content-Type", "text/html"),
+                ("ETag", "W/\"abc\""),
+                ("Vary", "Accept-Encoding"),
+                ("Content-Encoding", "gzip"),
+            ])
+        );
+        assert!(http_resp.chunked);
+    }
+
+    #[test]
+    fn negotiate_disabled() {
+        let mut http_resp = response(200, &[("Content-Type", "text/html")]);
+        assert!(Encoder::negotiate(&Config::default(), &mut http_resp).is_none());
+        assert_eq!(
+            http_resp.resp_headers,
+            headers(&[("Content-Type", "text/html")])
+        );
+    }
+
+    #[test]
+    fn decode_body() {
+        let data = b"hello world".repeat(100);
+        let mut http_req = encoded_request("gzip", gzip(&data));
+
+        assert!(decode_request_body(&mut http_req, 1 << 20, 1 << 20).is_ok());
+        assert_eq!(http_req.content_length, data.len());
+        // What the app sees as CONTENT_LENGTH, with the coding gone
+        assert_eq!(
+            http_req.headers,
+            headers(&[("Content-Length", &data.len().to_string())])
+        );
+        assert_eq!(body(&mut http_req), data);
+    }
+
+    #[test]
+    fn decode_stacked() {
+        // Listed in the order applied, so br is undone first
+        let data = b"hello world".repeat(100);
+        let mut http_req = encoded_request("gzip, identity, br", brotli(&gzip(&data)));
+
+        assert!(decode_request_body(&mut http_req, 1 << 20, 1 << 20).is_ok());
+        assert_eq!(http_req.content_length, data.len());
+        assert_eq!(body(&mut http_req), data);
+    }
+
+    #[test]
+    fn decode_spooled() {
+        let data = b"hello world".repeat(100);
+        let mut http_req = encoded_request("x-gzip", gzip(&data));
+
+        assert!(decode_request_body(&mut http_req, 1 << 20, 16).is_ok());
+        assert!(matches!(http_req.body, Some(RequestBody::Spooled(_))));
+        assert_eq!(body(&mut http_req), data);
+    }
+
+    #[test]
+    fn decode_too_large() {
+        let data = vec![0; 10000];
+
+        let mut http_req = encoded_request("gzip", gzip(&data));
+        assert!(decode_request_body(&mut http_req, data.len(), 1 << 20).is_ok());
+
+        let mut http_req = encoded_request("gzip", gzip(&data));
+        assert!(matches!(
+            decode_request_body(&mut http_req, data.len() - 1, 1 << 20),
+            Err(HttpError::BadValue("decoded request body too large"))
+        ));
+    }
+
+    #[test]
+    fn decode_unsupported() {
+        for content_encoding in ["zstd", "gzip, zstd", "compress, br"] {
+            let mut http_req = encoded_request(content_encoding, b"abc".to_vec());
+            assert!(
+                matches!(
+                    decode_request_body(&mut http_req, 1 << 20, 1 << 20),
+                    Err(HttpError::BadValue(UNSUPPORTED_CODING))
+                ),
+                "{}",
+                content_encoding
+            );
+        }
+    }
+
+    #[test]
+    fn decode_identity() {
+        let mut http_req = encoded_request("identity", b"abc".to_vec());
+        assert!(decode_request_body(&mut http_req, 1 << 20, 1 << 20).is_ok());
+        assert_eq!(
+            http_req.headers,
+            headers(&[("Content-Encoding", "identity"), ("Content-Length", "3")])
+        );
+        assert_eq!(body(&mut http_req), b"abc");
+    }
+}
@@ -0,0 +1,210 @@
+// Conditional requests (RFC 9110 section 13) - If-None-Match,
+// If-Modified-Since and If-Range against a response's validators
+use sha2::{Digest, Sha256};
+
+use crate::http::{date, HttpResponseHeader};
+
+// GET and HEAD only. If-None-Match wins over If-Modified-Since.
+pub fn not_modified(
+    method: &http_types::Method,
+    req_headers: &[(String, String)],
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> bool {
+    if !matches!(method, http_types::Method::Get | http_types::Method::Head) {
+        return false;
+    }
+
+    if let Some(if_none_match) = header(req_headers, "If-None-Match") {
+        return match etag {
+            Some(etag) => etag_list_matches(if_none_match, etag),
+            None => false,
+        };
+    }
+
+    let since = header(req_headers, "If-Modified-Since").and_then(date::parse);
+    let modified = last_modified.and_then(date::parse);
+
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+// True if the Range header applies. A stale If-Range means the whole body.
+pub fn if_range_matches(
+    req_headers: &[(String, String)],
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> bool {
+    let if_range = match header(req_headers, "If-Range") {
+        Some(if_range) => if_range.trim(),
+        None => return true,
+    };
+
+    // Strong comparison, a weak ETag never matches
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return match etag {
+            Some(etag) => !etag.starts_with("W/") && if_range == etag,
+            None => false,
+        };
+    }
+
+    match last_modified {
+        Some(last_modified) => if_range == last_modified,
+        None => false,
+    }
+}
+
+// The 304 keeps the 200's headers less its representation metadata,
+// except Content-Location (RFC 9110 section 15.4.5)
+pub fn not_modified_header(mut headers: Vec<(String, String)>) -> HttpResponseHeader {
+    headers.retain(|(name, _)| {
+        let name = name.to_ascii_lowercase();
+        !name.starts_with("content-") || name == "content-location"
+    });
+
+    HttpResponseHeader {
+        code: 304,
+        reason: "Not Modified".to_string(),
+        headers,
+    }
+}
+
+// Weak, the same bytes could be sent with a different Content-Encoding
+pub fn weak_etag(body: &[u8]) -> String {
+    format!("W/\"{}\"", hex::encode(&Sha256::digest(body)[..16]))
+}
+
+pub fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+// Weak comparison, W/"x" matches "x"
+fn etag_list_matches(list: &str, etag: &str) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+
+    let etag = etag.trim_start_matches("W/");
+
+    list.split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{etag_list_matches, if_range_matches, not_modified};
+
+    fn headers(name: &str, value: &str) -> Vec<(String, String)> {
+        vec![(name.to_string(), value.to_string())]
+    }
+
+    const LAST_MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    #[test]
+    fn etag_list_weak_comparison() {
+        assert!(etag_list_matches("*", "\"a\""));
+        assert!(etag_list_matches("\"a\"", "\"a\""));
+        assert!(etag_list_matches("\"x\", \"a\"", "\"a\""));
+        assert!(etag_list_matches("W/\"a\"", "\"a\""));
+        assert!(etag_list_matches("\"a\"", "W/\"a\""));
+        assert!(!etag_list_matches("\"b\"", "\"a\""));
+        assert!(!etag_list_matches("\"x\", W/\"b\"", "W/\"a\""));
+    }
+
+    #[test]
+    fn if_range_etag() {
+        assert!(if_range_matches(&[], Some("\"a\""), None));
+        assert!(if_range_matches(
+            &headers("If-Range", "\"a\""),
+            Some("\"a\""),
+            None
+        ));
+        assert!(!if_range_matches(
+            &headers("If-Range", "\"b\""),
+            Some("\"a\""),
+            None
+        ));
+        assert!(!if_range_matches(
+            &headers("If-Range", "\"a\""),
+            None,
+            Some(LAST_MODIFIED)
+        ));
+
+        // Strong comparison, weak ETags never match
+        assert!(!if_range_matches(
+            &headers("If-Range", "\"a\""),
+            Some("W/\"a\""),
+            None
+        ));
+        assert!(!if_range_matches(
+            &headers("If-Range", "W/\"a\""),
+            Some("W/\"a\""),
+            None
+        ));
+    }
+
+    #[test]
+    fn if_range_date() {
+        assert!(if_range_matches(
+            &headers("If-Range", LAST_MODIFIED),
+            Some("\"a\""),
+            Some(LAST_MODIFIED)
+        ));
+        assert!(!if_range_matches(
+            &headers("If-Range", "Mon, 07 Nov 1994 08:49:37 GMT"),
+            None,
+            Some(LAST_MODIFIED)
+        ));
+        assert!(!if_range_matches(
+            &headers("If-Range", LAST_MODIFIED),
+            Some("\"a\""),
+            None
+        ));
+    }
+
+    #[test]
+    fn not_modified_validators() {
+        let get = http_types::Method::Get;
+
+        assert!(not_modified(
+            &get,
+            &headers("If-None-Match", "W/\"a\""),
+            Some("\"a\""),
+            None
+        ));
+        assert!(!not_modified(
+            &http_types::Method::Post,
+            &headers("If-None-Match", "\"a\""),
+            Some("\"a\""),
+            None
+        ));
+        assert!(not_modified(
+            &get,
+            &headers("If-Modified-Since", "Mon, 07 Nov 1994 08:49:37 GMT"),
+            None,
+            Some(LAST_MODIFIED)
+        ));
+        assert!(!not_modified(
+            &get,
+            &headers("If-Modified-Since", "Sat, 05 Nov 1994 08:49:37 GMT"),
+            None,
+            Some(LAST_MODIFIED)
+        ));
+
+        // If-None-Match wins, even over a matching date
+        let mut both = headers("If-None-Match", "\"b\"");
+        both.extend(headers("If-Modified-Since", LAST_MODIFIED));
+        assert!(!not_modified(
+            &get,
+            &both,
+            Some("\"a\""),
+            Some(LAST_MODIFIED)
+        ));
+    }
+}
@@ -1,27 +1,41 @@
 use std::os::unix::prelude::RawFd;
 
+use crate::http::ConnAddrs;
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Event {
     CtrlC,
+    Sighup,
     UnixStreamRead,
     UnixStreamWrite,
 
     // HTTP Server
-    NewStreamFd(RawFd),
+    NewStreamFd((RawFd, ConnAddrs)),
     ServerStreamRead,
     QueuedRequests,
-    PollPythonResponses,
+    PythonWake,
     ServerStreamWrite,
+    ResponseWriteCheck,
 
+    HeaderReadTimeout,
     RequestReadTimeout,
+    BodyRateCheck,
 
     CasketResponseWrite,
 
+    Http2Read,
+    Http2Write,
+    Http2IdleCheck,
+
     PythonCodeTimeout,
 }
 
 #[derive(Clone, Copy)]
 pub enum Timeout {
+    HeaderRead,
     RequestRead,
+    BodyRate,
     PythonCode,
+    ResponseWrite,
+    Http2Idle,
 }
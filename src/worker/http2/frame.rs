@@ -0,0 +1,292 @@
+// HTTP/2 framing (RFC 9113 section 4 and 6)
+
+pub const HEADER_SIZE: usize = 9;
+
+// Frame types
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+// Flags
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+// Settings
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Error codes
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const INTERNAL_ERROR: u32 = 0x2;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const STREAM_CLOSED: u32 = 0x5;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+pub const MAX_MAX_FRAME_SIZE: usize = (1 << 24) - 1;
+pub const DEFAULT_WINDOW_SIZE: i64 = 65535;
+pub const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+#[derive(Clone, Copy)]
+pub struct FrameHeader {
+    pub len: usize,
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+}
+
+impl FrameHeader {
+    pub fn parse(buf: &[u8]) -> Self {
+        Self {
+            len: (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize,
+            kind: buf[3],
+            flags: buf[4],
+            stream_id: read_u32(&buf[5..9]) & 0x7fff_ffff,
+        }
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+}
+
+pub fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+pub fn write_frame(buf: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let len = payload.len();
+
+    buf.extend([(len >> 16) as u8, (len >> 8) as u8, len as u8, kind, flags]);
+    buf.extend((stream_id & 0x7fff_ffff).to_be_bytes());
+    buf.extend(payload);
+}
+
+pub fn write_settings(buf: &mut Vec<u8>, settings: &[(u16, u32)]) {
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+
+    for (id, value) in settings.iter() {
+        payload.extend(id.to_be_bytes());
+        payload.extend(value.to_be_bytes());
+    }
+
+    write_frame(buf, SETTINGS, 0, 0, &payload);
+}
+
+pub fn write_window_update(buf: &mut Vec<u8>, stream_id: u32, increment: u32) {
+    write_frame(buf, WINDOW_UPDATE, 0, stream_id, &increment.to_be_bytes());
+}
+
+pub fn write_rst_stream(buf: &mut Vec<u8>, stream_id: u32, error_code: u32) {
+    write_frame(buf, RST_STREAM, 0, stream_id, &error_code.to_be_bytes());
+}
+
+pub fn write_goaway(buf: &mut Vec<u8>, last_stream_id: u32, error_code: u32) {
+    let mut payload = [0; 8];
+    payload[..4].copy_from_slice(&last_stream_id.to_be_bytes());
+    payload[4..].copy_from_slice(&error_code.to_be_bytes());
+
+    write_frame(buf, GOAWAY, 0, 0, &payload);
+}
+
+// A header block larger than max_frame_size is split over CONTINUATION frames
+pub fn write_headers(
+    buf: &mut Vec<u8>,
+    stream_id: u32,
+    block: &[u8],
+    end_stream: bool,
+    max_frame_size: usize,
+) {
+    let mut chunks = block.chunks(max_frame_size).peekable();
+    let mut kind = HEADERS;
+    let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+
+    // An empty block is still one HEADERS frame
+    if chunks.peek().is_none() {
+        write_frame(buf, kind, flags | FLAG_END_HEADERS, stream_id, &[]);
+        return;
+    }
+
+    while let Some(chunk) = chunks.next() {
+        if chunks.peek().is_none() {
+            flags |= FLAG_END_HEADERS;
+        }
+
+        write_frame(buf, kind, flags, stream_id, chunk);
+
+        kind = CONTINUATION;
+        flags = 0;
+    }
+}
+
+// Strip padding from a DATA or HEADERS payload
+pub fn unpad(header: &FrameHeader, payload: &[u8]) -> Option<(usize, usize)> {
+    if !header.has_flag(FLAG_PADDED) {
+        return Some((0, payload.len()));
+    }
+
+    let pad_len = *payload.first()? as usize;
+    if pad_len >= payload.len() {
+        return None;
+    }
+
+    Some((1, payload.len() - pad_len))
+}
+
+// HPACK literal header field without indexing - new name, no huffman (RFC 7541 6.2.2).
+// We never add to the client's dynamic table so its size setting doesn't matter.
+pub fn hpack_encode(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    hpack_string(block, name);
+    hpack_string(block, value);
+}
+
+fn hpack_string(block: &mut Vec<u8>, s: &[u8]) {
+    hpack_int(block, s.len(), 7, 0);
+    block.extend(s);
+}
+
+fn hpack_int(block: &mut Vec<u8>, mut value: usize, prefix_bits: u32, first: u8) {
+    let max_prefix = (1 << prefix_bits) - 1;
+
+    if value < max_prefix {
+        block.push(first | value as u8);
+        return;
+    }
+
+    block.push(first | max_prefix as u8);
+    value -= max_prefix;
+
+    while value >= 128 {
+        block.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+
+    block.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hpack_encode, unpad, write_frame, write_headers, FrameHeader, CONTINUATION, DATA,
+        FLAG_END_HEADERS, FLAG_END_STREAM, FLAG_PADDED, HEADERS, HEADER_SIZE,
+    };
+
+    fn header(flags: u8) -> FrameHeader {
+        FrameHeader {
+            len: 0,
+            kind: DATA,
+            flags,
+            stream_id: 1,
+        }
+    }
+
+    // Kind, flags and payload of each frame in buf
+    fn frames(mut buf: &[u8]) -> Vec<(u8, u8, Vec<u8>)> {
+        let mut frames = vec![];
+
+        while !buf.is_empty() {
+            let header = FrameHeader::parse(buf);
+            let payload = &buf[HEADER_SIZE..HEADER_SIZE + header.len];
+            frames.push((header.kind, header.flags, payload.to_vec()));
+            buf = &buf[HEADER_SIZE + header.len..];
+        }
+
+        frames
+    }
+
+    #[test]
+    fn parse_header() {
+        let header = FrameHeader::parse(&[0x01, 0x02, 0x03, HEADERS, 0x05, 0x80, 0, 0, 0x07]);
+        assert_eq!(header.len, 0x010203);
+        assert_eq!(header.kind, HEADERS);
+        assert!(header.has_flag(FLAG_END_STREAM));
+        assert!(header.has_flag(FLAG_END_HEADERS));
+        assert!(!header.has_flag(FLAG_PADDED));
+        // The reserved bit isn't part of the stream id
+        assert_eq!(header.stream_id, 7);
+    }
+
+    #[test]
+    fn write_parse_round_trip() {
+        let mut buf = vec![];
+        write_frame(&mut buf, DATA, FLAG_END_STREAM, 0xffff_ffff, b"hello");
+
+        let header = FrameHeader::parse(&buf);
+        assert_eq!(header.len, 5);
+        assert_eq!(header.stream_id, 0x7fff_ffff);
+        assert_eq!(&buf[HEADER_SIZE..], b"hello");
+    }
+
+    #[test]
+    fn unpadded() {
+        assert_eq!(unpad(&header(0), b"hello"), Some((0, 5)));
+        assert_eq!(unpad(&header(0), b""), Some((0, 0)));
+    }
+
+    #[test]
+    fn padded() {
+        assert_eq!(unpad(&header(FLAG_PADDED), b"\x02hi\0\0"), Some((1, 3)));
+        assert_eq!(unpad(&header(FLAG_PADDED), b"\x00hi"), Some((1, 3)));
+        assert_eq!(unpad(&header(FLAG_PADDED), b"\x00"), Some((1, 1)));
+        assert_eq!(unpad(&header(FLAG_PADDED), b"\x02hi"), Some((1, 1)));
+    }
+
+    #[test]
+    fn bad_padding() {
+        // No pad length at all
+        assert_eq!(unpad(&header(FLAG_PADDED), b""), None);
+        // Padding as long as or longer than the rest of the frame
+        assert_eq!(unpad(&header(FLAG_PADDED), b"\x03hi"), None);
+        assert_eq!(unpad(&header(FLAG_PADDED), b"\x05hi"), None);
+    }
+
+    #[test]
+    fn headers_split_into_continuation() {
+        let mut buf = vec![];
+        write_headers(&mut buf, 1, &[0; 10], true, 4);
+
+        assert_eq!(
+            frames(&buf),
+            vec![
+                (HEADERS, FLAG_END_STREAM, vec![0; 4]),
+                (CONTINUATION, 0, vec![0; 4]),
+                (CONTINUATION, FLAG_END_HEADERS, vec![0; 2]),
+            ]
+        );
+
+        let mut buf = vec![];
+        write_headers(&mut buf, 1, &[], false, 4);
+        assert_eq!(frames(&buf), vec![(HEADERS, FLAG_END_HEADERS, vec![])]);
+    }
+
+    #[test]
+    fn hpack_literal() {
+        let mut block = vec![];
+        hpack_encode(&mut block, b"x-a", b"b");
+        assert_eq!(block, b"\x00\x03x-a\x01b");
+
+        // Lengths from 127 up need continuation bytes
+        let value = vec![b'v'; 200];
+        let mut block = vec![];
+        hpack_encode(&mut block, b"x", &value);
+        assert_eq!(&block[..5], b"\x00\x01x\x7f\x49");
+        assert_eq!(&block[5..], &value[..]);
+    }
+}
@@ -0,0 +1,1629 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::time;
+
+use mio::Token;
+
+use crate::config::Config;
+use crate::errorpages::ErrorPages;
+use crate::http::{Context, HttpError, HttpRequest, HttpResponse, RequestBody, Version};
+use crate::server::NEW_STREAM_COUNT_INC;
+
+use super::log_content_length_mismatch;
+use super::serverreader::{append_body, parse_content_length, parse_context, url};
+use super::stream::Stream;
+
+mod frame;
+use frame::FrameHeader;
+
+// Every HTTP/2 connection starts with this, with or without tls
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const READ_SIZE: usize = 16384;
+const FILE_READ_SIZE: usize = 16384;
+// Stop pulling response bodies once this much is waiting to be written
+const WRITE_BUFFER_HIGH: usize = 1 << 16;
+const MAX_HEADER_BLOCK_SIZE: usize = 1 << 16;
+// As counted by RFC 9113 6.5.2
+const MAX_HEADER_LIST_SIZE: usize = 1 << 16;
+
+// Headers which only mean something to a HTTP/1 connection
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub enum Output {
+    // A complete request to hand to the python threads
+    Request((Token, Box<HttpRequest>)),
+    // The client reset a stream still waiting on python - drop the response
+    Cancel(Token),
+    // A stream was reset part way through its response
+    Reset(Token),
+    // The last frame of a response has been queued
+    Done((Token, Box<HttpResponse>)),
+}
+
+#[derive(Clone, Copy)]
+struct Options {
+    spool_size: usize,
+    strict: bool,
+    max_streams: usize,
+}
+
+pub struct Connection {
+    opts: Options,
+    error_pages: Arc<ErrorPages>,
+    decoder: hpack::Decoder<'static>,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+
+    // Each stream gets a token of its own, from the range the server gave this connection
+    next_tk: usize,
+    tk_limit: usize,
+
+    streams: BTreeMap<u32, H2Stream>,
+    tokens: HashMap<Token, u32>,
+    last_stream_id: u32,
+    // A HEADERS frame without END_HEADERS, waiting on CONTINUATION
+    header_block: Option<HeaderBlock>,
+
+    send_window: i64,
+    // What the client may still send before we hand back credit
+    recv_window: i64,
+    peer_initial_window: i64,
+    peer_max_frame_size: usize,
+
+    last_activity: time::SystemTime,
+    // Since when the client has left part of a response unread
+    write_stalled: Option<time::SystemTime>,
+    goaway: bool,
+    error: Option<&'static str>,
+}
+
+struct HeaderBlock {
+    stream_id: u32,
+    block: Vec<u8>,
+    end_stream: bool,
+}
+
+struct H2Stream {
+    tk: Token,
+    send_window: i64,
+    recv_window: i64,
+    state: StreamState,
+}
+
+enum StreamState {
+    Receiving(Box<PartialRequest>),
+    // Waiting on python
+    Pending,
+    Sending(Box<Sending>),
+}
+
+struct PartialRequest {
+    http_req: HttpRequest,
+    expected_length: Option<usize>,
+}
+
+struct Sending {
+    http_resp: Box<HttpResponse>,
+    chunk: Vec<u8>,
+    chunk_sent: usize,
+    bytes_sent: usize,
+}
+
+impl Connection {
+    // buf holds everything read so far, starting with the preface
+    pub fn new(tk: Token, cfg: &Config, mut buf: Vec<u8>, error_pages: Arc<ErrorPages>) -> Self {
+        buf.drain(..PREFACE.len());
+
+        let opts = Options {
+            spool_size: cfg.request_body_spool_size,
+            strict: cfg.strict_http,
+            max_streams: cfg.max_requests,
+        };
+
+        let mut write_buf = vec![];
+        frame::write_settings(
+            &mut write_buf,
+            &[
+                (
+                    frame::SETTINGS_MAX_CONCURRENT_STREAMS,
+                    opts.max_streams as u32,
+                ),
+                (frame::SETTINGS_ENABLE_PUSH, 0),
+                (
+                    frame::SETTINGS_MAX_HEADER_LIST_SIZE,
+                    MAX_HEADER_LIST_SIZE as u32,
+                ),
+            ],
+        );
+
+        Self {
+            opts,
+            error_pages,
+            decoder: hpack::Decoder::new(),
+            read_buf: buf,
+            write_buf,
+            next_tk: tk.0 + 1,
+            tk_limit: (tk.0 / NEW_STREAM_COUNT_INC + 1) * NEW_STREAM_COUNT_INC,
+            streams: BTreeMap::new(),
+            tokens: HashMap::new(),
+            last_stream_id: 0,
+            header_block: None,
+            send_window: frame::DEFAULT_WINDOW_SIZE,
+            recv_window: frame::DEFAULT_WINDOW_SIZE,
+            peer_initial_window: frame::DEFAULT_WINDOW_SIZE,
+            peer_max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+            last_activity: time::SystemTime::now(),
+            write_stalled: None,
+            goaway: false,
+            error: None,
+        }
+    }
+
+    // Frames already read with the preface
+    pub fn process_buffered(&mut self, out: &mut Vec<Output>) -> Result<(), HttpError> {
+        self.process_frames(out)
+    }
+
+    // Ok(true) when the client closed the connection
+    pub fn read_tcp_stream(
+        &mut self,
+        tcp_stream: &mut Stream,
+        out: &mut Vec<Output>,
+    ) -> Result<bool, HttpError> {
+        let mut buf = [0; READ_SIZE];
+
+        loop {
+            let bytes_read = match tcp_stream.read(&mut buf) {
+                Ok(0) => return Ok(true),
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(HttpError::Io(("failed to read http2 connection", e))),
+            };
+
+            self.last_activity = time::SystemTime::now();
+            self.read_buf.extend(&buf[..bytes_read]);
+            self.process_frames(out)?;
+
+            if self.error.is_some() {
+                return Ok(false);
+            }
+        }
+    }
+
+    pub fn write_tcp_stream(
+        &mut self,
+        tcp_stream: &mut Stream,
+        out: &mut Vec<Output>,
+    ) -> Result<(), HttpError> {
+        self.fill_data_frames(out);
+
+        let mut progress = false;
+
+        while !self.write_buf.is_empty() {
+            match tcp_stream.write(&self.write_buf) {
+                Ok(0) => break,
+                Ok(bytes_written) => {
+                    self.write_buf.drain(..bytes_written);
+                    progress = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(HttpError::Io(("failed to write http2 connection", e))),
+            }
+        }
+
+        // The clock restarts whenever the client takes some of a response.
+        // A stream the client's window holds back counts as unread.
+        let unsent = !self.write_buf.is_empty()
+            || self.streams.values().any(|stream| match stream.state {
+                StreamState::Sending(ref sending) => sending.chunk_sent < sending.chunk.len(),
+                _ => false,
+            });
+
+        self.write_stalled = match self.write_stalled {
+            _ if !unsent => None,
+            Some(write_stalled) if !progress => Some(write_stalled),
+            _ => Some(time::SystemTime::now()),
+        };
+
+        Ok(())
+    }
+
+    pub fn write_stalled_since(&self) -> Option<time::SystemTime> {
+        self.write_stalled
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.write_buf.is_empty()
+            || self.streams.values().any(|stream| match stream.state {
+                StreamState::Sending(ref sending) => {
+                    sending.chunk_sent < sending.chunk.len()
+                        && stream.send_window > 0
+                        && self.send_window > 0
+                }
+                _ => false,
+            })
+    }
+
+    // A stream has sent all it has, the next of the body comes with a python wake
+    pub fn waiting_body(&self) -> bool {
+        self.streams.values().any(|stream| match stream.state {
+            StreamState::Sending(ref sending) => sending.chunk_sent == sending.chunk.len(),
+            _ => false,
+        })
+    }
+
+    // Python has responded on this stream
+    pub fn start_response(&mut self, tk: Token, mut http_resp: Box<HttpResponse>) {
+        let stream_id = match self.tokens.get(&tk) {
+            Some(stream_id) => *stream_id,
+            None => return,
+        };
+
+        let mut block = vec![];
+        frame::hpack_encode(
+            &mut block,
+            b":status",
+            http_resp.code.to_string().as_bytes(),
+        );
+
+        for (name, value) in http_resp.resp_headers.iter() {
+            let name = name.to_ascii_lowercase();
+            if CONNECTION_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+
+            frame::hpack_encode(&mut block, name.as_bytes(), value.as_bytes());
+        }
+
+        frame::hpack_encode(
+            &mut block,
+            b"x-traceid",
+            http_resp.context.trace_id.as_bytes(),
+        );
+        frame::hpack_encode(&mut block, b"server", b"Casket");
+
+        frame::write_headers(
+            &mut self.write_buf,
+            stream_id,
+            &block,
+            false,
+            self.peer_max_frame_size,
+        );
+
+        http_resp.keep_alive = true;
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.state = StreamState::Sending(Box::new(Sending {
+                http_resp,
+                chunk: vec![],
+                chunk_sent: 0,
+                bytes_sent: 0,
+            }));
+        }
+    }
+
+    // A response casket makes itself (e.g 504), no body
+    pub fn error_response(
+        &mut self,
+        tk: Token,
+        status: (u16, &str),
+        ctx: &Context,
+        error: Option<&str>,
+    ) {
+        let stream_id = match self.tokens.remove(&tk) {
+            Some(stream_id) => stream_id,
+            None => return,
+        };
+
+        let (code, reason) = status;
+        let stream_window = self
+            .streams
+            .get(&stream_id)
+            .map(|stream| stream.send_window)
+            .unwrap_or(0);
+
+        // Only a body we can send in one go, error responses don't wait on flow control
+        let page = self
+            .error_pages
+            .render(code, reason, &ctx.trace_id, error)
+            .filter(|page| {
+                let len = page.body.len();
+                len <= self.peer_max_frame_size
+                    && (len as i64) <= stream_window
+                    && (len as i64) <= self.send_window
+            });
+
+        let content_length = page.as_ref().map(|page| page.body.len()).unwrap_or(0);
+
+        let mut block = vec![];
+        frame::hpack_encode(&mut block, b":status", code.to_string().as_bytes());
+        if let Some(ref page) = page {
+            frame::hpack_encode(&mut block, b"content-type", page.content_type.as_bytes());
+        }
+        frame::hpack_encode(
+            &mut block,
+            b"content-length",
+            content_length.to_string().as_bytes(),
+        );
+        frame::hpack_encode(&mut block, b"x-traceid", ctx.trace_id.as_bytes());
+        frame::hpack_encode(&mut block, b"server", b"Casket");
+
+        frame::write_headers(
+            &mut self.write_buf,
+            stream_id,
+            &block,
+            page.is_none(),
+            self.peer_max_frame_size,
+        );
+
+        if let Some(page) = page {
+            frame::write_frame(
+                &mut self.write_buf,
+                frame::DATA,
+                frame::FLAG_END_STREAM,
+                stream_id,
+                &page.body,
+            );
+            self.send_window -= page.body.len() as i64;
+        }
+
+        self.streams.remove(&stream_id);
+    }
+
+    // We couldn't take the request (e.g too busy), the client may retry it
+    pub fn refuse_stream(&mut self, tk: Token) {
+        if let Some(stream_id) = self.tokens.remove(&tk) {
+            self.streams.remove(&stream_id);
+            frame::write_rst_stream(&mut self.write_buf, stream_id, frame::REFUSED_STREAM);
+        }
+    }
+
+    pub fn is_pending(&self, tk: Token) -> bool {
+        match self.tokens.get(&tk).and_then(|id| self.streams.get(id)) {
+            Some(stream) => matches!(stream.state, StreamState::Pending),
+            None => false,
+        }
+    }
+
+    // Streams still waiting on python
+    pub fn pending_tokens(&self) -> Vec<Token> {
+        self.streams
+            .values()
+            .filter(|stream| matches!(stream.state, StreamState::Pending))
+            .map(|stream| stream.tk)
+            .collect()
+    }
+
+    // Stop taking new streams, we close once the open streams are done
+    pub fn go_away(&mut self) {
+        if !self.goaway {
+            self.goaway = true;
+            frame::write_goaway(&mut self.write_buf, self.last_stream_id, frame::NO_ERROR);
+        }
+    }
+
+    pub fn idle_since(&self) -> Option<time::SystemTime> {
+        if self.streams.is_empty() && self.header_block.is_none() {
+            Some(self.last_activity)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        if !self.write_buf.is_empty() {
+            return false;
+        }
+
+        self.error.is_some() || (self.goaway && self.streams.is_empty())
+    }
+
+    pub fn error(&self) -> Option<&'static str> {
+        self.error
+    }
+
+    fn connection_error(&mut self, error_code: u32, reason: &'static str) {
+        if self.error.is_none() {
+            self.error = Some(reason);
+            self.goaway = true;
+            frame::write_goaway(&mut self.write_buf, self.last_stream_id, error_code);
+        }
+    }
+
+    fn stream_error(&mut self, stream_id: u32, error_code: u32, out: &mut Vec<Output>) {
+        self.remove_stream(stream_id, out);
+        frame::write_rst_stream(&mut self.write_buf, stream_id, error_code);
+    }
+
+    fn remove_stream(&mut self, stream_id: u32, out: &mut Vec<Output>) {
+        let stream = match self.streams.remove(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        self.tokens.remove(&stream.tk);
+
+        match stream.state {
+            // Not handed to the worker yet
+            StreamState::Receiving(_) => {}
+            StreamState::Pending => out.push(Output::Cancel(stream.tk)),
+            StreamState::Sending(_) => out.push(Output::Reset(stream.tk)),
+        }
+    }
+
+    fn process_frames(&mut self, out: &mut Vec<Output>) -> Result<(), HttpError> {
+        let mut offset = 0;
+
+        while self.error.is_none() && self.read_buf.len() - offset >= frame::HEADER_SIZE {
+            let header = FrameHeader::parse(&self.read_buf[offset..]);
+
+            if header.len > frame::DEFAULT_MAX_FRAME_SIZE {
+                self.connection_error(frame::FRAME_SIZE_ERROR, "http2 frame too large");
+                break;
+            }
+
+            let frame_end = offset + frame::HEADER_SIZE + header.len;
+            if self.read_buf.len() < frame_end {
+                break;
+            }
+
+            let payload = self.read_buf[offset + frame::HEADER_SIZE..frame_end].to_vec();
+            offset = frame_end;
+
+            self.process_frame(header, &payload, out)?;
+        }
+
+        self.read_buf.drain(..offset);
+
+        Ok(())
+    }
+
+    fn process_frame(
+        &mut self,
+        header: FrameHeader,
+        payload: &[u8],
+        out: &mut Vec<Output>,
+    ) -> Result<(), HttpError> {
+        if self.header_block.is_some() && header.kind != frame::CONTINUATION {
+            self.connection_error(frame::PROTOCOL_ERROR, "http2 expected CONTINUATION frame");
+            return Ok(());
+        }
+
+        match header.kind {
+            frame::DATA => self.on_data(header, payload, out),
+            frame::HEADERS => {
+                self.on_headers(header, payload, out);
+                Ok(())
+            }
+            frame::CONTINUATION => {
+                self.on_continuation(header, payload, out);
+                Ok(())
+            }
+            frame::RST_STREAM => {
+                if header.stream_id == 0 || payload.len() != 4 {
+                    self.connection_error(frame::PROTOCOL_ERROR, "http2 bad RST_STREAM frame");
+                } else {
+                    self.remove_stream(header.stream_id, out);
+                }
+                Ok(())
+            }
+            frame::SETTINGS => {
+                self.on_settings(header, payload);
+                Ok(())
+            }
+            frame::PING => {
+                if header.stream_id != 0 || payload.len() != 8 {
+                    self.connection_error(frame::PROTOCOL_ERROR, "http2 bad PING frame");
+                } else if !header.has_flag(frame::FLAG_ACK) {
+                    frame::write_frame(
+                        &mut self.write_buf,
+                        frame::PING,
+                        frame::FLAG_ACK,
+                        0,
+                        payload,
+                    );
+                }
+                Ok(())
+            }
+            frame::GOAWAY => {
+                // The client won't open new streams, finish what we have
+                self.go_away();
+                Ok(())
+            }
+            frame::WINDOW_UPDATE => {
+                self.on_window_update(header, payload, out);
+                Ok(())
+            }
+            frame::PUSH_PROMISE => {
+                self.connection_error(frame::PROTOCOL_ERROR, "http2 client sent PUSH_PROMISE");
+                Ok(())
+            }
+            // PRIORITY and unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_headers(&mut self, header: FrameHeader, payload: &[u8], out: &mut Vec<Output>) {
+        let stream_id = header.stream_id;
+
+        if stream_id == 0 {
+            self.connection_error(frame::PROTOCOL_ERROR, "http2 HEADERS on stream 0");
+            return;
+        }
+
+        let (mut start, end) = match frame::unpad(&header, payload) {
+            Some(bounds) => bounds,
+            None => {
+                self.connection_error(frame::PROTOCOL_ERROR, "http2 bad padding");
+                return;
+            }
+        };
+
+        if header.has_flag(frame::FLAG_PRIORITY) {
+            start += 5;
+            if start > end {
+                self.connection_error(frame::FRAME_SIZE_ERROR, "http2 HEADERS frame too short");
+                return;
+            }
+        }
+
+        let header_block = HeaderBlock {
+            stream_id,
+            block: payload[start..end].to_vec(),
+            end_stream: header.has_flag(frame::FLAG_END_STREAM),
+        };
+
+        if header.has_flag(frame::FLAG_END_HEADERS) {
+            self.on_header_block(header_block, out);
+        } else {
+            self.header_block = Some(header_block);
+        }
+    }
+
+    fn on_continuation(&mut self, header: FrameHeader, payload: &[u8], out: &mut Vec<Output>) {
+        let mut header_block = match self.header_block.take() {
+            Some(header_block) if header_block.stream_id == header.stream_id => header_block,
+            _ => {
+                self.connection_error(frame::PROTOCOL_ERROR, "http2 unexpected CONTINUATION");
+                return;
+            }
+        };
+
+        header_block.block.extend(payload);
+        if header_block.block.len() > MAX_HEADER_BLOCK_SIZE {
+            self.connection_error(frame::ENHANCE_YOUR_CALM, "http2 header block too large");
+            return;
+        }
+
+        if header.has_flag(frame::FLAG_END_HEADERS) {
+            self.on_header_block(header_block, out);
+        } else {
+            self.header_block = Some(header_block);
+        }
+    }
+
+    fn on_header_block(&mut self, header_block: HeaderBlock, out: &mut Vec<Output>) {
+        let stream_id = header_block.stream_id;
+
+        // Always decode, the hpack table must stay in step with the client.
+        // Fields are only copied out up to MAX_HEADER_LIST_SIZE, small blocks
+        // can refer to large table entries many times over.
+        let mut fields = vec![];
+        let mut list_size = 0;
+
+        let decoded = self
+            .decoder
+            .decode_with_cb(&header_block.block, |name, value| {
+                list_size += name.len() + value.len() + 32;
+                if list_size <= MAX_HEADER_LIST_SIZE {
+                    fields.push((name.into_owned(), value.into_owned()));
+                }
+            });
+
+        if decoded.is_err() {
+            self.connection_error(frame::COMPRESSION_ERROR, "http2 couldn't decode headers");
+            return;
+        }
+
+        if list_size > MAX_HEADER_LIST_SIZE {
+            self.stream_error(stream_id, frame::ENHANCE_YOUR_CALM, out);
+            self.last_stream_id = self.last_stream_id.max(stream_id);
+            return;
+        }
+
+        // Trailers - their content is dropped
+        if let Some(stream) = self.streams.get(&stream_id) {
+            match stream.state {
+                StreamState::Receiving(_) if header_block.end_stream => {
+                    self.end_request(stream_id, out)
+                }
+                _ => self.stream_error(stream_id, frame::PROTOCOL_ERROR, out),
+            }
+            return;
+        }
+
+        // Client streams are odd
+        if stream_id & 1 == 0 || stream_id <= self.last_stream_id {
+            self.connection_error(frame::PROTOCOL_ERROR, "http2 bad stream id");
+            return;
+        }
+        self.last_stream_id = stream_id;
+
+        if self.goaway {
+            // Never processed, the client may retry it on a new connection
+            frame::write_rst_stream(&mut self.write_buf, stream_id, frame::REFUSED_STREAM);
+            return;
+        }
+
+        if self.streams.len() >= self.opts.max_streams || self.next_tk >= self.tk_limit {
+            frame::write_rst_stream(&mut self.write_buf, stream_id, frame::REFUSED_STREAM);
+            return;
+        }
+
+        let partial_req = match build_request(fields, self.opts.strict) {
+            Ok(partial_req) => partial_req,
+            Err(_) => {
+                frame::write_rst_stream(&mut self.write_buf, stream_id, frame::PROTOCOL_ERROR);
+                return;
+            }
+        };
+
+        let tk = Token(self.next_tk);
+        self.next_tk += 1;
+
+        self.tokens.insert(tk, stream_id);
+        self.streams.insert(
+            stream_id,
+            H2Stream {
+                tk,
+                send_window: self.peer_initial_window,
+                recv_window: frame::DEFAULT_WINDOW_SIZE,
+                state: StreamState::Receiving(Box::new(partial_req)),
+            },
+        );
+
+        if header_block.end_stream {
+            self.end_request(stream_id, out);
+        }
+    }
+
+    fn on_data(
+        &mut self,
+        header: FrameHeader,
+        payload: &[u8],
+        out: &mut Vec<Output>,
+    ) -> Result<(), HttpError> {
+        let stream_id = header.stream_id;
+
+        if stream_id == 0 {
+            self.connection_error(frame::PROTOCOL_ERROR, "http2 DATA on stream 0");
+            return Ok(());
+        }
+
+        // The whole frame counts against flow control, padding included
+        let len = payload.len() as i64;
+        if len > self.recv_window {
+            self.connection_error(
+                frame::FLOW_CONTROL_ERROR,
+                "http2 connection window exceeded",
+            );
+            return Ok(());
+        }
+        self.recv_window -= len;
+
+        let (start, end) = match frame::unpad(&header, payload) {
+            Some(bounds) => bounds,
+            None => {
+                self.connection_error(frame::PROTOCOL_ERROR, "http2 bad padding");
+                return Ok(());
+            }
+        };
+
+        let spool_size = self.opts.spool_size;
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => {
+                if stream_id > self.last_stream_id {
+                    self.connection_error(frame::PROTOCOL_ERROR, "http2 DATA on idle stream");
+                } else {
+                    frame::write_rst_stream(&mut self.write_buf, stream_id, frame::STREAM_CLOSED);
+                }
+                return Ok(());
+            }
+        };
+
+        if len > stream.recv_window {
+            self.stream_error(stream_id, frame::FLOW_CONTROL_ERROR, out);
+            return Ok(());
+        }
+        stream.recv_window -= len;
+
+        let partial_req = match stream.state {
+            StreamState::Receiving(ref mut partial_req) => partial_req,
+            _ => {
+                self.stream_error(stream_id, frame::STREAM_CLOSED, out);
+                return Ok(());
+            }
+        };
+
+        partial_req.append_body(&payload[start..end], spool_size)?;
+
+        if let Some(expected_length) = partial_req.expected_length {
+            if partial_req.http_req.content_length > expected_length {
+                self.stream_error(stream_id, frame::PROTOCOL_ERROR, out);
+                return Ok(());
+            }
+        }
+
+        // The body is spooled to disk once it is large, so the data can be credited back as
+        // soon as it is stored
+        if len > 0 {
+            frame::write_window_update(&mut self.write_buf, 0, len as u32);
+            self.recv_window += len;
+        }
+
+        if header.has_flag(frame::FLAG_END_STREAM) {
+            self.end_request(stream_id, out);
+        } else if len > 0 {
+            frame::write_window_update(&mut self.write_buf, stream_id, len as u32);
+            stream.recv_window += len;
+        }
+
+        Ok(())
+    }
+
+    fn end_request(&mut self, stream_id: u32, out: &mut Vec<Output>) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let partial_req = match mem::replace(&mut stream.state, StreamState::Pending) {
+            StreamState::Receiving(partial_req) => partial_req,
+            state => {
+                stream.state = state;
+                return;
+            }
+        };
+
+        let tk = stream.tk;
+
+        let http_req = match partial_req.into_request() {
+            Ok(http_req) => http_req,
+            Err(_) => {
+                self.streams.remove(&stream_id);
+                self.tokens.remove(&tk);
+                frame::write_rst_stream(&mut self.write_buf, stream_id, frame::PROTOCOL_ERROR);
+                return;
+            }
+        };
+
+        out.push(Output::Request((tk, Box::new(http_req))));
+    }
+
+    fn on_settings(&mut self, header: FrameHeader, payload: &[u8]) {
+        if header.stream_id != 0 {
+            self.connection_error(frame::PROTOCOL_ERROR, "http2 SETTINGS on a stream");
+            return;
+        }
+
+        if header.has_flag(frame::FLAG_ACK) {
+            return;
+        }
+
+        let settings = payload.chunks_exact(6);
+        if !settings.remainder().is_empty() {
+            self.connection_error(frame::FRAME_SIZE_ERROR, "http2 bad SETTINGS frame");
+            return;
+        }
+
+        for setting in settings {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = frame::read_u32(&setting[2..]);
+
+            match id {
+                frame::SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > frame::MAX_WINDOW_SIZE {
+                        self.connection_error(
+                            frame::FLOW_CONTROL_ERROR,
+                            "http2 initial window size too large",
+                        );
+                        return;
+                    }
+
+                    // Applies to the streams we already have
+                    let delta = value as i64 - self.peer_initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                    }
+                    self.peer_initial_window = value as i64;
+                }
+                frame::SETTINGS_MAX_FRAME_SIZE => {
+                    let value = value as usize;
+                    if !(frame::DEFAULT_MAX_FRAME_SIZE..=frame::MAX_MAX_FRAME_SIZE).contains(&value)
+                    {
+                        self.connection_error(frame::PROTOCOL_ERROR, "http2 bad max frame size");
+                        return;
+                    }
+
+                    self.peer_max_frame_size = value;
+                }
+                frame::SETTINGS_ENABLE_PUSH if value > 1 => {
+                    self.connection_error(frame::PROTOCOL_ERROR, "http2 bad enable push");
+                    return;
+                }
+                // We never index into the client's table or push
+                _ => {}
+            }
+        }
+
+        frame::write_frame(
+            &mut self.write_buf,
+            frame::SETTINGS,
+            frame::FLAG_ACK,
+            0,
+            &[],
+        );
+    }
+
+    fn on_window_update(&mut self, header: FrameHeader, payload: &[u8], out: &mut Vec<Output>) {
+        if payload.len() != 4 {
+            self.connection_error(frame::FRAME_SIZE_ERROR, "http2 bad WINDOW_UPDATE frame");
+            return;
+        }
+
+        let increment = (frame::read_u32(payload) & 0x7fff_ffff) as i64;
+
+        if header.stream_id == 0 {
+            if increment == 0 {
+                self.connection_error(frame::PROTOCOL_ERROR, "http2 zero window increment");
+            } else if self.send_window + increment > frame::MAX_WINDOW_SIZE {
+                self.connection_error(frame::FLOW_CONTROL_ERROR, "http2 window too large");
+            } else {
+                self.send_window += increment;
+            }
+
+            return;
+        }
+
+        let window = match self.streams.get_mut(&header.stream_id) {
+            Some(stream) => &mut stream.send_window,
+            // Closed stream
+            None => return,
+        };
+
+        if increment == 0 {
+            self.stream_error(header.stream_id, frame::PROTOCOL_ERROR, out);
+        } else if *window + increment > frame::MAX_WINDOW_SIZE {
+            self.stream_error(header.stream_id, frame::FLOW_CONTROL_ERROR, out);
+        } else {
+            *window += increment;
+        }
+    }
+
+    // Response bodies are only pulled from the python channel while the
+    // client has window for them, a slow client holds the rest in python
+    fn fill_data_frames(&mut self, out: &mut Vec<Output>) {
+        let mut done = vec![];
+
+        for (stream_id, stream) in self.streams.iter_mut() {
+            let sending = match stream.state {
+                StreamState::Sending(ref mut sending) => sending,
+                _ => continue,
+            };
+
+            loop {
+                if self.write_buf.len() >= WRITE_BUFFER_HIGH {
+                    break;
+                }
+
+                if sending.chunk_sent == sending.chunk.len() {
+                    // wsgi.file_wrapper - no sendfile, the bytes go into DATA frames
+                    if let Some(file) = sending.http_resp.resp_file.as_mut() {
+                        match file.read_chunk(FILE_READ_SIZE) {
+                            Ok(chunk) if !chunk.is_empty() => {
+                                sending.chunk = chunk;
+                                sending.chunk_sent = 0;
+                            }
+                            // A short body is caught by the Content-Length check
+                            _ => sending.http_resp.resp_file = None,
+                        }
+                        continue;
+                    }
+
+                    let body = match sending.http_resp.resp_body.as_ref() {
+                        Some(body) => body,
+                        None => {
+                            // Sender has dropped - no more data
+                            match sending.http_resp.resp_content_length {
+                                // The app failed part way, nothing partial goes in the cache
+                                _ if sending.http_resp.is_aborted() => {
+                                    sending.http_resp.capture = None;
+                                    frame::write_rst_stream(
+                                        &mut self.write_buf,
+                                        *stream_id,
+                                        frame::INTERNAL_ERROR,
+                                    );
+                                }
+                                // The body is short, tell the client it's incomplete
+                                Some(cl)
+                                    if sending.bytes_sent < cl && !sending.http_resp.no_body =>
+                                {
+                                    log_content_length_mismatch(
+                                        &sending.http_resp,
+                                        sending.bytes_sent,
+                                    );
+                                    frame::write_rst_stream(
+                                        &mut self.write_buf,
+                                        *stream_id,
+                                        frame::INTERNAL_ERROR,
+                                    );
+                                }
+                                _ => frame::write_frame(
+                                    &mut self.write_buf,
+                                    frame::DATA,
+                                    frame::FLAG_END_STREAM,
+                                    *stream_id,
+                                    &[],
+                                ),
+                            }
+                            done.push(*stream_id);
+                            break;
+                        }
+                    };
+
+                    match body.try_recv() {
+                        // HEAD, 204 and 304 - headers only
+                        Ok(_) if sending.http_resp.no_body => continue,
+                        Ok(mut chunk) => {
+                            // Stop reading once the app goes over its Content-Length
+                            if let Some(cl) = sending.http_resp.resp_content_length {
+                                let remaining = cl - sending.bytes_sent;
+
+                                if chunk.len() > remaining {
+                                    log_content_length_mismatch(
+                                        &sending.http_resp,
+                                        sending.bytes_sent + chunk.len(),
+                                    );
+                                    chunk.truncate(remaining);
+                                    sending.http_resp.resp_body = None;
+                                }
+                            }
+
+                            if let Some(capture) = sending.http_resp.capture.as_mut() {
+                                if !capture.push(&chunk) {
+                                    sending.http_resp.capture = None;
+                                }
+                            }
+
+                            sending.chunk = chunk;
+                            sending.chunk_sent = 0;
+                            continue;
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            sending.http_resp.resp_body = None;
+                            continue;
+                        }
+                    }
+                }
+
+                let remaining = (sending.chunk.len() - sending.chunk_sent) as i64;
+                let size = remaining
+                    .min(stream.send_window)
+                    .min(self.send_window)
+                    .min(self.peer_max_frame_size as i64);
+
+                if size <= 0 {
+                    break;
+                }
+
+                let size = size as usize;
+                let start = sending.chunk_sent;
+                frame::write_frame(
+                    &mut self.write_buf,
+                    frame::DATA,
+                    0,
+                    *stream_id,
+                    &sending.chunk[start..start + size],
+                );
+
+                sending.chunk_sent += size;
+                sending.bytes_sent += size;
+                stream.send_window -= size as i64;
+                self.send_window -= size as i64;
+            }
+        }
+
+        for stream_id in done {
+            let stream = match self.streams.remove(&stream_id) {
+                Some(stream) => stream,
+                None => continue,
+            };
+
+            self.tokens.remove(&stream.tk);
+
+            if let StreamState::Sending(sending) = stream.state {
+                let mut http_resp = sending.http_resp;
+                http_resp.resp_content_length = Some(sending.bytes_sent);
+                out.push(Output::Done((stream.tk, http_resp)));
+            }
+        }
+    }
+}
+
+impl PartialRequest {
+    fn append_body(&mut self, data: &[u8], spool_size: usize) -> Result<(), HttpError> {
+        let http_req = &mut self.http_req;
+        http_req.content_length += data.len();
+
+        let body = http_req.body.get_or_insert(RequestBody::Memory(vec![]));
+        append_body(body, data, spool_size, false)
+    }
+
+    fn into_request(mut self) -> Result<HttpRequest, HttpError> {
+        if let Some(expected_length) = self.expected_length {
+            if expected_length != self.http_req.content_length {
+                return Err(HttpError::BadValue(
+                    "body length doesn't match Content-Length",
+                ));
+            }
+        }
+
+        // Rewind so the body can be read back from the start
+        if let Some(RequestBody::Spooled(ref mut file)) = self.http_req.body {
+            file.seek(SeekFrom::Start(0))
+                .map_err(|e| HttpError::Io(("failed to rewind request body spool file", e)))?;
+        }
+
+        Ok(self.http_req)
+    }
+}
+
+fn build_request(
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+    strict: bool,
+) -> Result<PartialRequest, HttpError> {
+    let mut method: Option<String> = None;
+    let mut scheme: Option<String> = None;
+    let mut authority: Option<String> = None;
+    let mut path: Option<String> = None;
+    let mut host: Option<String> = None;
+    let mut content_type: Option<String> = None;
+    let mut expected_length: Option<usize> = None;
+    let mut context: Option<Context> = None;
+    let mut cookies = vec![];
+    let mut headers = vec![];
+
+    for (name, value) in fields {
+        let name =
+            String::from_utf8(name).map_err(|_| HttpError::BadValue("header name not utf8"))?;
+        let value =
+            String::from_utf8(value).map_err(|_| HttpError::BadValue("header value not utf8"))?;
+
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // Pseudo headers come first
+            if !headers.is_empty() || !cookies.is_empty() {
+                return Err(HttpError::BadValue("pseudo header after regular header"));
+            }
+
+            let field = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                "path" => &mut path,
+                _ => return Err(HttpError::BadValue("unknown pseudo header")),
+            };
+
+            if field.is_some() {
+                return Err(HttpError::BadValue("duplicate pseudo header"));
+            }
+
+            *field = Some(value);
+            continue;
+        }
+
+        if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err(HttpError::BadValue("uppercase http2 header name"));
+        }
+
+        if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return Err(HttpError::BadValue("connection header in http2 request"));
+        }
+
+        // Same reason as HTTP/1 - WSGI can't tell '-' and '_' apart
+        if name.contains('_') {
+            if strict {
+                return Err(HttpError::BadValue("header name contains underscore"));
+            }
+
+            continue;
+        }
+
+        match name.as_str() {
+            "content-type" => content_type = Some(value.clone()),
+            "content-length" => {
+                let cl = parse_content_length(&value, strict)?;
+                if matches!(expected_length.replace(cl), Some(prev) if prev != cl) {
+                    return Err(HttpError::BadValue("conflicting Content-Length headers"));
+                }
+            }
+            "host" => host = host.or_else(|| Some(value.clone())),
+            "traceparent" => context = parse_context(&value).ok(),
+            // Clients may split cookies into several fields (RFC 9113 8.2.3)
+            "cookie" => {
+                cookies.push(value);
+                continue;
+            }
+            _ => {}
+        }
+
+        headers.push((name, value));
+    }
+
+    if !cookies.is_empty() {
+        headers.push(("cookie".to_string(), cookies.join("; ")));
+    }
+
+    let method = method
+        .ok_or(HttpError::BadValue("http2 request missing :method"))?
+        .parse::<http_types::Method>()
+        .map_err(|_| HttpError::BadValue("http request with unrecognised method"))?;
+
+    if scheme.is_none() {
+        return Err(HttpError::BadValue("http2 request missing :scheme"));
+    }
+
+    let path = path.ok_or(HttpError::BadValue("http2 request missing :path"))?;
+    let host = authority
+        .or(host)
+        .ok_or(HttpError::BadValue("http request missing host header"))?;
+
+    Ok(PartialRequest {
+        http_req: HttpRequest {
+            method,
+            url: url(&host, &path)?,
+            version: Version::Http2,
+            headers,
+            context: context.unwrap_or_else(Context::new),
+            keep_alive: true,
+            content_type,
+            content_length: 0,
+            body: None,
+            tls: None,
+            addrs: None,
+        },
+        expected_length,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mio::Token;
+
+    use super::frame::{self, FrameHeader};
+    use super::{
+        build_request, Connection, H2Stream, Output, StreamState, MAX_HEADER_BLOCK_SIZE, PREFACE,
+    };
+    use crate::config::Config;
+    use crate::errorpages::ErrorPages;
+    use crate::http::HttpError;
+
+    fn connection() -> Connection {
+        let cfg = Config::default();
+        let error_pages = Arc::new(ErrorPages::load(&cfg).unwrap());
+        let mut conn = Connection::new(Token(1), &cfg, PREFACE.to_vec(), error_pages);
+
+        // Our SETTINGS
+        conn.write_buf.clear();
+        conn
+    }
+
+    // Adds a stream part way through its request body
+    fn receiving(conn: &mut Connection, stream_id: u32) {
+        let partial_req = build_request(fields(&[]), false).ok().unwrap();
+        conn.streams.insert(
+            stream_id,
+            H2Stream {
+                tk: Token(stream_id as usize),
+                send_window: frame::DEFAULT_WINDOW_SIZE,
+                recv_window: frame::DEFAULT_WINDOW_SIZE,
+                state: StreamState::Receiving(Box::new(partial_req)),
+            },
+        );
+        conn.last_stream_id = stream_id;
+    }
+
+    fn send(conn: &mut Connection, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        frame::write_frame(&mut conn.read_buf, kind, flags, stream_id, payload);
+        conn.process_buffered(&mut vec![]).ok().unwrap();
+    }
+
+    // Kind, stream id and payload of each frame we've queued
+    fn written(conn: &mut Connection) -> Vec<(u8, u32, Vec<u8>)> {
+        let mut frames = vec![];
+        let mut buf = &conn.write_buf[..];
+
+        while !buf.is_empty() {
+            let header = FrameHeader::parse(buf);
+            let end = frame::HEADER_SIZE + header.len;
+            frames.push((
+                header.kind,
+                header.stream_id,
+                buf[frame::HEADER_SIZE..end].to_vec(),
+            ));
+            buf = &buf[end..];
+        }
+
+        conn.write_buf.clear();
+        frames
+    }
+
+    fn window_update(stream_id: u32, increment: u32) -> (u8, u32, Vec<u8>) {
+        (
+            frame::WINDOW_UPDATE,
+            stream_id,
+            increment.to_be_bytes().to_vec(),
+        )
+    }
+
+    fn rst_stream(stream_id: u32, error_code: u32) -> (u8, u32, Vec<u8>) {
+        (
+            frame::RST_STREAM,
+            stream_id,
+            error_code.to_be_bytes().to_vec(),
+        )
+    }
+
+    // The pseudo headers of a valid request followed by extra
+    fn fields(extra: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        [
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/upload?a=1"),
+        ]
+        .iter()
+        .chain(extra)
+        .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .collect()
+    }
+
+    fn rejected(fields: Vec<(Vec<u8>, Vec<u8>)>) -> &'static str {
+        match build_request(fields, false) {
+            Err(HttpError::BadValue(reason)) => reason,
+            _ => panic!("request wasn't rejected"),
+        }
+    }
+
+    fn without(name: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+        fields(&[])
+            .into_iter()
+            .filter(|(n, _)| n != name.as_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn data_credited_once_stored() {
+        let mut conn = connection();
+        receiving(&mut conn, 1);
+
+        send(&mut conn, frame::DATA, 0, 1, b"hello");
+        assert_eq!(
+            written(&mut conn),
+            vec![window_update(0, 5), window_update(1, 5)]
+        );
+        assert_eq!(conn.recv_window, frame::DEFAULT_WINDOW_SIZE);
+
+        // Padding counts too, and the stream needs no credit once it ends
+        send(
+            &mut conn,
+            frame::DATA,
+            frame::FLAG_PADDED | frame::FLAG_END_STREAM,
+            1,
+            b"\x02hi\0\0",
+        );
+        assert_eq!(written(&mut conn), vec![window_update(0, 5)]);
+
+        match conn.streams[&1].state {
+            StreamState::Pending => {}
+            _ => panic!("request wasn't completed"),
+        }
+    }
+
+    #[test]
+    fn connection_window_exceeded() {
+        let mut conn = connection();
+        receiving(&mut conn, 1);
+        conn.recv_window = 4;
+
+        send(&mut conn, frame::DATA, 0, 1, b"hello");
+        assert_eq!(conn.error(), Some("http2 connection window exceeded"));
+
+        let frames = written(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0, frame::GOAWAY);
+        assert_eq!(frames[0].2[4..], frame::FLOW_CONTROL_ERROR.to_be_bytes());
+    }
+
+    #[test]
+    fn stream_window_exceeded() {
+        let mut conn = connection();
+        receiving(&mut conn, 1);
+        conn.streams.get_mut(&1).unwrap().recv_window = 4;
+
+        send(&mut conn, frame::DATA, 0, 1, b"hello");
+        assert_eq!(conn.error(), None);
+        assert_eq!(
+            written(&mut conn),
+            vec![rst_stream(1, frame::FLOW_CONTROL_ERROR)]
+        );
+        assert!(conn.streams.is_empty());
+        // Never credited back
+        assert_eq!(conn.recv_window, frame::DEFAULT_WINDOW_SIZE - 5);
+    }
+
+    #[test]
+    fn no_credit_for_closed_streams() {
+        let mut conn = connection();
+        receiving(&mut conn, 3);
+        conn.streams.get_mut(&3).unwrap().state = StreamState::Pending;
+
+        // Already fully received
+        let mut out = vec![];
+        frame::write_frame(&mut conn.read_buf, frame::DATA, 0, 3, b"hello");
+        conn.process_buffered(&mut out).ok().unwrap();
+        assert_eq!(
+            written(&mut conn),
+            vec![rst_stream(3, frame::STREAM_CLOSED)]
+        );
+        assert!(matches!(out[..], [Output::Cancel(Token(3))]));
+
+        // Reset earlier
+        send(&mut conn, frame::DATA, 0, 1, b"hello");
+        assert_eq!(
+            written(&mut conn),
+            vec![rst_stream(1, frame::STREAM_CLOSED)]
+        );
+
+        assert_eq!(conn.recv_window, frame::DEFAULT_WINDOW_SIZE - 10);
+    }
+
+    #[test]
+    fn data_on_idle_stream() {
+        let mut conn = connection();
+
+        send(&mut conn, frame::DATA, 0, 1, b"hello");
+        assert_eq!(conn.error(), Some("http2 DATA on idle stream"));
+        assert_eq!(written(&mut conn)[0].0, frame::GOAWAY);
+    }
+
+    #[test]
+    fn data_bad_padding() {
+        let mut conn = connection();
+        receiving(&mut conn, 1);
+
+        send(&mut conn, frame::DATA, frame::FLAG_PADDED, 1, b"\x05hi");
+        assert_eq!(conn.error(), Some("http2 bad padding"));
+        assert_eq!(written(&mut conn)[0].0, frame::GOAWAY);
+    }
+
+    #[test]
+    fn continuation_too_large() {
+        let mut conn = connection();
+        let chunk = vec![0; frame::DEFAULT_MAX_FRAME_SIZE];
+
+        send(&mut conn, frame::HEADERS, 0, 1, &chunk);
+        for _ in 0..MAX_HEADER_BLOCK_SIZE / chunk.len() - 1 {
+            send(&mut conn, frame::CONTINUATION, 0, 1, &chunk);
+            assert_eq!(conn.error(), None);
+        }
+
+        send(&mut conn, frame::CONTINUATION, 0, 1, b"x");
+        assert_eq!(conn.error(), Some("http2 header block too large"));
+    }
+
+    #[test]
+    fn continuation_interrupted() {
+        let mut conn = connection();
+
+        send(&mut conn, frame::HEADERS, 0, 1, b"");
+        send(&mut conn, frame::CONTINUATION, 0, 3, b"");
+        assert_eq!(conn.error(), Some("http2 unexpected CONTINUATION"));
+
+        let mut conn = connection();
+        send(&mut conn, frame::HEADERS, 0, 1, b"");
+        send(&mut conn, frame::PING, 0, 0, &[0; 8]);
+        assert_eq!(conn.error(), Some("http2 expected CONTINUATION frame"));
+    }
+
+    #[test]
+    fn settings() {
+        let mut conn = connection();
+        receiving(&mut conn, 1);
+
+        let mut payload = vec![];
+        payload.extend(frame::SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes());
+        payload.extend(100_000u32.to_be_bytes());
+        payload.extend(frame::SETTINGS_MAX_FRAME_SIZE.to_be_bytes());
+        payload.extend(32768u32.to_be_bytes());
+        send(&mut conn, frame::SETTINGS, 0, 0, &payload);
+
+        assert_eq!(conn.error(), None);
+        assert_eq!(conn.peer_initial_window, 100_000);
+        assert_eq!(conn.peer_max_frame_size, 32768);
+        // Existing streams move by the difference
+        assert_eq!(conn.streams[&1].send_window, 100_000);
+        assert_eq!(written(&mut conn), vec![(frame::SETTINGS, 0, vec![])]);
+    }
+
+    #[test]
+    fn bad_settings() {
+        let setting = |id: u16, value: u32| {
+            let mut payload = id.to_be_bytes().to_vec();
+            payload.extend(value.to_be_bytes());
+            payload
+        };
+
+        for (payload, error) in [
+            (vec![0; 5], "http2 bad SETTINGS frame"),
+            (
+                setting(frame::SETTINGS_INITIAL_WINDOW_SIZE, 1 << 31),
+                "http2 initial window size too large",
+            ),
+            (
+                setting(frame::SETTINGS_MAX_FRAME_SIZE, 16383),
+                "http2 bad max frame size",
+            ),
+            (
+                setting(frame::SETTINGS_MAX_FRAME_SIZE, 1 << 24),
+                "http2 bad max frame size",
+            ),
+            (
+                setting(frame::SETTINGS_ENABLE_PUSH, 2),
+                "http2 bad enable push",
+            ),
+        ] {
+            let mut conn = connection();
+            send(&mut conn, frame::SETTINGS, 0, 0, &payload);
+            assert_eq!(conn.error(), Some(error));
+        }
+
+        let mut conn = connection();
+        send(&mut conn, frame::SETTINGS, 0, 1, &[]);
+        assert_eq!(conn.error(), Some("http2 SETTINGS on a stream"));
+    }
+
+    #[test]
+    fn request() {
+        let partial_req = build_request(
+            fields(&[
+                ("content-length", "5"),
+                ("cookie", "a=1"),
+                ("cookie", "b=2"),
+                ("te", "trailers"),
+            ]),
+            true,
+        )
+        .ok()
+        .unwrap();
+
+        let http_req = &partial_req.http_req;
+        assert_eq!(partial_req.expected_length, Some(5));
+        assert_eq!(http_req.method, http_types::Method::Post);
+        assert_eq!(http_req.url.host_str(), Some("example.com"));
+        assert_eq!(http_req.url.path(), "/upload");
+        assert_eq!(http_req.url.query(), Some("a=1"));
+        assert_eq!(
+            http_req.headers,
+            vec![
+                ("content-length".to_string(), "5".to_string()),
+                ("te".to_string(), "trailers".to_string()),
+                ("cookie".to_string(), "a=1; b=2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_pseudo_headers() {
+        assert_eq!(
+            rejected(without(":method")),
+            "http2 request missing :method"
+        );
+        assert_eq!(rejected(without(":path")), "http2 request missing :path");
+        assert_eq!(
+            rejected(without(":scheme")),
+            "http2 request missing :scheme"
+        );
+        assert_eq!(
+            rejected(without(":authority")),
+            "http request missing host header"
+        );
+
+        // Host stands in for :authority
+        let mut with_host = without(":authority");
+        with_host.push((b"host".to_vec(), b"example.com".to_vec()));
+        assert!(build_request(with_host, false).is_ok());
+    }
+
+    #[test]
+    fn bad_pseudo_headers() {
+        let mut after = fields(&[("accept", "*/*")]);
+        after.push((b":protocol".to_vec(), b"x".to_vec()));
+        assert_eq!(rejected(after), "pseudo header after regular header");
+
+        assert_eq!(
+            rejected(fields(&[(":path", "/")])),
+            "duplicate pseudo header"
+        );
+        assert_eq!(
+            rejected(fields(&[(":status", "200")])),
+            "unknown pseudo header"
+        );
+    }
+
+    #[test]
+    fn uppercase_header() {
+        assert_eq!(
+            rejected(fields(&[("Accept", "*/*")])),
+            "uppercase http2 header name"
+        );
+    }
+
+    #[test]
+    fn connection_headers() {
+        for (name, value) in [
+            ("connection", "close"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("te", "gzip"),
+        ] {
+            assert_eq!(
+                rejected(fields(&[(name, value)])),
+                "connection header in http2 request",
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn underscore_header() {
+        let partial_req = build_request(fields(&[("x_forwarded_for", "1.2.3.4")]), false)
+            .ok()
+            .unwrap();
+        assert!(partial_req.http_req.headers.is_empty());
+
+        assert!(matches!(
+            build_request(fields(&[("x_forwarded_for", "1.2.3.4")]), true),
+            Err(HttpError::BadValue("header name contains underscore"))
+        ));
+    }
+
+    #[test]
+    fn conflicting_content_length() {
+        assert!(build_request(
+            fields(&[("content-length", "5"), ("content-length", "5")]),
+            false
+        )
+        .is_ok());
+        assert_eq!(
+            rejected(fields(&[("content-length", "5"), ("content-length", "6")])),
+            "conflicting Content-Length headers"
+        );
+    }
+}
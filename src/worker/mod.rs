@@ -1,50 +1,77 @@
 use std::collections::HashMap;
 use std::os::unix::io::FromRawFd;
-use std::os::unix::io::IntoRawFd;
 use std::sync::Arc;
 use std::time;
 
 use fd_queue::mio::UnixStream;
 use mio::{net::TcpStream, Token};
-use ndjsonlogger::info;
+use ndjsonlogger::{error, info, warn};
+use rustls::ServerConfig;
 
 use crate::config::Config;
+use crate::errorpages::ErrorPages;
 use crate::errors::{fatal_io_error, RuntimeResult};
-use crate::http::HttpError;
+use crate::http::{Context, HttpError, HttpResponse};
 use crate::msgs;
 use crate::pythonexec;
+use crate::server::KEEP_ALIVE_COUNT_INC;
+use crate::tls;
 
 mod actions;
 use actions::{
-    new_408_timeout, new_503_service_busy, new_504_gateway_timeout, Action, ActionResult,
-    CasketResponse, Error as ActionError, ErrorSource,
+    new_400_bad_request, new_408_timeout, new_503_service_busy, new_504_gateway_timeout, Action,
+    ActionResult, CasketResponse, Error as ActionError, ErrorSource,
 };
+mod cache;
+pub mod compress;
+pub mod conditional;
 mod events;
 use events::Event;
+mod http2;
 mod poller;
 mod pythonthreads;
+mod sendfile;
 mod serverreader;
 mod serverwriter;
+mod slowstreams;
+mod staticfiles;
+mod stream;
+use stream::Stream;
 
 const UNIX_STREAM_TOKEN: Token = Token(0);
 const NO_TOKEN: Token = Token(1);
-const POLL_TIME: time::Duration = time::Duration::from_millis(20);
+const PYTHON_WAKER_TOKEN: Token = Token(2);
+const BODY_RATE_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+// How often we wake to check for a SIGHUP that was delivered to another thread
+const SIGNAL_CHECK_TIME: time::Duration = time::Duration::from_secs(1);
 
 struct Worker {
     msg_buf: msgs::WorkerMsgBuffer,
     poll: poller::Poller,
     python_threads: pythonthreads::PythonThreads,
+    slow_streams: slowstreams::SlowStreams,
+    tls: Option<Arc<ServerConfig>>,
+    error_pages: Arc<ErrorPages>,
+    cache: Option<cache::Cache>,
 
-    server_reading_streams: HashMap<Token, (TcpStream, serverreader::Reader)>,
-    server_pending_streams: HashMap<Token, TcpStream>,
-    server_writing_streams: HashMap<Token, (TcpStream, serverwriter::Writer)>,
-    server_casket_responses: HashMap<Token, (TcpStream, CasketResponse)>,
+    server_reading_streams: HashMap<Token, (Stream, serverreader::Reader)>,
+    server_pending_streams: HashMap<Token, Stream>,
+    server_writing_streams: HashMap<Token, (Stream, serverwriter::Writer)>,
+    // Out of the poll until python sends more of the body
+    server_waiting_streams: HashMap<Token, (Stream, serverwriter::Writer)>,
+    server_casket_responses: HashMap<Token, (Stream, CasketResponse)>,
+
+    // HTTP/2 connections stay in the worker, each stream has its own token
+    http2_conns: HashMap<Token, (Stream, http2::Connection)>,
+    http2_streams: HashMap<Token, Token>,
 }
 
 pub fn run_worker(
     cfg: Arc<Config>,
     application: pythonexec::Application,
     mut unix_stream: UnixStream,
+    tls: Option<Arc<ServerConfig>>,
+    error_pages: Arc<ErrorPages>,
 ) -> RuntimeResult {
     let mut poll = poller::Poller::new()
         .map_err(|e| fatal_io_error("worker couldn't create poll instance", e))?;
@@ -52,15 +79,32 @@ pub fn run_worker(
     poll.register_read(&mut unix_stream, UNIX_STREAM_TOKEN, Event::UnixStreamRead)
         .map_err(|e| fatal_io_error("worker couldn't register unix stream for reading", e))?;
 
+    let waker = poll
+        .waker(PYTHON_WAKER_TOKEN, Event::PythonWake)
+        .map_err(|e| fatal_io_error("worker couldn't create poll waker", e))?;
+
     let mut worker = Worker {
         msg_buf: msgs::WorkerMsgBuffer::new(),
         poll,
-        python_threads: pythonthreads::PythonThreads::new(cfg.clone(), application),
+        python_threads: pythonthreads::PythonThreads::new(
+            cfg.clone(),
+            application,
+            waker,
+            error_pages.clone(),
+        ),
+        slow_streams: slowstreams::SlowStreams::new(cfg.max_slow_conns_per_ip),
+        tls,
+        error_pages,
+        cache: cache::Cache::new(&cfg),
 
         server_reading_streams: HashMap::new(),
         server_pending_streams: HashMap::new(),
         server_writing_streams: HashMap::new(),
+        server_waiting_streams: HashMap::new(),
         server_casket_responses: HashMap::new(),
+
+        http2_conns: HashMap::new(),
+        http2_streams: HashMap::new(),
     };
 
     let mut events_buf = Vec::with_capacity(64);
@@ -74,26 +118,39 @@ pub fn run_worker(
             && worker.python_threads.num_pending_reqs() == 0
             && !worker.python_threads.has_queued_reqs()
             && worker.server_writing_streams.is_empty()
+            && worker.server_waiting_streams.is_empty()
+            && worker.http2_conns.is_empty()
         {
             break Ok(());
         }
 
-        while let Some((tk, fd)) = worker.msg_buf.next_stream_fd() {
-            events_buf.push((tk, Event::NewStreamFd(fd)));
+        while let Some((tk, fd, addrs)) = worker.msg_buf.next_stream_fd() {
+            events_buf.push((tk, Event::NewStreamFd((fd, addrs))));
         }
 
-        if worker.python_threads.num_pending_reqs() > 0 {
-            events_buf.push((NO_TOKEN, Event::PollPythonResponses));
+        // Another worker's app asked for a purge
+        while let Some(purge) = worker.msg_buf.next_purge() {
+            if let Some(cache) = worker.cache.as_mut() {
+                cache.purge(&purge);
+            }
         }
 
         if worker.python_threads.has_queued_reqs() {
             events_buf.push((NO_TOKEN, Event::QueuedRequests));
         }
 
-        let timeout = if events_buf.is_empty() {
-            None
+        if tls::take_sighup() {
+            events_buf.push((NO_TOKEN, Event::Sighup));
+        }
+
+        // Python threads wake the poll themselves, we only
+        // check for events already waiting to be handled
+        let timeout = if !events_buf.is_empty() {
+            Some(time::Duration::ZERO)
+        } else if worker.tls.is_some() {
+            Some(SIGNAL_CHECK_TIME)
         } else {
-            Some(POLL_TIME)
+            None
         };
 
         worker.poll.tick(&mut events_buf, timeout)?;
@@ -102,7 +159,10 @@ pub fn run_worker(
             match ev {
                 Event::CtrlC => {
                     closing = true;
+                    close_idle_streams(&mut worker);
+                    http2_go_away_all(&cfg, &mut worker);
                 }
+                Event::Sighup => reload_tls(&cfg, &mut worker),
                 Event::UnixStreamRead => {
                     worker
                         .msg_buf
@@ -115,13 +175,14 @@ pub fn run_worker(
                         .write_unix_stream(&mut unix_stream)
                         .map_err(|e| fatal_io_error("worker couldn't read unix stream", e))?;
                 }
-                Event::NewStreamFd(fd) => {
+                Event::NewStreamFd((fd, addrs)) => {
                     let tcp_stream = unsafe { TcpStream::from_raw_fd(fd) };
 
-                    if worker.python_threads.num_pending_reqs() >= cfg.max_requests {
-                        worker_results.push(Ok(new_503_service_busy(tk, tcp_stream)));
-                    } else {
-                        worker_results.push(Ok(Action::NewServerRequest((tk, tcp_stream))));
+                    match Stream::new(tcp_stream, addrs, worker.tls.as_ref()) {
+                        Ok(tcp_stream) => {
+                            worker_results.push(Ok(Action::NewServerRequest((tk, tcp_stream))))
+                        }
+                        Err(e) => worker.msg_buf.resp_io_error(tk, e),
                     }
                 }
                 Event::ServerStreamRead => {
@@ -130,11 +191,12 @@ pub fn run_worker(
                         .remove(&tk)
                         .expect("couldn't find reading stream");
 
-                    worker_results.push(event_server_stream_read(tk, tcp_stream, reader));
+                    worker_results.push(server_stream_read(&mut worker, tk, tcp_stream, reader));
                 }
                 Event::QueuedRequests => worker.python_threads.send_queued_requests()?,
-                Event::PollPythonResponses => {
-                    worker.python_threads.take_responses(&mut worker_results)?
+                Event::PythonWake => {
+                    worker.python_threads.take_responses(&mut worker_results)?;
+                    resume_waiting_streams(&cfg, &mut worker);
                 }
                 Event::ServerStreamWrite => {
                     let (tcp_stream, writer) = worker
@@ -144,9 +206,18 @@ pub fn run_worker(
 
                     worker_results.push(event_server_stream_write(tk, tcp_stream, writer));
                 }
+                Event::ResponseWriteCheck => {
+                    events_timeout_buf.push((tk, events::Timeout::ResponseWrite));
+                }
+                Event::HeaderReadTimeout => {
+                    events_timeout_buf.push((tk, events::Timeout::HeaderRead));
+                }
                 Event::RequestReadTimeout => {
                     events_timeout_buf.push((tk, events::Timeout::RequestRead));
                 }
+                Event::BodyRateCheck => {
+                    events_timeout_buf.push((tk, events::Timeout::BodyRate));
+                }
                 Event::CasketResponseWrite => {
                     let (tcp_stream, casket_resp) = worker
                         .server_casket_responses
@@ -158,6 +229,11 @@ pub fn run_worker(
                 Event::PythonCodeTimeout => {
                     events_timeout_buf.push((tk, events::Timeout::PythonCode));
                 }
+                Event::Http2Read => http2_read(&cfg, &mut worker, tk),
+                Event::Http2Write => http2_write(&cfg, &mut worker, tk),
+                Event::Http2IdleCheck => {
+                    events_timeout_buf.push((tk, events::Timeout::Http2Idle));
+                }
             }
         }
 
@@ -165,25 +241,155 @@ pub fn run_worker(
         for res in worker_results.drain(..) {
             match res {
                 Ok(act) => handle_action(&cfg, &mut worker, act),
-                Err(e) => handle_error(&mut worker, e),
+                Err(e) => handle_error(&cfg, &mut worker, *e),
             }
         }
 
         // Timeout events
         for (tk, ev) in events_timeout_buf.drain(..) {
             match ev {
+                events::Timeout::HeaderRead => {
+                    let (reading_header, has_data) = match worker.server_reading_streams.get(&tk) {
+                        Some((_, reader)) => (reader.is_reading_header(), reader.has_data()),
+                        None => (false, false),
+                    };
+
+                    if reading_header && !has_data {
+                        // Idle keep-alive stream (tls) - close quietly
+                        close_reading_stream(&mut worker, tk)?;
+                    } else if reading_header {
+                        timeout_reading_stream(&mut worker, tk, &mut worker_results)?;
+                    }
+                }
                 events::Timeout::RequestRead => {
-                    if let Some((mut tcp_stream, _)) = worker.server_reading_streams.remove(&tk) {
-                        worker.poll.deregister(&mut tcp_stream).map_err(|e| {
-                            fatal_io_error("worker couldn't deregister stream poll", e)
-                        })?;
-                        worker_results.push(Ok(new_408_timeout(tk, tcp_stream)));
+                    timeout_reading_stream(&mut worker, tk, &mut worker_results)?;
+                }
+                events::Timeout::BodyRate => {
+                    let (too_slow, behind, ip) = match worker.server_reading_streams.get(&tk) {
+                        Some((tcp_stream, reader)) => (
+                            reader.body_too_slow(cfg.body_min_rate, cfg.body_rate_grace_period),
+                            reader.body_too_slow(cfg.body_min_rate, time::Duration::ZERO),
+                            tcp_stream.addrs().remote.ip(),
+                        ),
+                        // Finished reading - stop checking
+                        None => continue,
+                    };
+
+                    if !behind {
+                        worker.slow_streams.unmark(tk);
+                    }
+
+                    if too_slow {
+                        timeout_reading_stream(&mut worker, tk, &mut worker_results)?;
+                    } else if behind && !worker.slow_streams.mark(tk, ip) {
+                        // Within its grace period, but the client has enough slow streams
+                        warn!("too many slow tcp streams from client", {
+                            "client.ip"                        = &ip.to_string(),
+                            "cfg.max_slow_conns_per_ip": usize = cfg.max_slow_conns_per_ip
+                        });
+                        timeout_reading_stream(&mut worker, tk, &mut worker_results)?;
+                    } else {
+                        let timeout = time::SystemTime::now() + BODY_RATE_CHECK_INTERVAL;
+                        worker.poll.timer_event(tk, timeout, Event::BodyRateCheck);
                     }
                 }
                 events::Timeout::PythonCode => {
                     if let Some(tcp_stream) = worker.server_pending_streams.remove(&tk) {
-                        worker.python_threads.timeout_request(tk);
-                        worker_results.push(Ok(new_504_gateway_timeout(tk, tcp_stream)));
+                        let ctx = worker
+                            .python_threads
+                            .timeout_request(tk)
+                            .unwrap_or_else(Context::new);
+                        worker_results.push(Ok(new_504_gateway_timeout(
+                            tk,
+                            tcp_stream,
+                            &worker.error_pages,
+                            &ctx,
+                        )));
+                    } else if let Some(conn_tk) = worker.http2_streams.get(&tk).copied() {
+                        http2_python_code_timeout(&cfg, &mut worker, conn_tk, tk);
+                    }
+                }
+                events::Timeout::ResponseWrite => {
+                    let write_timeout = match cfg.response_write_timeout {
+                        Some(write_timeout) => write_timeout,
+                        None => continue,
+                    };
+
+                    let write_stalled = match worker.server_writing_streams.get(&tk) {
+                        Some((_, writer)) => writer.write_stalled_since(),
+                        // Waiting on the app, that's not the client's time
+                        None if worker.server_waiting_streams.contains_key(&tk) => None,
+                        // Finished writing - stop checking
+                        None => continue,
+                    };
+
+                    let next_check = match write_stalled {
+                        Some(write_stalled) => write_stalled + write_timeout,
+                        None => time::SystemTime::now() + write_timeout,
+                    };
+
+                    if next_check <= time::SystemTime::now() {
+                        close_writing_stream(&mut worker, tk)?;
+                    } else {
+                        worker
+                            .poll
+                            .timer_event(tk, next_check, Event::ResponseWriteCheck);
+                    }
+                }
+                events::Timeout::Http2Idle => {
+                    let (idle_since, write_stalled) = match worker.http2_conns.get(&tk) {
+                        Some((_, conn)) => (conn.idle_since(), conn.write_stalled_since()),
+                        None => continue,
+                    };
+
+                    // A client which stops reading holds python threads on full body channels.
+                    // Checked again within the write timeout while it's keeping up.
+                    let write_deadline = cfg.response_write_timeout.map(|write_timeout| {
+                        write_stalled.unwrap_or_else(time::SystemTime::now) + write_timeout
+                    });
+
+                    let write_expired = match (write_stalled, write_deadline) {
+                        (Some(_), Some(write_deadline)) => {
+                            write_deadline <= time::SystemTime::now()
+                        }
+                        _ => false,
+                    };
+
+                    if write_expired {
+                        let (tcp_stream, conn) = worker
+                            .http2_conns
+                            .remove(&tk)
+                            .expect("couldn't find http2 connection");
+
+                        info!("timed out writing http2 connection", {
+                            "client.ip" = &tcp_stream.addrs().remote.ip().to_string()
+                        });
+                        http2_close(&mut worker, tk, tcp_stream, conn);
+                        continue;
+                    }
+
+                    let next_check = match idle_since {
+                        Some(idle_since) => idle_since + cfg.http2_idle_timeout,
+                        None => time::SystemTime::now() + cfg.http2_idle_timeout,
+                    };
+
+                    if next_check <= time::SystemTime::now() {
+                        let (tcp_stream, mut conn) = worker
+                            .http2_conns
+                            .remove(&tk)
+                            .expect("couldn't find http2 connection");
+
+                        conn.go_away();
+                        http2_continue(&cfg, &mut worker, tk, tcp_stream, conn, vec![], false);
+                    } else {
+                        let next_check = match write_deadline {
+                            Some(write_deadline) => next_check.min(write_deadline),
+                            None => next_check,
+                        };
+
+                        worker
+                            .poll
+                            .timer_event(tk, next_check, Event::Http2IdleCheck);
                     }
                 }
             }
@@ -193,7 +399,7 @@ pub fn run_worker(
         for res in worker_results.drain(..) {
             match res {
                 Ok(act) => handle_action(&cfg, &mut worker, act),
-                Err(e) => handle_error(&mut worker, e),
+                Err(e) => handle_error(&cfg, &mut worker, *e),
             }
         }
 
@@ -230,15 +436,41 @@ fn handle_action(cfg: &Config, worker: &mut Worker, act: Action) {
                 return;
             }
 
-            let timeout = time::SystemTime::now() + cfg.request_read_timeout;
+            let now = time::SystemTime::now();
 
             worker
                 .poll
-                .timer_event(tk, timeout, Event::RequestReadTimeout);
+                .timer_event(tk, now + cfg.header_read_timeout, Event::HeaderReadTimeout);
+
+            if let Some(request_read_timeout) = cfg.request_read_timeout {
+                worker
+                    .poll
+                    .timer_event(tk, now + request_read_timeout, Event::RequestReadTimeout);
+            }
+
+            if cfg.body_min_rate > 0 {
+                // The slow stream cap watches the rate during the grace period too
+                let first_check = if cfg.max_slow_conns_per_ip > 0 {
+                    BODY_RATE_CHECK_INTERVAL
+                } else {
+                    cfg.body_rate_grace_period
+                };
+
+                worker
+                    .poll
+                    .timer_event(tk, now + first_check, Event::BodyRateCheck);
+            }
+
+            let reader = serverreader::Reader::new(cfg);
+            if tcp_stream.has_buffered_data() {
+                // A pipelined request came in the same tls record as the last one
+                read_buffered(cfg, worker, tk, tcp_stream, reader);
+                return;
+            }
 
             worker
                 .server_reading_streams
-                .insert(tk, (tcp_stream, serverreader::Reader::new()));
+                .insert(tk, (tcp_stream, reader));
         }
         ServerContinueRead((tk, reader, mut tcp_stream)) => {
             if let Err(e) =
@@ -250,6 +482,11 @@ fn handle_action(cfg: &Config, worker: &mut Worker, act: Action) {
                 return;
             }
 
+            if tcp_stream.has_buffered_data() {
+                read_buffered(cfg, worker, tk, tcp_stream, reader);
+                return;
+            }
+
             worker
                 .server_reading_streams
                 .insert(tk, (tcp_stream, reader));
@@ -260,6 +497,33 @@ fn handle_action(cfg: &Config, worker: &mut Worker, act: Action) {
                 return;
             }
 
+            // Static files never reach python
+            if staticfiles::is_static(cfg, &http_req) {
+                let http_resp = staticfiles::serve(cfg, &worker.error_pages, *http_req);
+                worker.server_pending_streams.insert(tk, tcp_stream);
+                start_response(cfg, worker, tk, Box::new(http_resp));
+                return;
+            }
+
+            let http_req = match worker.cache.as_mut() {
+                Some(cache) => match cache.serve(http_req) {
+                    Ok(http_resp) => {
+                        worker.server_pending_streams.insert(tk, tcp_stream);
+                        start_response(cfg, worker, tk, http_resp);
+                        return;
+                    }
+                    Err(http_req) => http_req,
+                },
+                None => http_req,
+            };
+
+            if worker.python_threads.num_pending_reqs() >= cfg.max_requests {
+                let act =
+                    new_503_service_busy(tk, tcp_stream, &worker.error_pages, &http_req.context);
+                handle_action(cfg, worker, act);
+                return;
+            }
+
             worker.python_threads.queue_http_req(tk, http_req);
             worker.server_pending_streams.insert(tk, tcp_stream);
         }
@@ -273,28 +537,41 @@ fn handle_action(cfg: &Config, worker: &mut Worker, act: Action) {
                 .msg_buf
                 .resp_stream_done_ok(tk, tcp_stream.into_raw_fd(), false);
         }
-        ServerNewResponse((tk, http_resp)) => {
-            let mut tcp_stream = worker
-                .server_pending_streams
-                .remove(&tk)
-                .expect("worker couldn't find pending stream");
+        Http2Start((tk, buf, tcp_stream)) => {
+            let mut conn = http2::Connection::new(tk, cfg, buf, worker.error_pages.clone());
+            let mut outputs = vec![];
 
-            if let Err(e) =
-                worker
-                    .poll
-                    .register_write(&mut tcp_stream, tk, Event::ServerStreamWrite)
-            {
-                worker.msg_buf.resp_stream_reg_error(tk, e);
-                return;
-            }
+            let closed = match conn.process_buffered(&mut outputs) {
+                Ok(()) => false,
+                Err(e) => {
+                    http2_io_error(e);
+                    true
+                }
+            };
 
-            worker.server_writing_streams.insert(
+            worker.poll.timer_event(
                 tk,
-                (
-                    tcp_stream,
-                    serverwriter::Writer::new(http_resp, vec![0; 2048]),
-                ),
+                time::SystemTime::now() + cfg.http2_idle_timeout,
+                Event::Http2IdleCheck,
             );
+
+            http2_continue(cfg, worker, tk, tcp_stream, conn, outputs, closed);
+        }
+        ServerNewResponse((tk, mut http_resp)) => {
+            sendfile::offload(cfg, &worker.error_pages, &mut http_resp);
+
+            if let Some(cache) = worker.cache.as_mut() {
+                for purge in cache::take_purges(&mut http_resp) {
+                    cache.purge(&purge);
+                    worker.msg_buf.resp_purge(purge);
+                }
+
+                cache.capture(&mut http_resp);
+            }
+
+            cache::strip_headers(&mut http_resp);
+
+            start_response(cfg, worker, tk, http_resp);
         }
         ServerContinueWrite((tk, writer, mut tcp_stream)) => {
             if let Err(e) =
@@ -310,23 +587,37 @@ fn handle_action(cfg: &Config, worker: &mut Worker, act: Action) {
                 .server_writing_streams
                 .insert(tk, (tcp_stream, writer));
         }
-        ServerDoneWrite((tk, http_resp, mut tcp_stream)) => {
-            info!("sent HTTP response", {
-                "http.status_code": u16           = http_resp.code,
-                "http.method"                     = http_resp.method.as_ref(),
-                "http.url.path"                   = http_resp.url.path(),
-                "http.req_content_length" :usize  = http_resp.req_content_length,
-                "http.resp_content_length":usize  = http_resp.resp_content_length.unwrap_or(0),
-                trace_id                          = &http_resp.context.trace_id,
-                span_id                           = &http_resp.context.span_id,
-                parent_id: Option<&str>           = http_resp.context.parent_id_as_ref()
-            });
+        ServerWaitBody((tk, writer, mut tcp_stream)) => {
+            // Writable would fire straight away with nothing to write
+            if let Err(e) = worker.poll.deregister(&mut tcp_stream) {
+                worker.msg_buf.resp_stream_reg_error(tk, e);
+                return;
+            }
+
+            worker
+                .server_waiting_streams
+                .insert(tk, (tcp_stream, writer));
+        }
+        ServerDoneWrite((tk, mut http_resp, mut tcp_stream)) => {
+            log_http_response(&http_resp);
+
+            if let Some(cache) = worker.cache.as_mut() {
+                cache.store(&mut http_resp);
+            }
 
             if let Err(e) = worker.poll.deregister(&mut tcp_stream) {
                 worker.msg_buf.resp_stream_reg_error(tk, e);
                 return;
             }
 
+            if http_resp.keep_alive && tcp_stream.is_tls() {
+                // The tls session can't go back to the server, read the next request here
+                let new_tk = Token(tk.0 + KEEP_ALIVE_COUNT_INC);
+                worker.msg_buf.rekey_stream(tk, new_tk);
+                handle_action(cfg, worker, NewServerRequest((new_tk, tcp_stream)));
+                return;
+            }
+
             worker
                 .msg_buf
                 .resp_stream_done_ok(tk, tcp_stream.into_raw_fd(), http_resp.keep_alive);
@@ -385,7 +676,396 @@ fn handle_action(cfg: &Config, worker: &mut Worker, act: Action) {
     }
 }
 
-fn handle_error(worker: &mut Worker, mut error: actions::Error) {
+// The response is ready, python's or one casket made itself
+fn start_response(cfg: &Config, worker: &mut Worker, tk: Token, http_resp: Box<HttpResponse>) {
+    if let Some(conn_tk) = worker.http2_streams.get(&tk).copied() {
+        let (tcp_stream, mut conn) = worker
+            .http2_conns
+            .remove(&conn_tk)
+            .expect("couldn't find http2 connection");
+
+        conn.start_response(tk, http_resp);
+        http2_continue(cfg, worker, conn_tk, tcp_stream, conn, vec![], false);
+        return;
+    }
+
+    let mut tcp_stream = worker
+        .server_pending_streams
+        .remove(&tk)
+        .expect("worker couldn't find pending stream");
+
+    if let Err(e) = worker
+        .poll
+        .register_write(&mut tcp_stream, tk, Event::ServerStreamWrite)
+    {
+        worker.msg_buf.resp_stream_reg_error(tk, e);
+        return;
+    }
+
+    worker.server_writing_streams.insert(
+        tk,
+        (
+            tcp_stream,
+            serverwriter::Writer::new(cfg, http_resp, vec![0; 2048]),
+        ),
+    );
+
+    if let Some(write_timeout) = cfg.response_write_timeout {
+        worker.poll.timer_event(
+            tk,
+            time::SystemTime::now() + write_timeout,
+            Event::ResponseWriteCheck,
+        );
+    }
+}
+
+fn log_http_response(http_resp: &HttpResponse) {
+    let client_addr = http_resp.addrs.map(|addrs| addrs.remote.to_string());
+    let server_addr = http_resp.addrs.map(|addrs| addrs.local.to_string());
+
+    info!("sent HTTP response", {
+        "http.status_code": u16           = http_resp.code,
+        "http.method"                     = http_resp.method.as_ref(),
+        "http.url.path"                   = http_resp.url.path(),
+        "http.req_content_length" :usize  = http_resp.req_content_length,
+        "http.resp_content_length":usize  = http_resp.resp_content_length.unwrap_or(0),
+        "client.address": Option<&str>    = client_addr.as_deref(),
+        "server.address": Option<&str>    = server_addr.as_deref(),
+        trace_id                          = &http_resp.context.trace_id,
+        span_id                           = &http_resp.context.span_id,
+        parent_id: Option<&str>           = http_resp.context.parent_id_as_ref()
+    });
+}
+
+// The app's body didn't match the Content-Length it set
+fn log_content_length_mismatch(http_resp: &HttpResponse, body_length: usize) {
+    warn!("response body doesn't match Content-Length", {
+        "http.content_length": usize = http_resp.resp_content_length.unwrap_or(0),
+        "http.body_length"   : usize = body_length,
+        trace_id                     = &http_resp.context.trace_id,
+        span_id                      = &http_resp.context.span_id,
+        parent_id: Option<&str>      = http_resp.context.parent_id_as_ref()
+    });
+}
+
+fn http2_read(cfg: &Config, worker: &mut Worker, tk: Token) {
+    let (mut tcp_stream, mut conn) = worker
+        .http2_conns
+        .remove(&tk)
+        .expect("couldn't find http2 connection");
+
+    let mut outputs = vec![];
+    let closed = match conn.read_tcp_stream(&mut tcp_stream, &mut outputs) {
+        Ok(eof) => eof,
+        Err(e) => {
+            http2_io_error(e);
+            true
+        }
+    };
+
+    http2_continue(cfg, worker, tk, tcp_stream, conn, outputs, closed);
+}
+
+fn http2_write(cfg: &Config, worker: &mut Worker, tk: Token) {
+    let (mut tcp_stream, mut conn) = worker
+        .http2_conns
+        .remove(&tk)
+        .expect("couldn't find http2 connection");
+
+    let mut outputs = vec![];
+    let closed = match conn.write_tcp_stream(&mut tcp_stream, &mut outputs) {
+        Ok(()) => false,
+        Err(e) => {
+            http2_io_error(e);
+            true
+        }
+    };
+
+    http2_continue(cfg, worker, tk, tcp_stream, conn, outputs, closed);
+}
+
+// Python may have sent more of a response body, try the streams waiting on one
+fn resume_waiting_streams(cfg: &Config, worker: &mut Worker) {
+    for (tk, (mut tcp_stream, writer)) in worker.server_waiting_streams.drain() {
+        if let Err(e) = worker
+            .poll
+            .register_write(&mut tcp_stream, tk, Event::ServerStreamWrite)
+        {
+            worker.msg_buf.resp_stream_reg_error(tk, e);
+            continue;
+        }
+
+        worker
+            .server_writing_streams
+            .insert(tk, (tcp_stream, writer));
+    }
+
+    let tks: Vec<Token> = worker
+        .http2_conns
+        .iter()
+        .filter(|(_, (_, conn))| conn.waiting_body())
+        .map(|(tk, _)| *tk)
+        .collect();
+
+    for tk in tks {
+        http2_write(cfg, worker, tk);
+    }
+}
+
+fn http2_io_error(error: HttpError) {
+    if let HttpError::Io((reason, err)) = error {
+        info!("i/o failed on tcp stream", {
+            reason,
+            error = &format!("{}", err)
+        });
+    }
+}
+
+fn http2_python_code_timeout(cfg: &Config, worker: &mut Worker, conn_tk: Token, tk: Token) {
+    let (tcp_stream, mut conn) = worker
+        .http2_conns
+        .remove(&conn_tk)
+        .expect("couldn't find http2 connection");
+
+    if conn.is_pending(tk) {
+        let ctx = worker
+            .python_threads
+            .timeout_request(tk)
+            .unwrap_or_else(Context::new);
+        worker.http2_streams.remove(&tk);
+        conn.error_response(tk, (504, "Gateway Timeout"), &ctx, None);
+
+        info!("casket sent error http response", {
+            "http.status_code": u16 = 504,
+            "reason" = "gateway timeout"
+        });
+    }
+
+    http2_continue(cfg, worker, conn_tk, tcp_stream, conn, vec![], false);
+}
+
+// On shutdown tell clients to stop opening streams, connections close once their streams are done
+fn http2_go_away_all(cfg: &Config, worker: &mut Worker) {
+    let tks: Vec<Token> = worker.http2_conns.keys().copied().collect();
+
+    for tk in tks {
+        if let Some((tcp_stream, mut conn)) = worker.http2_conns.remove(&tk) {
+            conn.go_away();
+            http2_continue(cfg, worker, tk, tcp_stream, conn, vec![], false);
+        }
+    }
+}
+
+// Act on what happened on the connection, then put it back in the poll (or close it)
+fn http2_continue(
+    cfg: &Config,
+    worker: &mut Worker,
+    tk: Token,
+    mut tcp_stream: Stream,
+    mut conn: http2::Connection,
+    outputs: Vec<http2::Output>,
+    closed: bool,
+) {
+    for output in outputs {
+        match output {
+            http2::Output::Request((stream_tk, mut http_req)) => {
+                http_req.addrs = Some(tcp_stream.addrs());
+
+                if let Some(tls_info) = tcp_stream.tls_info() {
+                    http_req.url.set_scheme("https").unwrap_or(());
+                    http_req.tls = Some(tls_info);
+                }
+
+                if staticfiles::is_static(cfg, &http_req) {
+                    let http_resp = staticfiles::serve(cfg, &worker.error_pages, *http_req);
+                    conn.start_response(stream_tk, Box::new(http_resp));
+                    continue;
+                }
+
+                let http_req = match worker.cache.as_mut() {
+                    Some(cache) => match cache.serve(http_req) {
+                        Ok(http_resp) => {
+                            conn.start_response(stream_tk, http_resp);
+                            continue;
+                        }
+                        Err(http_req) => http_req,
+                    },
+                    None => http_req,
+                };
+
+                if worker.python_threads.num_pending_reqs() >= cfg.max_requests {
+                    conn.refuse_stream(stream_tk);
+                    continue;
+                }
+
+                worker.http2_streams.insert(stream_tk, tk);
+                worker.python_threads.queue_http_req(stream_tk, http_req);
+            }
+            http2::Output::Cancel(stream_tk) => {
+                worker.http2_streams.remove(&stream_tk);
+                worker.python_threads.cancel_request(stream_tk);
+            }
+            http2::Output::Reset(stream_tk) => {
+                worker.http2_streams.remove(&stream_tk);
+            }
+            http2::Output::Done((stream_tk, mut http_resp)) => {
+                worker.http2_streams.remove(&stream_tk);
+                log_http_response(&http_resp);
+
+                if let Some(cache) = worker.cache.as_mut() {
+                    cache.store(&mut http_resp);
+                }
+            }
+        }
+    }
+
+    if closed || conn.is_finished() {
+        http2_close(worker, tk, tcp_stream, conn);
+        return;
+    }
+
+    let res = if conn.wants_write() {
+        worker
+            .poll
+            .reregister_rw(&mut tcp_stream, tk, Event::Http2Read, Event::Http2Write)
+    } else {
+        worker
+            .poll
+            .reregister_read(&mut tcp_stream, tk, Event::Http2Read)
+    };
+
+    if let Err(e) = res {
+        http2_drop_streams(worker, tk, &conn);
+        worker.msg_buf.resp_stream_reg_error(tk, e);
+        return;
+    }
+
+    let buffered = tcp_stream.has_buffered_data();
+    worker.http2_conns.insert(tk, (tcp_stream, conn));
+
+    // Frames rustls has decrypted but epoll can't see
+    if buffered {
+        http2_read(cfg, worker, tk);
+    }
+}
+
+fn http2_close(worker: &mut Worker, tk: Token, mut tcp_stream: Stream, conn: http2::Connection) {
+    if let Some(error) = conn.error() {
+        info!("http2 connection error", { error });
+    }
+
+    http2_drop_streams(worker, tk, &conn);
+
+    if let Err(e) = worker.poll.deregister(&mut tcp_stream) {
+        worker.msg_buf.resp_stream_reg_error(tk, e);
+        return;
+    }
+
+    worker
+        .msg_buf
+        .resp_stream_done_ok(tk, tcp_stream.into_raw_fd(), false);
+}
+
+fn http2_drop_streams(worker: &mut Worker, tk: Token, conn: &http2::Connection) {
+    for stream_tk in conn.pending_tokens() {
+        worker.python_threads.cancel_request(stream_tk);
+    }
+
+    worker.http2_streams.retain(|_, conn_tk| *conn_tk != tk);
+}
+
+fn close_reading_stream(worker: &mut Worker, tk: Token) -> RuntimeResult {
+    if let Some((mut tcp_stream, _)) = worker.server_reading_streams.remove(&tk) {
+        worker.slow_streams.unmark(tk);
+        worker
+            .poll
+            .deregister(&mut tcp_stream)
+            .map_err(|e| fatal_io_error("worker couldn't deregister stream poll", e))?;
+        worker
+            .msg_buf
+            .resp_stream_done_ok(tk, tcp_stream.into_raw_fd(), false);
+    }
+
+    Ok(())
+}
+
+// The client has stopped reading the response. Dropping it disconnects the
+// body channel, which lets go of a python thread blocked sending the body.
+fn close_writing_stream(worker: &mut Worker, tk: Token) -> RuntimeResult {
+    if let Some((mut tcp_stream, writer)) = worker.server_writing_streams.remove(&tk) {
+        info!("timed out writing response", {
+            trace_id = &writer.context().trace_id
+        });
+        worker
+            .poll
+            .deregister(&mut tcp_stream)
+            .map_err(|e| fatal_io_error("worker couldn't deregister stream poll", e))?;
+        worker
+            .msg_buf
+            .resp_stream_done_ok(tk, tcp_stream.into_raw_fd(), false);
+    }
+
+    Ok(())
+}
+
+// On shutdown close keep-alive streams the worker holds which are waiting on a new request
+fn close_idle_streams(worker: &mut Worker) {
+    let idle_tks: Vec<Token> = worker
+        .server_reading_streams
+        .iter()
+        .filter(|(_, (_, reader))| !reader.has_data())
+        .map(|(tk, _)| *tk)
+        .collect();
+
+    for tk in idle_tks {
+        if let Err(e) = close_reading_stream(worker, tk) {
+            error!("couldn't close idle stream", { error = &e.reason() });
+        }
+    }
+}
+
+fn reload_tls(cfg: &Config, worker: &mut Worker) {
+    if worker.tls.is_none() {
+        return;
+    }
+
+    match tls::load_server_config(cfg) {
+        Ok(server_config) => {
+            // Existing streams keep their session, new streams use the new certificates
+            worker.tls = Some(server_config);
+            info!("reloaded tls certificates");
+        }
+        Err(error) => {
+            error!(
+                "couldn't reload tls certificates - keeping old certificates",
+                { error = &error }
+            );
+        }
+    }
+}
+
+fn timeout_reading_stream(
+    worker: &mut Worker,
+    tk: Token,
+    results: &mut Vec<ActionResult>,
+) -> RuntimeResult {
+    if let Some((mut tcp_stream, _)) = worker.server_reading_streams.remove(&tk) {
+        worker.slow_streams.unmark(tk);
+        worker
+            .poll
+            .deregister(&mut tcp_stream)
+            .map_err(|e| fatal_io_error("worker couldn't deregister stream poll", e))?;
+        results.push(Ok(new_408_timeout(tk, tcp_stream, &worker.error_pages)));
+    }
+
+    Ok(())
+}
+
+fn handle_error(cfg: &Config, worker: &mut Worker, mut error: actions::Error) {
+    // Bad requests are answered with a 400, give them a trace id to match up logs.
+    // The client's own if the header got far enough to have one.
+    let ctx = error.context.take().unwrap_or_else(Context::new);
+
     // Logging
     match error.error {
         HttpError::Io((reason, ref err)) => {
@@ -395,10 +1075,16 @@ fn handle_error(worker: &mut Worker, mut error: actions::Error) {
             });
         }
         HttpError::HeaderParse(e) => {
-            info!("failed to parse http header", { error = &format!("{}", e) });
+            info!("failed to parse http header", {
+                error    = &format!("{}", e),
+                trace_id = &ctx.trace_id
+            });
         }
         HttpError::BadValue(error) => {
-            info!("invalid http", { error });
+            info!("invalid http", {
+                error,
+                trace_id = &ctx.trace_id
+            });
         }
     }
 
@@ -411,79 +1097,134 @@ fn handle_error(worker: &mut Worker, mut error: actions::Error) {
         ErrorSource::Server => match error.error {
             HttpError::Io((_, err)) => worker.msg_buf.resp_io_error(error.token, err),
 
-            HttpError::HeaderParse(_) => {
-                // TODO: Send Bad request
-
-                worker.msg_buf.resp_bad_client(error.token);
+            HttpError::HeaderParse(e) => {
+                let act = new_400_bad_request(
+                    error.token,
+                    error.tcp_stream,
+                    &worker.error_pages,
+                    &ctx,
+                    &format!("{}", e),
+                );
+                handle_action(cfg, worker, act);
             }
-            HttpError::BadValue(_) => {
-                // TODO: Send Bad Request
-
-                worker.msg_buf.resp_bad_client(error.token);
+            HttpError::BadValue(reason) => {
+                let act = new_400_bad_request(
+                    error.token,
+                    error.tcp_stream,
+                    &worker.error_pages,
+                    &ctx,
+                    reason,
+                );
+                handle_action(cfg, worker, act);
             }
         },
     }
 }
 
+fn server_stream_read(
+    worker: &mut Worker,
+    tk: Token,
+    tcp_stream: Stream,
+    reader: serverreader::Reader,
+) -> ActionResult {
+    let result = event_server_stream_read(tk, tcp_stream, reader);
+
+    // A slow stream stays counted until it's done reading
+    if !matches!(result, Ok(Action::ServerContinueRead(_))) {
+        worker.slow_streams.unmark(tk);
+    }
+
+    result
+}
+
+// rustls holds more of the request than the last read took, epoll won't tell us about it
+fn read_buffered(
+    cfg: &Config,
+    worker: &mut Worker,
+    tk: Token,
+    tcp_stream: Stream,
+    reader: serverreader::Reader,
+) {
+    match server_stream_read(worker, tk, tcp_stream, reader) {
+        Ok(act) => handle_action(cfg, worker, act),
+        Err(e) => handle_error(cfg, worker, *e),
+    }
+}
+
 fn event_server_stream_read(
     tk: Token,
-    mut tcp_stream: TcpStream,
+    mut tcp_stream: Stream,
     reader: serverreader::Reader,
 ) -> ActionResult {
     use serverreader::State::*;
 
     match reader.read_tcp_stream(&mut tcp_stream) {
-        Err(error) => Err(ActionError {
+        Err((error, context)) => Err(Box::new(ActionError {
             token: tk,
             error,
             source: ErrorSource::Server,
             tcp_stream,
-        }),
+            context,
+        })),
         Ok(Partial(reader)) => Ok(Action::ServerContinueRead((tk, reader, tcp_stream))),
-        Ok(Complete(http_req)) => Ok(Action::ServerReadDone((tk, http_req, tcp_stream))),
+        Ok(Complete(mut http_req)) => {
+            http_req.addrs = Some(tcp_stream.addrs());
+
+            if let Some(tls_info) = tcp_stream.tls_info() {
+                http_req.url.set_scheme("https").unwrap_or(());
+                http_req.tls = Some(tls_info);
+            }
+
+            Ok(Action::ServerReadDone((tk, http_req, tcp_stream)))
+        }
+        Ok(Http2(buf)) => Ok(Action::Http2Start((tk, buf, tcp_stream))),
         Ok(StreamEOF) => Ok(Action::ServerStreamEOF((tk, tcp_stream))),
     }
 }
 
 fn event_server_stream_write(
     tk: Token,
-    mut tcp_stream: TcpStream,
+    mut tcp_stream: Stream,
     writer: serverwriter::Writer,
 ) -> ActionResult {
     use serverwriter::State::*;
 
     match writer.write_tcp_stream(&mut tcp_stream) {
-        Err(error) => Err(ActionError {
+        Err(error) => Err(Box::new(ActionError {
             token: tk,
             error,
             source: ErrorSource::Server,
             tcp_stream,
-        }),
+            context: None,
+        })),
         Ok(Partial(writer)) => Ok(Action::ServerContinueWrite((tk, writer, tcp_stream))),
+        Ok(Waiting(writer)) => Ok(Action::ServerWaitBody((tk, writer, tcp_stream))),
         Ok(Done(http_resp)) => Ok(Action::ServerDoneWrite((tk, http_resp, tcp_stream))),
     }
 }
 
 fn event_casket_response_write(
     tk: Token,
-    mut tcp_stream: TcpStream,
+    mut tcp_stream: Stream,
     mut casket_resp: CasketResponse,
 ) -> ActionResult {
-    use std::io::Write;
+    use std::io::{self, Write};
 
     match tcp_stream.write(&casket_resp.response[casket_resp.bytes_sent..]) {
         Ok(sz) => casket_resp.bytes_sent += sz,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
         Err(e) => {
-            return Err(ActionError {
+            return Err(Box::new(ActionError {
                 token: tk,
                 source: ErrorSource::Server,
                 error: HttpError::Io(("failed to write casket response to tcp stream", e)),
                 tcp_stream,
-            })
+                context: None,
+            }))
         }
     }
 
-    if casket_resp.bytes_sent == casket_resp.response.len() {
+    if casket_resp.bytes_sent == casket_resp.response.len() && !tcp_stream.wants_write() {
         Ok(actions::Action::ServerCasketResponseDone((
             tk,
             tcp_stream,
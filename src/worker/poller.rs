@@ -1,11 +1,13 @@
 use std::cmp;
 use std::collections::{BinaryHeap, HashMap};
 use std::io;
+use std::sync::Arc;
 use std::time;
 
-use mio::{event::Source, Events, Interest, Poll, Token};
+use mio::{event::Source, Events, Interest, Poll, Token, Waker};
 
 use crate::errors::{fatal_io_error, RuntimeResult};
+use crate::tls;
 
 use super::events::Event;
 
@@ -14,6 +16,8 @@ pub struct Poller {
     mio_events: Events,
     events_reg_read: HashMap<Token, Event>,
     events_reg_write: HashMap<Token, Event>,
+    // Unlike the registrations above these fire every time they're woken
+    wakers: HashMap<Token, Event>,
     timerq: BinaryHeap<cmp::Reverse<TimerEntry>>,
 }
 
@@ -43,6 +47,7 @@ impl Poller {
             mio_events: Events::with_capacity(64),
             events_reg_read: HashMap::new(),
             events_reg_write: HashMap::new(),
+            wakers: HashMap::new(),
             timerq: BinaryHeap::new(),
         })
     }
@@ -77,14 +82,23 @@ impl Poller {
 
         if let Err(e) = self.poll.poll(&mut self.mio_events, timeout) {
             if e.kind() == io::ErrorKind::Interrupted {
-                // This works for now see GH-20
-                events.push((Token(0), Event::CtrlC));
+                if tls::take_sighup() {
+                    events.push((Token(0), Event::Sighup));
+                } else {
+                    // This works for now see GH-20
+                    events.push((Token(0), Event::CtrlC));
+                }
             } else {
                 return Err(fatal_io_error("worker failed to poll", e));
             }
         }
 
         for mio_ev in &self.mio_events {
+            if let Some(ev) = self.wakers.get(&mio_ev.token()) {
+                events.push((mio_ev.token(), *ev));
+                continue;
+            }
+
             if mio_ev.is_readable() {
                 let ev = self
                     .events_reg_read
@@ -111,6 +125,13 @@ impl Poller {
         self.poll.registry().register(s, tk, Interest::READABLE)
     }
 
+    // For other threads to wake the poll
+    pub fn waker(&mut self, tk: Token, ev: Event) -> io::Result<Arc<Waker>> {
+        let waker = Waker::new(self.poll.registry(), tk)?;
+        self.wakers.insert(tk, ev);
+        Ok(Arc::new(waker))
+    }
+
     pub fn deregister<S: Source>(&mut self, s: &mut S) -> io::Result<()> {
         self.poll.registry().deregister(s)
     }
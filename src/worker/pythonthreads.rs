@@ -1,13 +1,14 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::time;
 
-use mio::Token;
+use mio::{Token, Waker};
 
 use crate::config::Config;
+use crate::errorpages::ErrorPages;
 use crate::errors::{RuntimeError, RuntimeResult};
-use crate::http::{HttpRequest, HttpResponse};
+use crate::http::{Context, HttpRequest, HttpResponse};
 use crate::pythonexec;
 use crate::workq;
 
@@ -19,13 +20,21 @@ pub struct PythonThreads {
     req_send: workq::Sender<(usize, HttpRequest)>,
     resp_recv: mpsc::Receiver<(usize, HttpResponse)>,
     python_code_start_recv: mpsc::Receiver<(usize, time::SystemTime)>,
-    timed_out_reqs: HashSet<Token>,
+    dropped_reqs: HashSet<Token>,
+    // Trace context of each request python has yet to answer
+    contexts: HashMap<Token, Context>,
 }
 
 impl PythonThreads {
-    pub fn new(cfg: Arc<Config>, application: pythonexec::Application) -> Self {
+    pub fn new(
+        cfg: Arc<Config>,
+        application: pythonexec::Application,
+        waker: Arc<Waker>,
+        error_pages: Arc<ErrorPages>,
+    ) -> Self {
         let server = (cfg.hostname.clone(), cfg.port());
-        let (req_send, code_start_recv, resp_recv) = pythonexec::spawn(cfg, application, server);
+        let (req_send, code_start_recv, resp_recv) =
+            pythonexec::spawn(cfg, application, server, waker, error_pages);
 
         Self {
             queued_reqs: vec![],
@@ -33,11 +42,13 @@ impl PythonThreads {
             req_send,
             resp_recv,
             python_code_start_recv: code_start_recv,
-            timed_out_reqs: HashSet::new(),
+            dropped_reqs: HashSet::new(),
+            contexts: HashMap::new(),
         }
     }
 
     pub fn queue_http_req(&mut self, tk: Token, http_req: Box<HttpRequest>) {
+        self.contexts.insert(tk, http_req.context.clone());
         self.queued_reqs.push((tk, *http_req));
     }
 
@@ -61,8 +72,16 @@ impl PythonThreads {
         Ok(())
     }
 
-    pub fn timeout_request(&mut self, tk: Token) {
-        self.timed_out_reqs.insert(tk);
+    // The context lets the 504 carry the request's trace id
+    pub fn timeout_request(&mut self, tk: Token) -> Option<Context> {
+        self.dropped_reqs.insert(tk);
+        self.contexts.remove(&tk)
+    }
+
+    // The client has gone (e.g a reset http2 stream), drop the response when it arrives
+    pub fn cancel_request(&mut self, tk: Token) {
+        self.dropped_reqs.insert(tk);
+        self.contexts.remove(&tk);
     }
 
     pub fn take_responses(&mut self, results: &mut Vec<ActionResult>) -> RuntimeResult {
@@ -83,7 +102,9 @@ impl PythonThreads {
         loop {
             match self.resp_recv.try_recv() {
                 Ok((tk, resp)) => {
-                    if !self.timed_out_reqs.remove(&Token(tk)) {
+                    self.contexts.remove(&Token(tk));
+
+                    if !self.dropped_reqs.remove(&Token(tk)) {
                         results.push(Ok(Action::ServerNewResponse((Token(tk), Box::new(resp)))));
                     }
 
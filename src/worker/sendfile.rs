@@ -0,0 +1,96 @@
+// X-Casket-Sendfile - the app names a file and the worker sends it in place
+// of the app's body, with the same ranges and validators as CASKET_STATIC
+use std::path::{Path, PathBuf};
+
+use ndjsonlogger::warn;
+
+use crate::config::Config;
+use crate::errorpages::ErrorPages;
+use crate::http::{HttpResponse, HttpResponseHeader};
+
+use super::conditional::header;
+use super::staticfiles::{body, content_type, file_response, open, within};
+
+const SENDFILE_HEADER: &str = "X-Casket-Sendfile";
+
+// Casket works these out from the file itself
+const FILE_HEADERS: [&str; 5] = [
+    "Content-Length",
+    "Content-Range",
+    "Accept-Ranges",
+    "ETag",
+    "Last-Modified",
+];
+
+// Only a 200 is replaced, the header is never sent to the client
+pub fn offload(cfg: &Config, error_pages: &ErrorPages, http_resp: &mut HttpResponse) {
+    let path = match header(&http_resp.resp_headers, SENDFILE_HEADER) {
+        Some(path) => path.trim().to_string(),
+        None => return,
+    };
+
+    http_resp
+        .resp_headers
+        .retain(|(name, _)| !name.eq_ignore_ascii_case(SENDFILE_HEADER));
+
+    if http_resp.code != 200 {
+        return;
+    }
+
+    // Python stops producing the body once the channel is dropped
+    http_resp.resp_body = None;
+
+    let opened = allowed(cfg, Path::new(&path)).and_then(|p| {
+        let (file, metadata) = open(&p)?;
+        Some((p, file, metadata))
+    });
+
+    let (mut resp_header, file) = match opened {
+        Some((p, file, metadata)) => {
+            let mut headers: Vec<(String, String)> = http_resp
+                .resp_headers
+                .drain(..)
+                .filter(|(name, _)| !FILE_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)))
+                .collect();
+
+            if header(&headers, "Content-Type").is_none() {
+                headers.push(("Content-Type".to_string(), content_type(&p).to_string()));
+            }
+
+            file_response(
+                &http_resp.method,
+                &http_resp.req_headers,
+                headers,
+                (file, metadata),
+                "",
+            )
+        }
+        None => {
+            warn!("X-Casket-Sendfile file not found", {
+                path     = &path,
+                trace_id = &http_resp.context.trace_id
+            });
+
+            let resp_header = HttpResponseHeader {
+                code: 404,
+                reason: "Not Found".to_string(),
+                headers: vec![],
+            };
+            (resp_header, None)
+        }
+    };
+
+    let body = body(error_pages, &mut resp_header, &http_resp.context.trace_id);
+    http_resp.set_header(resp_header);
+    http_resp.resp_body = Some(body);
+    http_resp.resp_file = if http_resp.no_body { None } else { file };
+}
+
+// Relative paths are under CASKET_SENDFILE_ROOT, which absolute paths must stay within
+fn allowed(cfg: &Config, path: &Path) -> Option<PathBuf> {
+    match cfg.sendfile_root.as_ref() {
+        Some(root) => within(root, &root.join(path)),
+        None if path.is_absolute() => path.canonicalize().ok(),
+        None => None,
+    }
+}
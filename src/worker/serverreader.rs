@@ -1,12 +1,29 @@
-use std::io::Read;
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time;
 
-use mio::net::TcpStream;
+use crate::config::Config;
+use crate::http::{Context, HttpError, HttpRequest, RequestBody, Version};
 
-use crate::http::{Context, HttpError, HttpRequest};
+use super::http2::PREFACE as HTTP2_PREFACE;
+use super::stream::Stream;
+
+// Host used to build the request url when a HTTP/1.0 client omits the Host header
+const DEFAULT_HOST: &str = "localhost";
+
+// Size of the stack buffer used when reading a body off the tcp stream
+const BODY_READ_SIZE: usize = 16384;
+
+// Once the header is parsed the request's context goes with an error,
+// so the 400 and its log line carry the client's trace id
+pub type ReadError = (HttpError, Option<Context>);
 
 pub enum State {
     Partial(Reader),
     Complete(Box<HttpRequest>),
+    // The client speaks HTTP/2, everything read so far
+    Http2(Vec<u8>),
     StreamEOF,
 }
 
@@ -15,28 +32,77 @@ enum InnerState {
     HaveHeader(Box<PartialHttpReq>),
 }
 
+#[derive(Clone, Copy)]
+struct Options {
+    spool_size: usize,
+    strict: bool,
+    http2: bool,
+}
+
 pub struct Reader {
     state: InnerState,
+    opts: Options,
 }
 
 impl Reader {
-    pub fn new() -> Self {
+    pub fn new(cfg: &Config) -> Self {
         Self {
             state: InnerState::Begin((0, vec![0; 2048])),
+            opts: Options {
+                spool_size: cfg.request_body_spool_size,
+                strict: cfg.strict_http,
+                http2: cfg.http2,
+            },
         }
     }
 
-    pub fn read_tcp_stream(self, tcp_stream: &mut TcpStream) -> Result<State, HttpError> {
+    pub fn is_reading_header(&self) -> bool {
+        matches!(self.state, InnerState::Begin(_))
+    }
+
+    // An idle keep-alive stream has no bytes of its next request
+    pub fn has_data(&self) -> bool {
+        match self.state {
+            InnerState::Begin((buf_len, _)) => buf_len > 0,
+            InnerState::HaveHeader(_) => true,
+        }
+    }
+
+    // The body must arrive at min_rate bytes per second on average,
+    // we start checking once grace has passed since the header was read
+    pub fn body_too_slow(&self, min_rate: usize, grace: time::Duration) -> bool {
+        let partial_http_req = match self.state {
+            InnerState::Begin(_) => return false,
+            InnerState::HaveHeader(ref partial_http_req) => partial_http_req,
+        };
+
+        let elapsed = match partial_http_req.body_started.elapsed() {
+            Ok(elapsed) => elapsed,
+            Err(_) => return false,
+        };
+
+        if elapsed < grace {
+            return false;
+        }
+
+        (partial_http_req.bytes_read as f64) < (min_rate as f64) * elapsed.as_secs_f64()
+    }
+
+    pub fn read_tcp_stream(self, tcp_stream: &mut Stream) -> Result<State, ReadError> {
         match self.state {
-            InnerState::Begin((buf_len, buf)) => read_header(buf_len, buf, tcp_stream),
+            InnerState::Begin((buf_len, buf)) => read_header(buf_len, buf, self.opts, tcp_stream),
             InnerState::HaveHeader(mut partial_http_req) => {
-                partial_http_req.read_tcp_stream(tcp_stream)?;
+                let spool_size = self.opts.spool_size;
+                if let Err(e) = partial_http_req.read_tcp_stream(tcp_stream, spool_size) {
+                    return Err((e, Some(partial_http_req.context.clone())));
+                }
 
                 if partial_http_req.is_done() {
                     Ok(State::Complete(Box::new((*partial_http_req).into())))
                 } else {
                     Ok(State::Partial(Reader {
                         state: InnerState::HaveHeader(partial_http_req),
+                        opts: self.opts,
                     }))
                 }
             }
@@ -47,15 +113,28 @@ impl Reader {
 fn read_header(
     mut buf_len: usize,
     mut buf: Vec<u8>,
-    tcp_stream: &mut TcpStream,
-) -> Result<State, HttpError> {
+    opts: Options,
+    tcp_stream: &mut Stream,
+) -> Result<State, ReadError> {
     if buf.len() - buf_len < 1024 {
         buf.resize(buf.len() * 2, 0);
     }
 
-    let bytes_read = tcp_stream
-        .read(&mut buf[buf_len..])
-        .map_err(|e| HttpError::Io(("failed to read tcp stream for server request", e)))?;
+    let bytes_read = match read_stream(
+        tcp_stream,
+        &mut buf[buf_len..],
+        "failed to read tcp stream for server request",
+    )
+    .map_err(|e| (e, None))?
+    {
+        Some(bytes_read) => bytes_read,
+        None => {
+            return Ok(State::Partial(Reader {
+                state: InnerState::Begin((buf_len, buf)),
+                opts,
+            }))
+        }
+    };
 
     if bytes_read == 0 {
         return Ok(State::StreamEOF);
@@ -63,26 +142,55 @@ fn read_header(
 
     buf_len += bytes_read;
 
+    // HTTP/2 with prior knowledge (h2c) and h2 negotiated with ALPN both begin with the preface
+    if opts.http2 {
+        let len = cmp::min(buf_len, HTTP2_PREFACE.len());
+
+        if buf[..len] == HTTP2_PREFACE[..len] {
+            if len < HTTP2_PREFACE.len() {
+                return Ok(State::Partial(Reader {
+                    state: InnerState::Begin((buf_len, buf)),
+                    opts,
+                }));
+            }
+
+            buf.truncate(buf_len);
+            return Ok(State::Http2(buf));
+        }
+    }
+
     let mut headers = [httparse::EMPTY_HEADER; 24];
     let mut request = httparse::Request::new(&mut headers);
 
-    match request.parse(&buf[..buf_len]) {
-        Err(e) => Err(HttpError::HeaderParse(e)),
+    // NOTE: httparse always rejects obsolete line folding
+    // and header names which are not tokens
+    let parsed = httparse::ParserConfig::default()
+        .allow_multiple_spaces_in_request_line_delimiters(!opts.strict)
+        .parse_request(&mut request, &buf[..buf_len]);
+
+    match parsed {
+        Err(e) => Err((HttpError::HeaderParse(e), None)),
 
         Ok(httparse::Status::Partial) => Ok(State::Partial(Reader {
             state: InnerState::Begin((buf_len, buf)),
+            opts,
         })),
 
         Ok(httparse::Status::Complete(header_size)) => {
-            let mut partial_http_req = PartialHttpReq::new(request)?;
+            let context = request_context(request.headers).unwrap_or_else(Context::new);
+            let mut partial_http_req = PartialHttpReq::new(request, context.clone(), opts.strict)
+                .map_err(|e| (e, Some(context.clone())))?;
             buf.truncate(buf_len);
-            partial_http_req.take_body(buf, header_size)?;
+            partial_http_req
+                .take_body(buf, header_size, opts.spool_size)
+                .map_err(|e| (e, Some(context)))?;
 
             if partial_http_req.is_done() {
                 Ok(State::Complete(Box::new(partial_http_req.into())))
             } else {
                 Ok(State::Partial(Reader {
                     state: InnerState::HaveHeader(Box::new(partial_http_req)),
+                    opts,
                 }))
             }
         }
@@ -91,18 +199,24 @@ fn read_header(
 
 struct PartialHttpReq {
     method: http_types::Method,
+    version: Version,
     headers: Vec<(String, String)>,
     url: http_types::Url,
     content_type: Option<String>,
     content_length: usize,
     keep_alive: bool,
-    body: Vec<u8>,
+    body: RequestBody,
+    body_started: time::SystemTime,
     bytes_read: usize,
     context: Context,
 }
 
 impl PartialHttpReq {
-    fn new(request: httparse::Request<'_, '_>) -> Result<Self, HttpError> {
+    fn new(
+        request: httparse::Request<'_, '_>,
+        context: Context,
+        strict: bool,
+    ) -> Result<Self, HttpError> {
         let mut headers = vec![];
 
         let method = request
@@ -111,84 +225,143 @@ impl PartialHttpReq {
             .parse::<http_types::Method>()
             .map_err(|_| HttpError::BadValue("http request with unrecognised method"))?;
 
+        let version = match request.version.expect("request not parsed") {
+            0 => Version::Http10,
+            _ => Version::Http11,
+        };
+
         let mut content_type: Option<String> = None;
         let mut host: Option<&str> = None;
-        let mut keep_alive = true;
-        let mut content_length = 0;
-        let mut context: Option<Context> = None;
+        let mut connection: Option<&str> = None;
+        let mut content_length: Option<usize> = None;
 
         for h in request.headers {
             let value = std::str::from_utf8(h.value)
                 .map_err(|_| HttpError::BadValue("header value not utf8"))?;
 
+            // WSGI maps both '-' and '_' to '_' in environ keys,
+            // so X-Real_Ip could otherwise masquerade as X-Real-Ip
+            if h.name.contains('_') {
+                if strict {
+                    return Err(HttpError::BadValue("header name contains underscore"));
+                }
+
+                continue;
+            }
+
             if h.name.eq_ignore_ascii_case("Content-Type") {
                 content_type = Some(value.to_string());
             }
 
             if h.name.eq_ignore_ascii_case("Content-Length") {
-                content_length = value
-                    .parse()
-                    .map_err(|_| HttpError::BadValue("Content-Length not uint"))?;
+                let cl = parse_content_length(value, strict)?;
+
+                match content_length {
+                    None => content_length = Some(cl),
+                    Some(_) if strict => {
+                        return Err(HttpError::BadValue("duplicate Content-Length header"))
+                    }
+                    Some(prev) if prev != cl => {
+                        return Err(HttpError::BadValue("conflicting Content-Length headers"))
+                    }
+                    Some(_) => {}
+                }
             }
 
-            if h.name.eq_ignore_ascii_case("Host") {
-                host = Some(value);
+            if h.name.eq_ignore_ascii_case("Transfer-Encoding") {
+                // We don't decode chunked request bodies, reading the request
+                // any other way risks desync with a proxy in front of us
+                return Err(HttpError::BadValue("Transfer-Encoding not supported"));
             }
 
-            if h.name.eq_ignore_ascii_case("Traceparent") {
-                if let Ok(ctx) = parse_context(value) {
-                    context = Some(ctx);
+            if h.name.eq_ignore_ascii_case("Host") {
+                if host.is_some() && strict {
+                    return Err(HttpError::BadValue("duplicate Host header"));
                 }
+
+                host = host.or(Some(value));
             }
 
-            if h.name.eq_ignore_ascii_case("Connection") && value.eq_ignore_ascii_case("Close") {
-                keep_alive = false;
+            if h.name.eq_ignore_ascii_case("Connection") {
+                connection = Some(value);
             }
 
             headers.push((h.name.to_string(), value.to_string()));
         }
 
-        let host = host.ok_or(HttpError::BadValue("http request missing host header"))?;
+        let content_length = content_length.unwrap_or(0);
+
+        let host = match (host, version) {
+            (Some(host), _) => host,
+            (None, Version::Http10) => DEFAULT_HOST,
+            (None, _) => return Err(HttpError::BadValue("http request missing host header")),
+        };
+
+        // HTTP/1.1 is persistent by default, HTTP/1.0 must ask for keep-alive
+        let keep_alive = match version {
+            Version::Http10 => connection_has(connection, "keep-alive"),
+            _ => !connection_has(connection, "close"),
+        };
 
         Ok(Self {
             method,
+            version,
             headers,
             content_type,
             content_length,
             keep_alive,
-            body: vec![],
+            body: RequestBody::Memory(vec![]),
+            body_started: time::SystemTime::now(),
             bytes_read: 0,
-            context: context.unwrap_or_else(Context::new),
+            context,
             url: url(host, request.path.expect("request not parsed"))?,
         })
     }
 
-    fn take_body(&mut self, buffer: Vec<u8>, header_size: usize) -> Result<(), HttpError> {
-        if buffer[header_size..].len() > self.content_length {
+    fn take_body(
+        &mut self,
+        buffer: Vec<u8>,
+        header_size: usize,
+        spool_size: usize,
+    ) -> Result<(), HttpError> {
+        let body_part = &buffer[header_size..];
+
+        if body_part.len() > self.content_length {
             // Too many bytes in buffer
             return Err(HttpError::BadValue("content-length too large"));
         }
 
-        self.body.reserve(self.content_length);
-        self.body.extend(&buffer[header_size..]);
-        self.body.resize(self.content_length, 0);
-        self.bytes_read = buffer.len() - header_size;
-
-        Ok(())
+        self.bytes_read = body_part.len();
+        self.body = RequestBody::Memory(Vec::with_capacity(cmp::min(
+            self.content_length,
+            spool_size,
+        )));
+        let done = self.is_done();
+        append_body(&mut self.body, body_part, spool_size, done)
     }
 
-    fn read_tcp_stream(&mut self, tcp_stream: &mut TcpStream) -> Result<(), HttpError> {
-        let bytes_read = tcp_stream
-            .read(&mut self.body[self.bytes_read..])
-            .map_err(|e| HttpError::Io(("failed to ready request body on tcp stream", e)))?;
+    fn read_tcp_stream(
+        &mut self,
+        tcp_stream: &mut Stream,
+        spool_size: usize,
+    ) -> Result<(), HttpError> {
+        const REASON: &str = "failed to ready request body on tcp stream";
+
+        let mut buf = [0; BODY_READ_SIZE];
+        let remaining = cmp::min(self.content_length - self.bytes_read, buf.len());
+
+        let bytes_read = match read_stream(tcp_stream, &mut buf[..remaining], REASON)? {
+            Some(bytes_read) => bytes_read,
+            None => return Ok(()),
+        };
 
         if bytes_read == 0 {
             return Err(HttpError::BadValue("stream EOF without complete body"));
         }
 
         self.bytes_read += bytes_read;
-
-        Ok(())
+        let done = self.is_done();
+        append_body(&mut self.body, &buf[..bytes_read], spool_size, done)
     }
 
     fn is_done(&self) -> bool {
@@ -201,17 +374,121 @@ impl From<PartialHttpReq> for HttpRequest {
         HttpRequest {
             method: req.method,
             url: req.url,
+            version: req.version,
             headers: req.headers,
             context: req.context,
             keep_alive: req.keep_alive,
             content_type: req.content_type,
             content_length: req.content_length,
             body: Some(req.body),
+            tls: None,
+            addrs: None,
+        }
+    }
+}
+
+// The last valid traceparent header
+fn request_context(headers: &[httparse::Header<'_>]) -> Option<Context> {
+    headers
+        .iter()
+        .rev()
+        .filter(|h| h.name.eq_ignore_ascii_case("Traceparent"))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+        .find_map(|value| parse_context(value).ok())
+}
+
+// None if there was nothing to read - a tls stream may only have had handshake records
+fn read_stream(
+    tcp_stream: &mut Stream,
+    buf: &mut [u8],
+    reason: &'static str,
+) -> Result<Option<usize>, HttpError> {
+    match tcp_stream.read(buf) {
+        Ok(bytes_read) => Ok(Some(bytes_read)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(HttpError::Io((reason, e))),
+    }
+}
+
+fn spool(file: &mut fs::File, data: &[u8], done: bool) -> Result<(), HttpError> {
+    file.write_all(data)
+        .map_err(|e| HttpError::Io(("failed to write request body to spool file", e)))?;
+
+    if done {
+        // Rewind so the body can be read back from the start
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| HttpError::Io(("failed to rewind request body spool file", e)))?;
+    }
+
+    Ok(())
+}
+
+// Bodies stay in memory up to spool_size, larger ones move to a temp file.
+// done rewinds a spooled body so it can be read back from the start.
+pub(super) fn append_body(
+    body: &mut RequestBody,
+    data: &[u8],
+    spool_size: usize,
+    done: bool,
+) -> Result<(), HttpError> {
+    if let RequestBody::Memory(ref mut mem) = body {
+        if mem.len() + data.len() <= spool_size {
+            mem.extend(data);
+            return Ok(());
+        }
+
+        // Large body - keep it out of memory
+        let mut file = tempfile::tempfile()
+            .map_err(|e| HttpError::Io(("failed to create request body spool file", e)))?;
+        spool(&mut file, mem, false)?;
+        *body = RequestBody::Spooled(file);
+    }
+
+    if let RequestBody::Spooled(ref mut file) = body {
+        spool(file, data, done)?;
+    }
+
+    Ok(())
+}
+
+pub(super) fn parse_content_length(value: &str, strict: bool) -> Result<usize, HttpError> {
+    let mut content_length: Option<usize> = None;
+
+    // A list of identical values (e.g "5, 5") is allowed in lenient mode
+    for v in value.split(',') {
+        let v = v.trim();
+
+        if v.is_empty() || !v.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(HttpError::BadValue("Content-Length not uint"));
         }
+
+        let cl = v
+            .parse()
+            .map_err(|_| HttpError::BadValue("Content-Length not uint"))?;
+
+        match content_length {
+            None => content_length = Some(cl),
+            Some(_) if strict => return Err(HttpError::BadValue("Content-Length is a list")),
+            Some(prev) if prev != cl => {
+                return Err(HttpError::BadValue("conflicting Content-Length values"))
+            }
+            Some(_) => {}
+        }
+    }
+
+    content_length.ok_or(HttpError::BadValue("Content-Length not uint"))
+}
+
+fn connection_has(connection: Option<&str>, option: &str) -> bool {
+    match connection {
+        Some(value) => value
+            .split(',')
+            .any(|v| v.trim().eq_ignore_ascii_case(option)),
+        None => false,
     }
 }
 
-fn url(host: &str, path: &str) -> Result<http_types::Url, HttpError> {
+pub(super) fn url(host: &str, path: &str) -> Result<http_types::Url, HttpError> {
     if path.starts_with("http://") || path.starts_with("https://") {
         http_types::Url::parse(path).map_err(|_| HttpError::BadValue("invalid http path"))
     } else if path.starts_with('/') {
@@ -222,7 +499,7 @@ fn url(host: &str, path: &str) -> Result<http_types::Url, HttpError> {
     }
 }
 
-fn parse_context(val: &str) -> Result<Context, &'static str> {
+pub(super) fn parse_context(val: &str) -> Result<Context, &'static str> {
     let mut trace_id = "";
     let mut parent_id = "";
 
@@ -260,3 +537,181 @@ fn parse_context(val: &str) -> Result<Context, &'static str> {
 
     Ok(Context::from_vals(trace_id, parent_id))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{append_body, parse_content_length, PartialHttpReq};
+    use crate::http::{Context, HttpError, RequestBody};
+
+    fn parse(header: &str, strict: bool) -> Result<PartialHttpReq, HttpError> {
+        let mut headers = [httparse::EMPTY_HEADER; 24];
+        let mut request = httparse::Request::new(&mut headers);
+        assert!(request.parse(header.as_bytes()).unwrap().is_complete());
+
+        PartialHttpReq::new(request, Context::new(), strict)
+    }
+
+    fn rejected(header: &str, strict: bool) -> &'static str {
+        match parse(header, strict) {
+            Err(HttpError::BadValue(reason)) => reason,
+            _ => panic!("request wasn't rejected"),
+        }
+    }
+
+    fn content_length(value: &str, strict: bool) -> Option<usize> {
+        parse_content_length(value, strict).ok()
+    }
+
+    fn contents(body: RequestBody) -> Vec<u8> {
+        match body {
+            RequestBody::Memory(mem) => mem,
+            RequestBody::Spooled(mut file) => {
+                let mut data = vec![];
+                file.read_to_end(&mut data).unwrap();
+                data
+            }
+        }
+    }
+
+    #[test]
+    fn body_in_memory() {
+        let mut body = RequestBody::Memory(vec![]);
+        assert!(append_body(&mut body, b"hello ", 11, false).is_ok());
+        assert!(append_body(&mut body, b"world", 11, true).is_ok());
+
+        assert!(matches!(body, RequestBody::Memory(_)));
+        assert_eq!(contents(body), b"hello world");
+    }
+
+    #[test]
+    fn body_spooled() {
+        let mut body = RequestBody::Memory(vec![]);
+        assert!(append_body(&mut body, b"hello ", 10, false).is_ok());
+        assert!(append_body(&mut body, b"world", 10, false).is_ok());
+        assert!(matches!(body, RequestBody::Spooled(_)));
+
+        // Rewound once done
+        assert!(append_body(&mut body, b"!", 10, true).is_ok());
+        assert_eq!(contents(body), b"hello world!");
+    }
+
+    #[test]
+    fn content_length_values() {
+        for strict in [false, true] {
+            assert_eq!(content_length("0", strict), Some(0));
+            assert_eq!(content_length("42", strict), Some(42));
+            assert_eq!(content_length(" 42 ", strict), Some(42));
+
+            for value in [
+                "",
+                " ",
+                "-1",
+                "+1",
+                "0x10",
+                "1e3",
+                "4 2",
+                "42;",
+                "99999999999999999999999",
+            ] {
+                assert_eq!(content_length(value, strict), None, "{}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn content_length_lists() {
+        assert_eq!(content_length("5, 5", false), Some(5));
+        assert!(matches!(
+            parse_content_length("5, 5", true),
+            Err(HttpError::BadValue("Content-Length is a list"))
+        ));
+        assert!(matches!(
+            parse_content_length("5, 6", false),
+            Err(HttpError::BadValue("conflicting Content-Length values"))
+        ));
+        assert_eq!(content_length("5,", false), None);
+        assert_eq!(content_length(",5", false), None);
+    }
+
+    #[test]
+    fn duplicate_content_length() {
+        let header = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\n";
+        assert_eq!(parse(header, false).ok().unwrap().content_length, 5);
+        assert_eq!(rejected(header, true), "duplicate Content-Length header");
+
+        let header =
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 50\r\n\r\n";
+        assert_eq!(
+            rejected(header, false),
+            "conflicting Content-Length headers"
+        );
+        assert_eq!(rejected(header, true), "duplicate Content-Length header");
+    }
+
+    #[test]
+    fn transfer_encoding() {
+        for value in ["chunked", "identity", "gzip, chunked", ""] {
+            let header = format!(
+                "POST / HTTP/1.1\r\nHost: a\r\ntransfer-encoding: {}\r\n\r\n",
+                value
+            );
+
+            for strict in [false, true] {
+                assert_eq!(rejected(&header, strict), "Transfer-Encoding not supported");
+            }
+        }
+
+        // Alongside Content-Length, in either order
+        for header in [
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n",
+        ] {
+            assert_eq!(rejected(header, false), "Transfer-Encoding not supported");
+        }
+    }
+
+    #[test]
+    fn underscore_header() {
+        // Dropped, so it can't stand in for X-Real-Ip in the environ
+        let header =
+            "GET / HTTP/1.1\r\nHost: a\r\nX-Real_Ip: 1.2.3.4\r\nX-Real-Ip: 5.6.7.8\r\n\r\n";
+        let partial_http_req = parse(header, false).ok().unwrap();
+        assert_eq!(
+            partial_http_req.headers,
+            vec![
+                ("Host".to_string(), "a".to_string()),
+                ("X-Real-Ip".to_string(), "5.6.7.8".to_string()),
+            ]
+        );
+
+        assert_eq!(rejected(header, true), "header name contains underscore");
+
+        // Even when it would otherwise frame the body
+        let header = "POST / HTTP/1.1\r\nHost: a\r\nContent_Length: 5\r\n\r\n";
+        assert_eq!(parse(header, false).ok().unwrap().content_length, 0);
+        assert_eq!(rejected(header, true), "header name contains underscore");
+    }
+
+    #[test]
+    fn duplicate_host() {
+        let header = "GET /x HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n";
+        assert_eq!(
+            parse(header, false).ok().unwrap().url.as_str(),
+            "http://a/x"
+        );
+        assert_eq!(rejected(header, true), "duplicate Host header");
+    }
+
+    #[test]
+    fn missing_host() {
+        assert_eq!(
+            rejected("GET / HTTP/1.1\r\n\r\n", false),
+            "http request missing host header"
+        );
+
+        let partial_http_req = parse("GET /x HTTP/1.0\r\n\r\n", true).ok().unwrap();
+        assert_eq!(partial_http_req.url.as_str(), "http://localhost/x");
+    }
+}
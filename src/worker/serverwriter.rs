@@ -1,54 +1,95 @@
-use std::io::Write;
+use std::io::{self, Write};
 use std::sync::mpsc::TryRecvError;
+use std::time;
 
-use mio::net::TcpStream;
+use ndjsonlogger::error;
 
-use crate::http::{HttpError, HttpResponse};
+use crate::config::Config;
+use crate::http::{Context, HttpError, HttpResponse, SendFile};
+
+use super::compress::Encoder;
+use super::log_content_length_mismatch;
+use super::stream::Stream;
+
+const FILE_READ_SIZE: usize = 65536;
 
 pub enum State {
     Partial(Writer),
+    // Everything so far is written, the app hasn't sent more of the body
+    Waiting(Writer),
     Done(Box<HttpResponse>),
 }
 
 pub struct Writer {
     http_resp: Box<HttpResponse>,
     buffer: Vec<u8>,
-    header_size: usize,
-    bytes_written: usize,
+    body_size: usize,
+    encoder: Option<Encoder>,
+    // Since when the client has left part of the response unread
+    write_stalled: Option<time::SystemTime>,
 }
 
 impl Writer {
-    pub fn new(http_resp: Box<HttpResponse>, mut buffer: Vec<u8>) -> Self {
+    pub fn new(cfg: &Config, mut http_resp: Box<HttpResponse>, mut buffer: Vec<u8>) -> Self {
+        let encoder = Encoder::negotiate(cfg, &mut http_resp);
+
         buffer.clear();
         http_resp.write_header(&mut buffer);
 
         Self {
             http_resp,
-            header_size: buffer.len(),
             buffer,
-            bytes_written: 0,
+            body_size: 0,
+            encoder,
+            write_stalled: None,
         }
     }
 
-    pub fn write_tcp_stream(mut self, tcp_stream: &mut TcpStream) -> Result<State, HttpError> {
-        if let Some(body) = self.http_resp.resp_body.take() {
+    pub fn context(&self) -> &Context {
+        &self.http_resp.context
+    }
+
+    // None while the client is keeping up, or the response is waiting on the app
+    pub fn write_stalled_since(&self) -> Option<time::SystemTime> {
+        self.write_stalled
+    }
+
+    pub fn write_tcp_stream(mut self, tcp_stream: &mut Stream) -> Result<State, HttpError> {
+        let mut body_empty = false;
+        let mut file_sent = 0;
+
+        if let Some(file) = self.http_resp.resp_file.take() {
+            file_sent = self.write_file(file, tcp_stream)?;
+        } else if let Some(body) = self.http_resp.resp_body.take() {
             match body.try_recv() {
                 Ok(body_part) => {
-                    self.buffer.extend(&body_part);
-                    self.http_resp.resp_body = Some(body);
+                    // Stop reading once the app goes over its Content-Length
+                    match self.write_body_part(&body_part) {
+                        Ok(true) => self.http_resp.resp_body = Some(body),
+                        Ok(false) => self.finish_body(),
+                        Err(e) => self.compress_failed(e),
+                    }
                 }
                 Err(TryRecvError::Empty) => {
                     self.http_resp.resp_body = Some(body);
+                    body_empty = true;
+                }
+                Err(TryRecvError::Disconnected) if self.http_resp.is_aborted() => {
+                    self.abort_body();
                 }
                 Err(TryRecvError::Disconnected) => {
                     // Sender has dropped - no more data
+                    self.finish_body();
                 }
             }
         }
 
-        let bytes_written = tcp_stream
-            .write(&self.buffer)
-            .map_err(|e| HttpError::Io(("failed to write response to tcp stream", e)))?;
+        let bytes_written = match tcp_stream.write(&self.buffer) {
+            Ok(bytes_written) => bytes_written,
+            // TLS records from an earlier write are still backed up
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(HttpError::Io(("failed to write response to tcp stream", e))),
+        };
 
         let bytes_remaining = self.buffer.len() - bytes_written;
         for n in 0..bytes_remaining {
@@ -56,13 +97,331 @@ impl Writer {
         }
 
         self.buffer.truncate(bytes_remaining);
-        self.bytes_written += bytes_written;
 
-        if self.buffer.is_empty() && self.http_resp.resp_body.is_none() {
-            self.http_resp.resp_content_length = Some(self.bytes_written - self.header_size);
+        if self.buffer.is_empty()
+            && self.http_resp.resp_file.is_none()
+            && self.http_resp.resp_body.is_none()
+            && !tcp_stream.wants_write()
+        {
+            self.http_resp.resp_content_length = Some(self.body_size);
             Ok(State::Done(self.http_resp))
+        } else if self.buffer.is_empty() && body_empty && !tcp_stream.wants_write() {
+            self.write_stalled = None;
+            Ok(State::Waiting(self))
         } else {
+            // The clock restarts whenever the client takes some of the response
+            if bytes_written > 0 || file_sent > 0 || self.write_stalled.is_none() {
+                self.write_stalled = Some(time::SystemTime::now());
+            }
             Ok(State::Partial(self))
         }
     }
+
+    // The file goes before anything on the body channel. Once it's
+    // exhausted resp_file is left empty. Returns the bytes sendfile wrote.
+    fn write_file(&mut self, mut file: SendFile, tcp_stream: &Stream) -> Result<usize, HttpError> {
+        if file.remaining() == 0 {
+            return Ok(0);
+        }
+
+        let mut file_sent = 0;
+
+        match tcp_stream.sendfile_fd() {
+            // Compressed bodies have to pass through the encoder
+            Some(fd) if self.encoder.is_none() && !self.http_resp.chunked => {
+                // The header goes out first
+                if self.buffer.is_empty() {
+                    match file.send_to(fd) {
+                        Ok(bytes_sent) => {
+                            self.body_size += bytes_sent;
+                            file_sent = bytes_sent;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => {
+                            return Err(HttpError::Io((
+                                "failed to sendfile response to tcp stream",
+                                e,
+                            )))
+                        }
+                    }
+                }
+            }
+            // Don't read ahead of a slow client
+            _ if self.buffer.len() >= FILE_READ_SIZE => {}
+            _ => {
+                let body_part = file
+                    .read_chunk(FILE_READ_SIZE)
+                    .map_err(|e| HttpError::Io(("failed to read response file", e)))?;
+
+                if let Err(e) = self.write_body_part(&body_part) {
+                    self.compress_failed(e);
+                    return Ok(file_sent);
+                }
+            }
+        }
+
+        self.http_resp.resp_file = Some(file);
+        Ok(file_sent)
+    }
+
+    // Returns false if the body part went over the Content-Length
+    fn write_body_part(&mut self, mut body_part: &[u8]) -> io::Result<bool> {
+        if self.http_resp.no_body {
+            return Ok(true);
+        }
+
+        let mut within_length = true;
+
+        if let Some(cl) = self.http_resp.resp_content_length {
+            let remaining = cl - self.body_size;
+
+            if body_part.len() > remaining {
+                log_content_length_mismatch(&self.http_resp, self.body_size + body_part.len());
+                body_part = &body_part[..remaining];
+                within_length = false;
+            }
+        }
+
+        self.body_size += body_part.len();
+
+        // The cache keeps the body as the app sent it, before compression
+        if let Some(capture) = self.http_resp.capture.as_mut() {
+            if !capture.push(body_part) {
+                self.http_resp.capture = None;
+            }
+        }
+
+        match self.encoder.as_mut() {
+            Some(encoder) => {
+                let compressed = encoder.compress(body_part)?;
+                self.write_chunk(&compressed);
+            }
+            None => self.write_chunk(body_part),
+        }
+
+        Ok(within_length)
+    }
+
+    fn finish_body(&mut self) {
+        if let Some(encoder) = self.encoder.take() {
+            match encoder.finish() {
+                Ok(compressed) => self.write_chunk(&compressed),
+                Err(e) => {
+                    self.compress_failed(e);
+                    return;
+                }
+            }
+        }
+
+        if self.http_resp.chunked {
+            self.buffer.extend(b"0\r\n\r\n");
+        }
+
+        // The client is still waiting for the rest of the body
+        match self.http_resp.resp_content_length {
+            Some(cl) if self.body_size < cl && !self.http_resp.no_body => {
+                log_content_length_mismatch(&self.http_resp, self.body_size);
+                self.http_resp.keep_alive = false;
+            }
+            _ => {}
+        }
+    }
+
+    // The app failed part way. No chunked terminator and the stream is
+    // closed, so the client can tell the body is incomplete.
+    fn abort_body(&mut self) {
+        self.encoder = None;
+        self.http_resp.capture = None;
+        self.http_resp.keep_alive = false;
+    }
+
+    // Only this response is cut short, the rest of its body is dropped
+    fn compress_failed(&mut self, e: io::Error) {
+        error!("couldn't compress response", {
+            error    = &format!("{}", e),
+            trace_id = &self.http_resp.context.trace_id
+        });
+
+        self.http_resp.resp_body = None;
+        self.http_resp.resp_file = None;
+        self.abort_body();
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) {
+        if !self.http_resp.chunked {
+            self.buffer.extend(data);
+            return;
+        }
+
+        // An empty chunk would end the body
+        if data.is_empty() {
+            return;
+        }
+
+        self.buffer
+            .extend(format!("{:x}\r\n", data.len()).as_bytes());
+        self.buffer.extend(data);
+        self.buffer.extend(b"\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::sync_channel;
+
+    use super::Writer;
+    use crate::config::Config;
+    use crate::http::{Context, HttpRequest, HttpResponseHeader, Version};
+
+    // A writer with its header already taken off the buffer
+    fn writer(method: http_types::Method, version: Version, headers: &[(&str, &str)]) -> Writer {
+        let http_req = HttpRequest {
+            method,
+            url: http_types::Url::parse("http://localhost/").unwrap(),
+            version,
+            headers: vec![],
+            context: Context::new(),
+            keep_alive: true,
+            content_type: None,
+            content_length: 0,
+            body: None,
+            tls: None,
+            addrs: None,
+        };
+
+        let http_resp = http_req.into_http_response(
+            HttpResponseHeader {
+                code: 200,
+                reason: "OK".to_string(),
+                headers: headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            },
+            sync_channel(1).1,
+        );
+
+        let mut writer = Writer::new(&Config::default(), Box::new(http_resp), vec![]);
+        writer.buffer.clear();
+        writer
+    }
+
+    fn get(version: Version, headers: &[(&str, &str)]) -> Writer {
+        writer(http_types::Method::Get, version, headers)
+    }
+
+    #[test]
+    fn chunked() {
+        let mut writer = get(Version::Http11, &[]);
+        assert!(writer.http_resp.chunked);
+
+        assert!(writer.write_body_part(b"hello").unwrap());
+        assert!(writer
+            .write_body_part(b"abcdefghijklmnopqrstuvwxyz")
+            .unwrap());
+        // An empty chunk would end the body early
+        assert!(writer.write_body_part(b"").unwrap());
+        writer.finish_body();
+
+        assert_eq!(
+            writer.buffer,
+            b"5\r\nhello\r\n1a\r\nabcdefghijklmnopqrstuvwxyz\r\n0\r\n\r\n"
+        );
+        assert!(writer.http_resp.keep_alive);
+    }
+
+    #[test]
+    fn chunked_aborted() {
+        let mut writer = get(Version::Http11, &[]);
+
+        assert!(writer.write_body_part(b"hello").unwrap());
+        writer.abort_body();
+
+        // No terminator, the client sees the stream close mid body
+        assert_eq!(writer.buffer, b"5\r\nhello\r\n");
+        assert!(!writer.http_resp.keep_alive);
+    }
+
+    #[test]
+    fn not_chunked() {
+        // The stream closing ends a HTTP/1.0 body of unknown length
+        let mut writer = get(Version::Http10, &[]);
+        assert!(!writer.http_resp.chunked);
+        assert!(!writer.http_resp.keep_alive);
+
+        assert!(writer.write_body_part(b"hello").unwrap());
+        writer.finish_body();
+        assert_eq!(writer.buffer, b"hello");
+
+        // A known length needs no framing
+        let mut writer = get(Version::Http11, &[("Content-Length", "5")]);
+        assert!(!writer.http_resp.chunked);
+
+        assert!(writer.write_body_part(b"hello").unwrap());
+        writer.finish_body();
+        assert_eq!(writer.buffer, b"hello");
+    }
+
+    #[test]
+    fn no_body() {
+        let mut writer = writer(http_types::Method::Head, Version::Http11, &[]);
+        assert!(!writer.http_resp.chunked);
+
+        assert!(writer.write_body_part(b"hello").unwrap());
+        writer.finish_body();
+        assert!(writer.buffer.is_empty());
+        assert!(writer.http_resp.keep_alive);
+    }
+
+    #[test]
+    fn truncated_at_content_length() {
+        let mut writer = get(Version::Http11, &[("Content-Length", "8")]);
+
+        assert!(writer.write_body_part(b"hello").unwrap());
+        // Over the declared length, the rest is dropped and reading stops
+        assert!(!writer.write_body_part(b" world").unwrap());
+        writer.finish_body();
+
+        assert_eq!(writer.buffer, b"hello wo");
+        assert_eq!(writer.body_size, 8);
+        assert!(writer.http_resp.keep_alive);
+    }
+
+    #[test]
+    fn exact_content_length() {
+        let mut writer = get(Version::Http11, &[("Content-Length", "11")]);
+
+        assert!(writer.write_body_part(b"hello").unwrap());
+        assert!(writer.write_body_part(b" world").unwrap());
+        writer.finish_body();
+
+        assert_eq!(writer.buffer, b"hello world");
+        assert!(writer.http_resp.keep_alive);
+    }
+
+    #[test]
+    fn short_body() {
+        let mut writer = get(Version::Http11, &[("Content-Length", "11")]);
+
+        assert!(writer.write_body_part(b"hello").unwrap());
+        writer.finish_body();
+
+        // The client is still waiting on 6 bytes, only closing ends it
+        assert_eq!(writer.buffer, b"hello");
+        assert!(!writer.http_resp.keep_alive);
+    }
+
+    #[test]
+    fn short_body_without_body() {
+        // HEAD's Content-Length describes a body which is never sent
+        let mut writer = writer(
+            http_types::Method::Head,
+            Version::Http11,
+            &[("Content-Length", "11")],
+        );
+
+        writer.finish_body();
+        assert!(writer.buffer.is_empty());
+        assert!(writer.http_resp.keep_alive);
+    }
 }
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use mio::Token;
+
+// Streams whose request body is arriving slower than CASKET_BODY_MIN_RATE
+// We cap how many of these a single client may hold open at once
+pub struct SlowStreams {
+    max_per_ip: usize,
+    streams: HashMap<Token, IpAddr>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl SlowStreams {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip,
+            streams: HashMap::new(),
+            per_ip: HashMap::new(),
+        }
+    }
+
+    // Returns false if the client already has too many slow streams
+    pub fn mark(&mut self, tk: Token, ip: IpAddr) -> bool {
+        if self.max_per_ip == 0 || self.streams.contains_key(&tk) {
+            return true;
+        }
+
+        let count = self.per_ip.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return false;
+        }
+
+        *count += 1;
+        self.streams.insert(tk, ip);
+
+        true
+    }
+
+    pub fn unmark(&mut self, tk: Token) {
+        let ip = match self.streams.remove(&tk) {
+            Some(ip) => ip,
+            None => return,
+        };
+
+        if let Some(count) = self.per_ip.get_mut(&ip) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.per_ip.remove(&ip);
+            }
+        }
+    }
+}
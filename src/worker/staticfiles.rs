@@ -0,0 +1,501 @@
+// Files under a CASKET_STATIC mount are answered here, python never sees the request
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time;
+
+use crate::config::Config;
+use crate::errorpages::ErrorPages;
+use crate::http::{date, HttpRequest, HttpResponse, HttpResponseHeader, SendFile};
+
+use super::compress::qvalue;
+use super::conditional::{header, if_range_matches, not_modified};
+
+// Served in place of the file when the client accepts the coding
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+enum Range {
+    Full,
+    // First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+pub fn is_static(cfg: &Config, http_req: &HttpRequest) -> bool {
+    mount(cfg, http_req.url.path()).is_some()
+}
+
+pub fn serve(cfg: &Config, error_pages: &ErrorPages, http_req: HttpRequest) -> HttpResponse {
+    let (mut header, file) = respond(cfg, &http_req);
+    let body = body(error_pages, &mut header, &http_req.context.trace_id);
+
+    let mut http_resp = http_req.into_http_response(header, body);
+    if !http_resp.no_body {
+        http_resp.resp_file = file;
+    }
+
+    http_resp
+}
+
+// The body channel, with an error page for codes from 400. The file goes in resp_file.
+pub(super) fn body(
+    error_pages: &ErrorPages,
+    header: &mut HttpResponseHeader,
+    trace_id: &str,
+) -> Receiver<Vec<u8>> {
+    let (body_send, body) = mpsc::sync_channel(1);
+
+    if header.code >= 400 {
+        let page = error_pages.render(header.code, &header.reason, trace_id, None);
+
+        let body = match page {
+            Some(page) => {
+                header
+                    .headers
+                    .push(("Content-Type".to_string(), page.content_type.to_string()));
+                page.body
+            }
+            None => vec![],
+        };
+
+        header
+            .headers
+            .push(("Content-Length".to_string(), body.len().to_string()));
+        body_send.send(body).unwrap_or(());
+    }
+
+    // The body is complete, the writer sees the channel disconnect
+    drop(body_send);
+    body
+}
+
+fn respond(cfg: &Config, http_req: &HttpRequest) -> (HttpResponseHeader, Option<SendFile>) {
+    let (root, rest) = match mount(cfg, http_req.url.path()) {
+        Some(mount) => mount,
+        None => return (status(404, "Not Found"), None),
+    };
+
+    if !matches!(
+        http_req.method,
+        http_types::Method::Get | http_types::Method::Head
+    ) {
+        let mut header = status(405, "Method Not Allowed");
+        header
+            .headers
+            .push(("Allow".to_string(), "GET, HEAD".to_string()));
+        return (header, None);
+    }
+
+    let path = match file_path(root, rest) {
+        Some(path) => path,
+        None => return (status(404, "Not Found"), None),
+    };
+
+    let (file, metadata) = match open(&path) {
+        Some(opened) => opened,
+        None => return (status(404, "Not Found"), None),
+    };
+
+    let mut headers = vec![("Content-Type".to_string(), content_type(&path).to_string())];
+
+    // A .br or .gz next to the file, if the client takes it
+    let accept_encoding = header(&http_req.headers, "Accept-Encoding").unwrap_or("");
+    let mut variant = None;
+    let mut has_variants = false;
+
+    for (coding, ext) in PRECOMPRESSED.iter() {
+        let mut variant_path = path.clone().into_os_string();
+        variant_path.push(".");
+        variant_path.push(ext);
+
+        let opened = within(root, Path::new(&variant_path)).and_then(|p| open(&p));
+        if opened.is_none() {
+            continue;
+        }
+
+        has_variants = true;
+        if variant.is_none() && qvalue(accept_encoding, coding) > 0.0 {
+            variant = opened.map(|(file, metadata)| (*coding, file, metadata));
+        }
+    }
+
+    if has_variants {
+        headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    }
+
+    let (file, metadata, etag_suffix) = match variant {
+        Some((coding, file, metadata)) => {
+            headers.push(("Content-Encoding".to_string(), coding.to_string()));
+            (file, metadata, format!("-{}", coding))
+        }
+        None => (file, metadata, String::new()),
+    };
+
+    file_response(
+        &http_req.method,
+        &http_req.headers,
+        headers,
+        (file, metadata),
+        &etag_suffix,
+    )
+}
+
+// Validators, then a 304, a range or the whole file
+pub(super) fn file_response(
+    method: &http_types::Method,
+    req_headers: &[(String, String)],
+    mut headers: Vec<(String, String)>,
+    (file, metadata): (fs::File, fs::Metadata),
+    etag_suffix: &str,
+) -> (HttpResponseHeader, Option<SendFile>) {
+    headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+
+    let size = metadata.len();
+    let modified = metadata.modified().unwrap_or(time::UNIX_EPOCH);
+    let mtime = modified
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let etag = format!("\"{:x}-{:x}{}\"", size, mtime, etag_suffix);
+    let last_modified = date::format(modified);
+
+    headers.push(("ETag".to_string(), etag.clone()));
+    headers.push(("Last-Modified".to_string(), last_modified.clone()));
+
+    if not_modified(method, req_headers, Some(&etag), Some(&last_modified)) {
+        let mut header = status(304, "Not Modified");
+        header.headers = headers;
+        return (header, None);
+    }
+
+    let range = if if_range_matches(req_headers, Some(&etag), Some(&last_modified)) {
+        range(req_headers, size)
+    } else {
+        Range::Full
+    };
+
+    match range {
+        Range::Full => {
+            headers.push(("Content-Length".to_string(), size.to_string()));
+
+            let header = HttpResponseHeader {
+                code: 200,
+                reason: "OK".to_string(),
+                headers,
+            };
+            (header, Some(SendFile::new(file, 0, size)))
+        }
+        Range::Partial(first, last) => {
+            let length = last - first + 1;
+            headers.push((
+                "Content-Range".to_string(),
+                format!("bytes {}-{}/{}", first, last, size),
+            ));
+            headers.push(("Content-Length".to_string(), length.to_string()));
+
+            let header = HttpResponseHeader {
+                code: 206,
+                reason: "Partial Content".to_string(),
+                headers,
+            };
+            (header, Some(SendFile::new(file, first, length)))
+        }
+        Range::Unsatisfiable => {
+            let mut header = status(416, "Range Not Satisfiable");
+            header
+                .headers
+                .push(("Content-Range".to_string(), format!("bytes */{}", size)));
+            (header, None)
+        }
+    }
+}
+
+// The mount root and the rest of the path, "/static/css/a.css" gives "/css/a.css"
+fn mount<'a, 'b>(cfg: &'a Config, path: &'b str) -> Option<(&'a Path, &'b str)> {
+    cfg.static_mounts.iter().find_map(|(prefix, root)| {
+        let rest = path.strip_prefix(prefix.as_str())?;
+
+        // "/static" mustn't match "/staticfoo"
+        if rest.is_empty() || rest.starts_with('/') {
+            Some((root.as_path(), rest))
+        } else {
+            None
+        }
+    })
+}
+
+// None for anything which could step outside the root, or a hidden file
+fn file_path(root: &Path, rest: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+
+    for segment in rest.split('/').filter(|s| !s.is_empty()) {
+        let segment = percent_decode(segment)?;
+
+        if segment.starts_with(b".") || segment.contains(&b'/') || segment.contains(&0) {
+            return None;
+        }
+
+        path.push(OsStr::from_bytes(&segment));
+    }
+
+    within(root, &path)
+}
+
+// Symlinks mustn't lead outside the root either
+pub(super) fn within(root: &Path, path: &Path) -> Option<PathBuf> {
+    let path = path.canonicalize().ok()?;
+    let root = root.canonicalize().ok()?;
+
+    if path.starts_with(&root) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+pub(super) fn open(path: &Path) -> Option<(fs::File, fs::Metadata)> {
+    let file = fs::File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+
+    if metadata.is_file() {
+        Some((file, metadata))
+    } else {
+        None
+    }
+}
+
+fn percent_decode(segment: &str) -> Option<Vec<u8>> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+// A single byte range. Several ranges (multipart/byteranges) get the whole file.
+fn range(req_headers: &[(String, String)], size: u64) -> Range {
+    let spec = match header(req_headers, "Range").and_then(|r| r.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Range::Full,
+    };
+
+    let (first, last) = match spec.split_once('-') {
+        Some((first, last)) => (first.trim(), last.trim()),
+        None => return Range::Full,
+    };
+
+    // bytes=-500 is the last 500 bytes
+    if first.is_empty() {
+        return match last.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if size == 0 => Range::Unsatisfiable,
+            Ok(suffix) => Range::Partial(size.saturating_sub(suffix), size - 1),
+            Err(_) => Range::Full,
+        };
+    }
+
+    let first = match first.parse::<u64>() {
+        Ok(first) => first,
+        Err(_) => return Range::Full,
+    };
+
+    if first >= size {
+        return Range::Unsatisfiable;
+    }
+
+    if last.is_empty() {
+        return Range::Partial(first, size - 1);
+    }
+
+    match last.parse::<u64>() {
+        Ok(last) if last >= first => Range::Partial(first, last.min(size - 1)),
+        _ => Range::Full,
+    }
+}
+
+fn status(code: u16, reason: &str) -> HttpResponseHeader {
+    HttpResponseHeader {
+        code,
+        reason: reason.to_string(),
+        headers: vec![],
+    }
+}
+
+pub(super) fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=UTF-8",
+        Some("css") => "text/css; charset=UTF-8",
+        Some("js") | Some("mjs") => "application/javascript",
+        Some("json") | Some("map") => "application/json",
+        Some("xml") => "application/xml",
+        Some("txt") => "text/plain; charset=UTF-8",
+        Some("csv") => "text/csv; charset=UTF-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::{file_path, percent_decode, range, Range};
+
+    fn range_header(value: &str) -> Vec<(String, String)> {
+        vec![("Range".to_string(), value.to_string())]
+    }
+
+    // root/a.txt, root/css/site.css, root/link -> base and base/secret.txt outside the root
+    fn tree(name: &str) -> (PathBuf, PathBuf) {
+        let base = env::temp_dir().join(format!("casket-{}-{}", name, process::id()));
+        let root = base.join("root");
+
+        fs::create_dir_all(root.join("css")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("css/site.css"), "css").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+        symlink(&base, root.join("link")).unwrap();
+
+        (base, root)
+    }
+
+    #[test]
+    fn range_single() {
+        assert!(matches!(range(&[], 1000), Range::Full));
+        assert!(matches!(
+            range(&range_header("bytes=0-499"), 1000),
+            Range::Partial(0, 499)
+        ));
+        assert!(matches!(
+            range(&range_header("bytes=500-"), 1000),
+            Range::Partial(500, 999)
+        ));
+        assert!(matches!(
+            range(&range_header("bytes=900-2000"), 1000),
+            Range::Partial(900, 999)
+        ));
+    }
+
+    #[test]
+    fn range_suffix() {
+        assert!(matches!(
+            range(&range_header("bytes=-200"), 1000),
+            Range::Partial(800, 999)
+        ));
+        assert!(matches!(
+            range(&range_header("bytes=-2000"), 1000),
+            Range::Partial(0, 999)
+        ));
+        assert!(matches!(
+            range(&range_header("bytes=-0"), 1000),
+            Range::Unsatisfiable
+        ));
+        assert!(matches!(
+            range(&range_header("bytes=-5"), 0),
+            Range::Unsatisfiable
+        ));
+    }
+
+    #[test]
+    fn range_ignored() {
+        assert!(matches!(
+            range(&range_header("bytes=1000-"), 1000),
+            Range::Unsatisfiable
+        ));
+
+        // Anything we don't serve as a single range gets the whole file
+        for value in [
+            "bytes=0-1,5-6",
+            "bytes=5-1",
+            "bytes=a-b",
+            "items=0-1",
+            "bytes=5",
+        ] {
+            assert!(matches!(range(&range_header(value), 1000), Range::Full));
+        }
+    }
+
+    #[test]
+    fn percent_decode_segments() {
+        assert_eq!(percent_decode("a.txt"), Some(b"a.txt".to_vec()));
+        assert_eq!(percent_decode("a%20b"), Some(b"a b".to_vec()));
+        assert_eq!(percent_decode("%C3%A9"), Some(vec![0xc3, 0xa9]));
+        assert_eq!(percent_decode("%2e%2E"), Some(b"..".to_vec()));
+        assert_eq!(percent_decode("a%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+
+    #[test]
+    fn file_path_inside_root() {
+        let (base, root) = tree("file-path-inside");
+        let canonical = root.canonicalize().unwrap();
+
+        assert_eq!(file_path(&root, "/a.txt"), Some(canonical.join("a.txt")));
+        assert_eq!(
+            file_path(&root, "/css//site.css"),
+            Some(canonical.join("css/site.css"))
+        );
+        assert_eq!(file_path(&root, "/missing.txt"), None);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn file_path_traversal() {
+        let (base, root) = tree("file-path-traversal");
+
+        for rest in [
+            "/../secret.txt",
+            "/css/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/..%2fsecret.txt",
+            "/css%2F..%2F..%2Fsecret.txt",
+            "/a.txt%00",
+            "/.hidden",
+            "/link/secret.txt",
+        ] {
+            assert_eq!(file_path(&root, rest), None, "{}", rest);
+        }
+
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::sync::Arc;
+
+use mio::{event::Source, net::TcpStream, Interest, Registry, Token};
+use rustls::{ProtocolVersion, ServerConfig, ServerConnection};
+
+use crate::http::{ClientCert, ConnAddrs, TlsInfo};
+use crate::tls;
+
+// A client stream, either plain tcp or tls over tcp.
+// TLS session state lives here, so a tls stream never goes
+// back to the server between requests - the worker keeps it.
+pub struct Stream {
+    transport: Transport,
+    addrs: ConnAddrs,
+}
+
+enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+pub struct TlsStream {
+    tcp_stream: TcpStream,
+    conn: ServerConnection,
+    // Parsed on the first request, reused on keep-alive
+    client_cert: Option<ClientCert>,
+}
+
+impl Stream {
+    pub fn new(
+        tcp_stream: TcpStream,
+        addrs: ConnAddrs,
+        tls: Option<&Arc<ServerConfig>>,
+    ) -> io::Result<Self> {
+        let transport = match tls {
+            None => Transport::Plain(tcp_stream),
+            Some(server_config) => {
+                let conn = ServerConnection::new(server_config.clone())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+                Transport::Tls(Box::new(TlsStream {
+                    tcp_stream,
+                    conn,
+                    client_cert: None,
+                }))
+            }
+        };
+
+        Ok(Self { transport, addrs })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.transport, Transport::Tls(_))
+    }
+
+    pub fn addrs(&self) -> ConnAddrs {
+        self.addrs
+    }
+
+    // Only a plain tcp stream can have a file sent straight to it,
+    // TLS has to encrypt the bytes first
+    pub fn sendfile_fd(&self) -> Option<RawFd> {
+        match &self.transport {
+            Transport::Plain(tcp_stream) => Some(tcp_stream.as_raw_fd()),
+            Transport::Tls(_) => None,
+        }
+    }
+
+    pub fn tls_info(&mut self) -> Option<TlsInfo> {
+        let tls = match &mut self.transport {
+            Transport::Plain(_) => return None,
+            Transport::Tls(tls) => tls,
+        };
+
+        let protocol = tls.conn.protocol_version().map(|v| match v {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            v => format!("{:?}", v),
+        });
+
+        if tls.client_cert.is_none() {
+            tls.client_cert = tls.conn.peer_certificates().and_then(tls::client_cert);
+        }
+
+        Some(TlsInfo {
+            server_name: tls.conn.server_name().map(|s| s.to_string()),
+            protocol,
+            client_cert: tls.client_cert.clone(),
+        })
+    }
+
+    // TLS records are still buffered waiting to go on the wire
+    pub fn wants_write(&self) -> bool {
+        match &self.transport {
+            Transport::Plain(_) => false,
+            Transport::Tls(tls) => tls.conn.wants_write(),
+        }
+    }
+
+    // Decrypted bytes rustls is holding. The socket may have nothing left
+    // to wake the poll, so the caller has to read them without waiting.
+    pub fn has_buffered_data(&mut self) -> bool {
+        match &mut self.transport {
+            Transport::Plain(_) => false,
+            Transport::Tls(tls) => match tls.conn.process_new_packets() {
+                Ok(io_state) => io_state.plaintext_bytes_to_read() > 0,
+                // The next read reports it
+                Err(_) => true,
+            },
+        }
+    }
+
+    pub fn into_raw_fd(self) -> RawFd {
+        match self.transport {
+            Transport::Plain(tcp_stream) => tcp_stream.into_raw_fd(),
+            Transport::Tls(mut tls) => {
+                // Best effort - the server shuts the stream down next
+                tls.conn.send_close_notify();
+                tls.flush_tls().unwrap_or(());
+                tls.tcp_stream.into_raw_fd()
+            }
+        }
+    }
+}
+
+impl TlsStream {
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.tcp_stream)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let tls = match &mut self.transport {
+            Transport::Plain(tcp_stream) => return tcp_stream.read(buf),
+            Transport::Tls(tls) => tls,
+        };
+
+        loop {
+            match tls.conn.reader().read(buf) {
+                Ok(sz) => return Ok(sz),
+                // Client closed without close_notify
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            // NOTE: May be WouldBlock if we only had handshake records
+            if tls.conn.read_tls(&mut tls.tcp_stream)? == 0 {
+                return Ok(0);
+            }
+
+            tls.conn
+                .process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            // Handshake replies
+            match tls.flush_tls() {
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let tls = match &mut self.transport {
+            Transport::Plain(tcp_stream) => return tcp_stream.write(buf),
+            Transport::Tls(tls) => tls,
+        };
+
+        // Don't buffer more plaintext while the socket is backed up
+        tls.flush_tls()?;
+
+        let sz = tls.conn.writer().write(buf)?;
+
+        match tls.flush_tls() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e),
+            _ => Ok(sz),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.transport {
+            Transport::Plain(tcp_stream) => tcp_stream.flush(),
+            Transport::Tls(tls) => tls.flush_tls(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, tk: Token, interests: Interest) -> io::Result<()> {
+        match &mut self.transport {
+            Transport::Plain(tcp_stream) => tcp_stream.register(registry, tk, interests),
+            Transport::Tls(tls) => tls.tcp_stream.register(registry, tk, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        tk: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match &mut self.transport {
+            Transport::Plain(tcp_stream) => tcp_stream.reregister(registry, tk, interests),
+            Transport::Tls(tls) => tls.tcp_stream.reregister(registry, tk, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match &mut self.transport {
+            Transport::Plain(tcp_stream) => tcp_stream.deregister(registry),
+            Transport::Tls(tls) => tls.tcp_stream.deregister(registry),
+        }
+    }
+}